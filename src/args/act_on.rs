@@ -35,6 +35,85 @@ impl ActOn {
     }
 }
 
+/// The order in which a command should visit pods, based on each pod's
+/// `depends_on_pods` list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyOrder {
+    /// Visit each pod after the pods it depends on.  Used when starting
+    /// things up.
+    Forward,
+    /// Visit each pod before the pods it depends on.  Used when shutting
+    /// things down.
+    Reverse,
+}
+
+impl ActOn {
+    /// Collect the pods or services specified by this `ActOn` object,
+    /// sorted by `order`.  Services belonging to the same pod are kept
+    /// together.
+    pub fn pods_or_services_in_order<'a>(
+        &'a self,
+        project: &'a Project,
+        order: DependencyOrder,
+    ) -> Result<Vec<PodOrService<'a>>> {
+        let mut result = self.pods_or_services(project).collect::<Result<Vec<_>>>()?;
+        // `sort_by_key` is stable, so this doesn't shuffle services within
+        // a pod.
+        result
+            .sort_by_key(|pod_or_service| project.pod_position(pod_or_service.pod()));
+        if order == DependencyOrder::Reverse {
+            result = reverse_pod_order(result);
+        }
+        Ok(result)
+    }
+
+    /// Like `pods_or_services_in_order`, but group the results into stages
+    /// using `Project::pod_stage`.  Everything in a stage may be processed
+    /// in parallel, once all the earlier stages are done.
+    pub fn pods_or_services_in_stages<'a>(
+        &'a self,
+        project: &'a Project,
+        order: DependencyOrder,
+    ) -> Result<Vec<Vec<PodOrService<'a>>>> {
+        let mut stages: Vec<Vec<PodOrService<'a>>> = vec![];
+        for pod_or_service in
+            self.pods_or_services_in_order(project, DependencyOrder::Forward)?
+        {
+            let stage = project.pod_stage(pod_or_service.pod());
+            if stages.len() <= stage {
+                stages.resize_with(stage + 1, Vec::new);
+            }
+            stages[stage].push(pod_or_service);
+        }
+        stages.retain(|stage| !stage.is_empty());
+        if order == DependencyOrder::Reverse {
+            stages.reverse();
+            for stage in &mut stages {
+                *stage = reverse_pod_order(std::mem::take(stage));
+            }
+        }
+        Ok(stages)
+    }
+}
+
+/// Reverse the order of the pods in `pods_or_services`, but keep services
+/// belonging to the same pod together and in their original order.
+fn reverse_pod_order(
+    pods_or_services: Vec<PodOrService<'_>>,
+) -> Vec<PodOrService<'_>> {
+    let mut groups: Vec<Vec<PodOrService<'_>>> = vec![];
+    for pod_or_service in pods_or_services {
+        let pod_name = pod_or_service.pod().name();
+        match groups.last_mut() {
+            Some(group) if group[0].pod().name() == pod_name => {
+                group.push(pod_or_service)
+            }
+            _ => groups.push(vec![pod_or_service]),
+        }
+    }
+    groups.into_iter().rev().flatten().collect()
+}
+
 /// A filter function which excludes `PodType::Task` pods.  We could use an
 /// inline closure for this, but it's annoying to stick Rust closures into
 /// structs, because the types get too complicated.
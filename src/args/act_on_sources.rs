@@ -15,6 +15,26 @@ pub enum ActOnSources {
 }
 
 impl ActOnSources {
+    /// Iterate over the sources specified by this `ActOnSources` object.
+    pub fn sources<'a>(
+        &'a self,
+        sources: &'a Sources,
+    ) -> impl Iterator<Item = &'a Source> + 'a {
+        match self {
+            ActOnSources::All => {
+                Box::new(sources.iter()) as Box<dyn Iterator<Item = &'a Source> + 'a>
+            }
+            ActOnSources::Named(aliases) => {
+                let aliases = aliases.iter().cloned().collect::<BTreeSet<_>>();
+                Box::new(
+                    sources
+                        .iter()
+                        .filter(move |source| aliases.contains(source.alias())),
+                )
+            }
+        }
+    }
+
     /// Iterate over the pods or services specified by this `ActOn` object.
     pub fn sources_mut<'a>(
         &'a self,
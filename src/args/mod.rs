@@ -3,7 +3,7 @@
 
 use std::ffi::OsString;
 
-pub use self::act_on::ActOn;
+pub use self::act_on::{ActOn, DependencyOrder};
 pub use self::act_on_sources::ActOnSources;
 pub use self::cmd::*;
 
@@ -3,6 +3,7 @@
 use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::ops::{Deref, DerefMut};
+use std::time::Duration;
 
 use crate::args::ToArgs;
 
@@ -315,6 +316,9 @@ pub struct Rm {
     /// Delete any anonymous volumes attached to containers.
     pub remove_volumes: bool,
 
+    /// Stop containers before removing them.
+    pub stop: bool,
+
     /// PRIVATE: This field is a stand-in for future options.
     /// See http://stackoverflow.com/q/39277157/12089
     #[doc(hidden)]
@@ -330,6 +334,9 @@ impl ToArgs for Rm {
         if self.remove_volumes {
             args.push(OsStr::new("-v").to_owned());
         }
+        if self.stop {
+            args.push(OsStr::new("-s").to_owned());
+        }
         args
     }
 }
@@ -339,10 +346,58 @@ fn rm_options_to_args_returns_appropriate_flags() {
     let mut opts = Rm::default();
     opts.force = true;
     opts.remove_volumes = true;
-    let raw_expected = &["-f", "-v"];
+    opts.stop = true;
+    let raw_expected = &["-f", "-v", "-s"];
     let expected: Vec<OsString> = raw_expected
         .iter()
         .map(|s| OsStr::new(s).to_owned())
         .collect();
     assert_eq!(opts.to_args(), expected);
 }
+
+/// Options for `cage down`.  These are used by `cage` itself, and aren't
+/// passed directly to `docker-compose`.
+#[derive(Debug, Clone, Default)]
+#[allow(missing_copy_implementations)]
+#[non_exhaustive]
+pub struct Down {
+    /// Delete anonymous volumes attached to containers, and any named
+    /// volumes created for this project.
+    pub remove_volumes: bool,
+
+    /// Delete any networks created for this project.
+    pub remove_networks: bool,
+}
+
+/// Options for `cage watch`.  These are used by `cage` itself, and aren't
+/// passed directly to `docker-compose`.
+#[derive(Debug, Clone)]
+#[allow(missing_copy_implementations)]
+#[non_exhaustive]
+pub struct Watch {
+    /// How long to wait after the last change before restarting services,
+    /// so that saving many files at once only restarts each service once.
+    pub debounce: Duration,
+
+    /// How often to check the source trees for changes.
+    pub poll_interval: Duration,
+}
+
+impl Watch {
+    /// Create new `Watch` options.
+    pub fn new(debounce: Duration) -> Watch {
+        Watch {
+            debounce,
+            ..Watch::default()
+        }
+    }
+}
+
+impl Default for Watch {
+    fn default() -> Watch {
+        Watch {
+            debounce: Duration::from_millis(500),
+            poll_interval: Duration::from_millis(250),
+        }
+    }
+}
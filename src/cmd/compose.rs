@@ -1,13 +1,37 @@
 //! Pass simple commands directly through to `docker-compose`.
 
+use colored::*;
+use rayon::prelude::*;
+use std::ffi::OsString;
+#[cfg(test)]
+use std::path::Path;
+
 use crate::args;
 #[cfg(test)]
 use crate::command_runner::TestCommandRunner;
 use crate::command_runner::{Command, CommandRunner};
+use crate::engine::{Engine, EngineCommand};
 use crate::errors::*;
 use crate::pod::Pod;
 use crate::project::{PodOrService, Project};
 
+/// `docker-compose` commands which shut things down, and which should
+/// therefore visit pods in reverse dependency order.
+const SHUTDOWN_COMMANDS: &[&str] = &["kill", "rm", "stop"];
+
+/// `docker-compose` commands which `compose_parallel` may run on several
+/// pods at once.  `up` is handled by `CommandUp`.
+const PARALLEL_COMMANDS: &[&str] = &["kill", "restart", "rm", "stop"];
+
+/// The order in which to visit pods when running `command`.
+fn dependency_order(command: &str) -> args::DependencyOrder {
+    if SHUTDOWN_COMMANDS.contains(&command) {
+        args::DependencyOrder::Reverse
+    } else {
+        args::DependencyOrder::Forward
+    }
+}
+
 /// Pass simple commands directly through to `docker-compose`.
 pub trait CommandCompose {
     /// Pass simple commands directly through to `docker-compose`.
@@ -21,6 +45,23 @@ pub trait CommandCompose {
     where
         CR: CommandRunner;
 
+    /// Like `compose`, but if `Project::jobs` is greater than 1, run `kill`,
+    /// `restart`, `rm -f` and `stop` on independent pods in parallel,
+    /// buffering the output for each pod.  By default, this just calls
+    /// `compose`.
+    fn compose_parallel<CR>(
+        &self,
+        runner: &CR,
+        command: &str,
+        act_on: &args::ActOn,
+        opts: &dyn args::ToArgs,
+    ) -> Result<()>
+    where
+        CR: CommandRunner + Sync,
+    {
+        self.compose(runner, command, act_on, opts)
+    }
+
     /// Run a `docker-compose` command on a single pod.  If the pod is
     /// disabled, this does nothing.
     fn compose_pod<CR>(
@@ -58,17 +99,53 @@ impl CommandCompose for Project {
     where
         CR: CommandRunner,
     {
-        for pod_or_service in act_on.pods_or_services(self) {
-            match pod_or_service? {
-                PodOrService::Pod(pod) => {
-                    self.compose_pod(runner, command, pod, opts)?;
-                }
-                PodOrService::Service(pod, service_name) => {
-                    self.compose_service(runner, command, pod, service_name, opts)?;
-                }
-            }
+        let order = dependency_order(command);
+        let args = opts.to_args();
+        for pod_or_service in act_on.pods_or_services_in_order(self, order)? {
+            self.compose_pod_or_service(
+                runner,
+                command,
+                &pod_or_service,
+                &args,
+                false,
+            )?;
         }
+        Ok(())
+    }
 
+    fn compose_parallel<CR>(
+        &self,
+        runner: &CR,
+        command: &str,
+        act_on: &args::ActOn,
+        opts: &dyn args::ToArgs,
+    ) -> Result<()>
+    where
+        CR: CommandRunner + Sync,
+    {
+        let args = opts.to_args();
+
+        // `docker-compose rm` asks for confirmation unless we pass `-f`,
+        // and we can't do that with buffered output.
+        let parallel = self.jobs() > 1
+            && PARALLEL_COMMANDS.contains(&command)
+            && (command != "rm" || args.iter().any(|arg| arg == "-f"));
+        if !parallel {
+            return self.compose(runner, command, act_on, opts);
+        }
+
+        let order = dependency_order(command);
+        for stage in act_on.pods_or_services_in_stages(self, order)? {
+            self.in_parallel(&stage, |pod_or_service| {
+                self.compose_pod_or_service(
+                    runner,
+                    command,
+                    pod_or_service,
+                    &args,
+                    true,
+                )
+            })?;
+        }
         Ok(())
     }
 
@@ -82,15 +159,14 @@ impl CommandCompose for Project {
     where
         CR: CommandRunner,
     {
-        if pod.enabled_in(self.current_target()) {
-            runner
-                .build("docker-compose")
-                .args(&pod.compose_args(self)?)
-                .arg(command)
-                .args(&opts.to_args())
-                .exec()?;
-        }
-        Ok(())
+        let pod_or_service = PodOrService::Pod(pod);
+        self.compose_pod_or_service(
+            runner,
+            command,
+            &pod_or_service,
+            &opts.to_args(),
+            false,
+        )
     }
 
     fn compose_service<CR>(
@@ -104,17 +180,113 @@ impl CommandCompose for Project {
     where
         CR: CommandRunner,
     {
-        if pod.enabled_in(self.current_target()) {
-            runner
-                .build("docker-compose")
-                .args(&pod.compose_args(self)?)
-                .arg(command)
-                .args(&opts.to_args())
-                .arg(service_name)
-                .exec()?;
+        let pod_or_service = PodOrService::Service(pod, service_name);
+        self.compose_pod_or_service(
+            runner,
+            command,
+            &pod_or_service,
+            &opts.to_args(),
+            false,
+        )
+    }
+}
+
+impl Project {
+    /// Run a `docker-compose` command on a pod or service.  If the pod is
+    /// disabled, this does nothing.  If `capture` is true, we buffer the
+    /// command's output the same way `pull` does, and only show it if the
+    /// command fails.
+    pub(crate) fn compose_pod_or_service<CR>(
+        &self,
+        runner: &CR,
+        command: &str,
+        pod_or_service: &PodOrService<'_>,
+        args: &[OsString],
+        capture: bool,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let (pod, service_name) = match *pod_or_service {
+            PodOrService::Pod(pod) => (pod, None),
+            PodOrService::Service(pod, service_name) => (pod, Some(service_name)),
+        };
+        if !pod.enabled_in(self.current_target()) {
+            return Ok(());
         }
+
+        if let Some(engine_command) = engine_command(self, command, args) {
+            return Engine::connect()?.compose(
+                runner,
+                self,
+                pod,
+                service_name,
+                &engine_command,
+            );
+        }
+
+        let mut cmd = self.compose_command(runner, pod)?;
+        cmd.arg(command).args(args);
+        if let Some(service_name) = service_name {
+            cmd.arg(service_name);
+        }
+        if !capture {
+            return cmd.exec();
+        }
+
+        let label = match service_name {
+            Some(service_name) => format!("{}/{}", pod.name(), service_name),
+            None => pod.name().to_owned(),
+        };
+        cmd.exec_capturing(&label)?;
+        println!("  {} {} {}", "[ok]".green().bold(), command, label);
         Ok(())
     }
+
+    /// Call `f` on each pod or service in `stage`, using up to
+    /// `self.jobs()` threads.  Like `pull`, we report every failure before
+    /// returning an error.
+    pub(crate) fn in_parallel<'a, F>(
+        &self,
+        stage: &[PodOrService<'a>],
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&PodOrService<'a>) -> Result<()> + Sync,
+    {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs())
+            .build()?;
+        let results: Vec<Result<()>> =
+            pool.install(|| stage.par_iter().map(&f).collect());
+
+        let mut failures = 0usize;
+        for r in results {
+            if let Err(e) = r {
+                failures += 1;
+                eprintln!("{} {:#}", "[error]".red().bold(), e);
+            }
+        }
+        if failures > 0 {
+            Err(anyhow::anyhow!("{} pod(s) failed", failures))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// If we're using the Docker Engine API backend, and it knows how to run
+/// `command` with `args`, return the command to run.
+fn engine_command(
+    project: &Project,
+    command: &str,
+    args: &[OsString],
+) -> Option<EngineCommand> {
+    if project.compose_backend().is_engine_api() {
+        EngineCommand::parse(command, args)
+    } else {
+        None
+    }
 }
 
 #[test]
@@ -127,12 +299,13 @@ fn runs_docker_compose_on_all_pods() {
     let opts = args::opts::Empty;
     proj.compose(&runner, "stop", &args::ActOn::All, &opts)
         .unwrap();
+    // We stop pods in reverse order, so that placeholders go last.
     assert_ran!(runner, {
         ["docker-compose",
          "-p",
          "railshello",
          "-f",
-         proj.output_dir().join("pods").join("db.yml"),
+         proj.output_dir().join("pods").join("rake.yml"),
          "stop"],
         ["docker-compose",
          "-p",
@@ -144,7 +317,7 @@ fn runs_docker_compose_on_all_pods() {
          "-p",
          "railshello",
          "-f",
-         proj.output_dir().join("pods").join("rake.yml"),
+         proj.output_dir().join("pods").join("db.yml"),
          "stop"]
     });
 
@@ -166,16 +339,109 @@ fn runs_docker_compose_on_named_pods_and_services() {
          "-p",
          "railshello",
          "-f",
-         proj.output_dir().join("pods").join("db.yml"),
-         "stop"],
+         proj.output_dir().join("pods").join("frontend.yml"),
+         "stop",
+         "web"],
         ["docker-compose",
          "-p",
          "railshello",
          "-f",
+         proj.output_dir().join("pods").join("db.yml"),
+         "stop"]
+    });
+
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn runs_docker_compose_in_dependency_order() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_pod_dependencies").unwrap();
+    let runner = TestCommandRunner::new();
+    proj.output("restart").unwrap();
+
+    // The names here are in the opposite of dependency order.
+    let act_on = args::ActOn::Named(vec!["web".to_owned(), "queue".to_owned()]);
+    let opts = args::opts::Empty;
+    proj.compose(&runner, "restart", &act_on, &opts).unwrap();
+    assert_ran!(runner, {
+        ["docker-compose",
+         "-p",
+         "withpoddependencies",
+         "-f",
+         proj.output_dir().join("pods").join("queue.yml"),
+         "restart"],
+        ["docker-compose",
+         "-p",
+         "withpoddependencies",
+         "-f",
          proj.output_dir().join("pods").join("frontend.yml"),
-         "stop",
+         "restart",
          "web"]
     });
 
     proj.remove_test_output().unwrap();
 }
+
+#[test]
+fn runs_docker_compose_plugin_when_selected() {
+    use crate::compose_backend::ComposeBackend;
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("rails_hello").unwrap();
+    proj.set_compose_backend(ComposeBackend::DockerComposePlugin);
+    let runner = TestCommandRunner::new();
+    proj.output("stop").unwrap();
+
+    let act_on = args::ActOn::Named(vec!["web".to_owned()]);
+    let opts = args::opts::Empty;
+    proj.compose(&runner, "stop", &act_on, &opts).unwrap();
+    assert_ran!(runner, {
+        [
+            "docker",
+            "compose",
+            "-p",
+            "railshello",
+            "-f",
+            proj.output_dir().join("pods").join("frontend.yml"),
+            "stop",
+            "web",
+        ]
+    });
+
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn runs_docker_compose_on_independent_pods_in_parallel() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_fixture("with_parallel_pods").unwrap();
+    proj.set_jobs(4);
+    let runner = TestCommandRunner::new();
+    proj.output("stop").unwrap();
+
+    let opts = args::opts::Empty;
+    proj.compose_parallel(&runner, "stop", &args::ActOn::All, &opts)
+        .unwrap();
+
+    // Pods within a stage may finish in any order, so compare each stage
+    // as a sorted list.
+    let pods: Vec<String> = runner
+        .cmds()
+        .iter()
+        .map(|cmd| {
+            let path = Path::new(&cmd[4]);
+            path.file_stem().unwrap().to_string_lossy().into_owned()
+        })
+        .collect();
+    assert_eq!(pods.len(), 5);
+    assert_eq!(pods[0], "worker");
+    let mut middle = pods[1..3].to_vec();
+    middle.sort();
+    assert_eq!(middle, vec!["api", "web"]);
+    let mut last = pods[3..].to_vec();
+    last.sort();
+    assert_eq!(last, vec!["cache", "db"]);
+
+    proj.remove_test_output().unwrap();
+}
@@ -0,0 +1,132 @@
+//! The `down` command.
+
+use crate::args;
+use crate::cmd::CommandCompose;
+#[cfg(test)]
+use crate::command_runner::TestCommandRunner;
+use crate::command_runner::{Command, CommandRunner};
+use crate::errors::*;
+use crate::project::{PodOrService, Project};
+use crate::runtime_state::RuntimeState;
+
+/// We implement `down` with a trait so we put it in its own module.
+pub trait CommandDown {
+    /// Tear down the entire project: stop and remove the containers for
+    /// every pod in reverse dependency order, remove any one-off containers
+    /// left behind by `cage run` or `cage test`, and optionally remove the
+    /// project's volumes and networks.
+    fn down<CR>(&self, runner: &CR, opts: &args::opts::Down) -> Result<()>
+    where
+        CR: CommandRunner;
+}
+
+impl CommandDown for Project {
+    fn down<CR>(&self, runner: &CR, opts: &args::opts::Down) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let state = RuntimeState::for_project(self)?;
+        self.down_with_state(runner, &state, opts)
+    }
+}
+
+impl Project {
+    /// The actual implementation of `down`, using an existing
+    /// `RuntimeState` to find one-off containers.
+    fn down_with_state<CR>(
+        &self,
+        runner: &CR,
+        state: &RuntimeState,
+        opts: &args::opts::Down,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let mut rm_opts = args::opts::Rm::default();
+        rm_opts.force = true;
+        rm_opts.stop = true;
+        rm_opts.remove_volumes = opts.remove_volumes;
+
+        let pods = args::ActOn::All
+            .pods_or_services_in_order(self, args::DependencyOrder::Reverse)?;
+        for pod_or_service in pods {
+            let pod = match pod_or_service {
+                PodOrService::Pod(pod) => pod,
+                PodOrService::Service(pod, _) => pod,
+            };
+            self.compose_pod(runner, "rm", pod, &rm_opts)?;
+
+            // `docker-compose rm` won't necessarily clean up after
+            // `docker-compose run`, so remove those containers by hand.
+            let one_offs = state.one_off_containers_in_pod(pod);
+            if !one_offs.is_empty() {
+                let mut cmd = runner.build("docker");
+                cmd.arg("rm").arg("--force");
+                if opts.remove_volumes {
+                    cmd.arg("--volumes");
+                }
+                for container in one_offs {
+                    cmd.arg(container.id());
+                }
+                cmd.exec()?;
+            }
+        }
+
+        // Only remove volumes and networks that `docker-compose` created
+        // for this project, and which are no longer in use.
+        let project_label =
+            format!("label=com.docker.compose.project={}", self.compose_name());
+        if opts.remove_volumes {
+            runner
+                .build("docker")
+                .args(&["volume", "prune", "--force", "--all", "--filter"])
+                .arg(&project_label)
+                .exec()?;
+        }
+        if opts.remove_networks {
+            runner
+                .build("docker")
+                .args(&["network", "prune", "--force", "--filter"])
+                .arg(&project_label)
+                .exec()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn down_removes_pods_in_reverse_order_with_one_offs_volumes_and_networks() {
+    use crate::runtime_state::ContainerStatus;
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("rails_hello").unwrap();
+    let runner = TestCommandRunner::new();
+    proj.output("down").unwrap();
+
+    let mut state = RuntimeState::fake(&[("web", ContainerStatus::Running)]);
+    state.add_fake_one_off("rake", "abc123");
+    state.add_fake_one_off("web", "def456");
+
+    let mut opts = args::opts::Down::default();
+    opts.remove_volumes = true;
+    opts.remove_networks = true;
+    proj.down_with_state(&runner, &state, &opts).unwrap();
+
+    let pods_dir = proj.output_dir().join("pods");
+    assert_ran!(runner, {
+        ["docker-compose", "-p", "railshello", "-f", pods_dir.join("rake.yml"),
+         "rm", "-f", "-v", "-s"],
+        ["docker", "rm", "--force", "--volumes", "abc123"],
+        ["docker-compose", "-p", "railshello", "-f", pods_dir.join("frontend.yml"),
+         "rm", "-f", "-v", "-s"],
+        ["docker", "rm", "--force", "--volumes", "def456"],
+        ["docker-compose", "-p", "railshello", "-f", pods_dir.join("db.yml"),
+         "rm", "-f", "-v", "-s"],
+        ["docker", "volume", "prune", "--force", "--all", "--filter",
+         "label=com.docker.compose.project=railshello"],
+        ["docker", "network", "prune", "--force", "--filter",
+         "label=com.docker.compose.project=railshello"]
+    });
+
+    proj.remove_test_output().unwrap();
+}
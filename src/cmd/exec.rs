@@ -4,6 +4,7 @@ use crate::args::{self, ToArgs};
 #[cfg(test)]
 use crate::command_runner::TestCommandRunner;
 use crate::command_runner::{Command, CommandRunner};
+use crate::engine::Engine;
 use crate::errors::*;
 use crate::ext::service::ServiceExt;
 use crate::project::Project;
@@ -48,9 +49,10 @@ impl CommandExec for Project {
         CR: CommandRunner,
     {
         let (pod, service_name) = self.service_or_err(service_name)?;
-        runner
-            .build("docker-compose")
-            .args(&pod.compose_args(self)?)
+        if self.compose_backend().is_engine_api() {
+            return Engine::connect()?.exec(runner, self, service_name, command, opts);
+        }
+        self.compose_command(runner, pod)?
             .arg("exec")
             .args(&opts.to_args())
             .arg(service_name)
@@ -112,6 +114,39 @@ fn invokes_docker_exec() {
     proj.remove_test_output().unwrap();
 }
 
+#[test]
+fn invokes_docker_compose_plugin_exec_when_selected() {
+    use crate::compose_backend::ComposeBackend;
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("hello").unwrap();
+    proj.set_compose_backend(ComposeBackend::DockerComposePlugin);
+    let runner = TestCommandRunner::new();
+    proj.output("exec").unwrap();
+
+    let command = args::Command::new("true");
+    let mut opts = args::opts::Exec::default();
+    opts.allocate_tty = false;
+    proj.exec(&runner, "web", &command, &opts).unwrap();
+
+    assert_ran!(runner, {
+        [
+            "docker",
+            "compose",
+            "-p",
+            "hello",
+            "-f",
+            proj.output_dir().join("pods").join("frontend.yml"),
+            "exec",
+            "-T",
+            "web",
+            "true",
+        ]
+    });
+
+    proj.remove_test_output().unwrap();
+}
+
 #[test]
 fn runs_shells() {
     let _ = env_logger::try_init();
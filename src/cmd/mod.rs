@@ -11,23 +11,34 @@
 #![allow(clippy::print_stdout)]
 
 pub use self::compose::CommandCompose;
+pub use self::down::CommandDown;
 pub use self::exec::CommandExec;
 pub use self::generate::CommandGenerate;
 pub use self::logs::CommandLogs;
 pub use self::pull::CommandPull;
 pub use self::run::CommandRun;
 pub use self::run_script::CommandRunScript;
-pub use self::source::CommandSource;
-pub use self::status::CommandStatus;
+pub use self::secrets::CommandSecrets;
+pub use self::source::{CommandSource, CommandSourceGit};
+pub use self::status::{
+    CommandStatus, ContainerSummary, PodStatus, ProjectStatus, ServiceStatus,
+    SourceStatus,
+};
 pub use self::up::CommandUp;
+pub use self::vault::CommandVault;
+pub use self::watch::CommandWatch;
 
 mod compose;
+mod down;
 mod exec;
 mod generate;
 mod logs;
 mod pull;
 mod run;
 mod run_script;
+mod secrets;
 mod source;
 mod status;
 mod up;
+mod vault;
+mod watch;
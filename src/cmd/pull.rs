@@ -119,10 +119,8 @@ where
         None => pod.name().to_owned(),
     };
 
-    let mut cmd = runner.build("docker-compose");
-    cmd.args(&pod.compose_args(project)?)
-        .arg("pull")
-        .args(&opts.to_args());
+    let mut cmd = project.compose_command(runner, pod)?;
+    cmd.arg("pull").args(&opts.to_args());
     if let Some(svc) = service_name {
         cmd.arg(svc);
     }
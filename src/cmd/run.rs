@@ -5,6 +5,7 @@ use rand::random;
 #[cfg(test)]
 use crate::command_runner::TestCommandRunner;
 use crate::command_runner::{Command, CommandRunner};
+use crate::engine::Engine;
 use crate::errors::*;
 use crate::ext::service::ServiceExt;
 use crate::project::Project;
@@ -52,6 +53,10 @@ impl CommandRun for Project {
         CR: CommandRunner,
     {
         let (pod, service_name) = self.service_or_err(service)?;
+        if self.compose_backend().is_engine_api() {
+            let engine = Engine::connect()?;
+            return engine.run(runner, self, pod, service_name, command, opts);
+        }
 
         // Build and run our command.
         let command_args = if let Some(c) = command {
@@ -59,9 +64,7 @@ impl CommandRun for Project {
         } else {
             vec![]
         };
-        runner
-            .build("docker-compose")
-            .args(&pod.compose_args(self)?)
+        self.compose_command(runner, pod)?
             .arg("run")
             .args(&opts.to_args())
             .arg(service_name)
@@ -108,9 +111,7 @@ impl CommandRun for Project {
             service.test_command()?.iter().map(|s| s.into()).collect()
         };
         let container_name = format!("{}_{}", service_name, random::<u16>());
-        runner
-            .build("docker-compose")
-            .args(&pod.compose_args(self)?)
+        self.compose_command(runner, pod)?
             .arg("run")
             .arg("--name")
             .arg(&container_name)
@@ -0,0 +1,161 @@
+//! The `secrets` command.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[cfg(test)]
+use crate::command_runner::OsCommandRunner;
+use crate::command_runner::{Command, CommandRunner};
+use crate::errors::*;
+use crate::plugins::transform::secrets::{self, Keys};
+use crate::project::Project;
+
+/// What we show the user when they edit a new `config/secrets.yml.age`.
+const NEW_SECRETS: &str = "\
+# Secrets in this file use the same format as `config/secrets.yml`, but
+# they're encrypted when you save them, so you can check them in.
+
+# You can share secrets between all containers.
+common: {}
+
+# You can specify secrets by pod.
+pods: {}
+
+# You can override secrets for each target.
+targets: {}
+";
+
+/// We implement `secrets` with a trait so we put it in its own module.
+pub trait CommandSecrets {
+    /// Decrypt `config/secrets.yml.age` to a temporary file, open it in
+    /// `$VISUAL` or `$EDITOR`, and encrypt it again when the editor exits.
+    /// If we don't have a key yet, generate one.
+    fn secrets_edit<CR>(&self, runner: &CR) -> Result<()>
+    where
+        CR: CommandRunner;
+}
+
+impl CommandSecrets for Project {
+    fn secrets_edit<CR>(&self, runner: &CR) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let path = secrets::Plugin::encrypted_config_path(self);
+        let mut keys = Keys::load()?;
+        if keys.first().is_none() && !path.exists() {
+            let key_path = Keys::path()?;
+            keys = Keys::generate(&key_path)?;
+            println!(
+                "Generated a new key in {} (share it with your team securely)",
+                key_path.display()
+            );
+        }
+
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_owned());
+        let editor = shlex::split(&editor)
+            .filter(|args| !args.is_empty())
+            .ok_or_else(|| Error::parse("editor command", editor))?;
+        edit_encrypted_file(runner, &path, &keys, &editor)
+    }
+}
+
+/// Decrypt `path` to a temporary file, run `editor` on it, and encrypt the
+/// result.  We only delete the temporary file once we've encrypted the
+/// result, so that if the edited secrets are invalid, the user doesn't lose
+/// their changes.
+fn edit_encrypted_file<CR>(
+    runner: &CR,
+    path: &Path,
+    keys: &Keys,
+    editor: &[String],
+) -> Result<()>
+where
+    CR: CommandRunner,
+{
+    let (original, key) = if path.exists() {
+        keys.decrypt(path)?
+    } else {
+        let key = keys.first().expect("should have generated a key");
+        (NEW_SECRETS.to_owned(), key)
+    };
+
+    let tmp = temp_secrets_path();
+    secrets::write_private_file(&tmp, original.as_bytes())?;
+    let result = runner
+        .build(&editor[0])
+        .args(&editor[1..])
+        .arg(&tmp)
+        .exec()
+        .and_then(|()| {
+            fs::read_to_string(&tmp).map_err(|e| {
+                anyhow::Error::new(e).context(Error::CouldNotReadFile(tmp.clone()))
+            })
+        })
+        .and_then(|edited| {
+            if edited == original {
+                println!("No changes to {}", path.display());
+                return Ok(());
+            }
+            secrets::check_config(path, &edited)?;
+            secrets::encrypt_to_file(path, &edited, key)?;
+            println!("Encrypted {}", path.display());
+            Ok(())
+        });
+    if result.is_err() {
+        eprintln!(
+            "Your changes have not been saved, but are still in {}",
+            tmp.display()
+        );
+    } else if let Err(e) = fs::remove_file(&tmp) {
+        warn!("Could not delete {}: {}", tmp.display(), e);
+    }
+    result
+}
+
+/// The temporary file in which we let the user edit their secrets.
+fn temp_secrets_path() -> PathBuf {
+    env::temp_dir().join(format!("cage-secrets-{}.yml", process::id()))
+}
+
+#[test]
+fn edits_and_reencrypts_secrets() {
+    use age::secrecy::ExposeSecret;
+    use age::x25519;
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("rails_hello").unwrap();
+    let runner = OsCommandRunner::new();
+    let key = x25519::Identity::generate();
+    let key_text = key.to_string().expose_secret().to_owned();
+    let path = proj.output_dir().join("secrets.yml.age");
+    fs::create_dir_all(proj.output_dir()).unwrap();
+
+    // Our "editor" replaces the file with new secrets.
+    let editor: Vec<String> = vec![
+        "sh".into(),
+        "-c".into(),
+        "echo 'common: {PASSWORD: hunter2}' > \"$0\"".into(),
+    ];
+    let keys = Keys::parse(&key_text).unwrap();
+    edit_encrypted_file(&runner, &path, &keys, &editor).unwrap();
+    let (plaintext, _) = keys.decrypt(&path).unwrap();
+    assert_eq!(plaintext, "common: {PASSWORD: hunter2}\n");
+
+    assert!(!temp_secrets_path().exists());
+
+    // Invalid secrets are never saved, but we keep the user's changes.
+    let editor: Vec<String> =
+        vec!["sh".into(), "-c".into(), "echo 'common: [' > \"$0\"".into()];
+    assert!(edit_encrypted_file(&runner, &path, &keys, &editor).is_err());
+    let (plaintext, _) = keys.decrypt(&path).unwrap();
+    assert_eq!(plaintext, "common: {PASSWORD: hunter2}\n");
+    let kept = fs::read_to_string(temp_secrets_path()).unwrap();
+    assert_eq!(kept, "common: [\n");
+    fs::remove_file(temp_secrets_path()).unwrap();
+
+    proj.remove_test_output().unwrap();
+}
@@ -1,11 +1,15 @@
 //! The `source` subcommand.
 
 use colored::*;
+use faraday_compose_yml::v2 as dc;
+use rayon::prelude::*;
+use std::path::Path;
 
 use crate::args::act_on_sources::ActOnSources;
 use crate::command_runner::CommandRunner;
 use crate::errors::*;
 use crate::project::Project;
+use crate::sources::{PullOutcome, SOURCES_LOCK_YML};
 
 /// We implement `source` with a trait so we put it in its own module.
 pub trait CommandSource {
@@ -53,6 +57,14 @@ impl CommandSource for Project {
                 };
                 println!("  Available at {} {}", path.display(), mounted);
             }
+            for name in source.worktrees() {
+                let active = if source.worktree() == Some(name) {
+                    " (selected)"
+                } else {
+                    ""
+                };
+                println!("  Worktree {}{}", name, active);
+            }
         }
         Ok(())
     }
@@ -116,4 +128,316 @@ impl CommandSource for Project {
     }
 }
 
+/// Commands which work with the git checkouts of source trees, such as
+/// `source status`, `source pull`, `source lock`, `source worktree` and
+/// `source link`.
+pub trait CommandSourceGit {
+    /// Show the git state of the specified source trees, and whether they're
+    /// mounted.
+    fn source_status<CR>(
+        &self,
+        runner: &CR,
+        act_on_sources: ActOnSources,
+    ) -> Result<()>
+    where
+        CR: CommandRunner;
+
+    /// Fetch and fast-forward the specified source trees in parallel.  When
+    /// acting on all source trees, we skip any that haven't been cloned.
+    fn source_pull<CR>(&self, runner: &CR, act_on_sources: ActOnSources) -> Result<()>
+    where
+        CR: CommandRunner + Sync;
+
+    /// Lock the specified source trees to their current commits, and write
+    /// the project's lockfile.
+    fn source_lock<CR>(
+        &mut self,
+        runner: &CR,
+        act_on_sources: ActOnSources,
+    ) -> Result<()>
+    where
+        CR: CommandRunner;
+
+    /// Check out `branch` of the specified source tree in a new git
+    /// worktree.
+    fn source_worktree_add<CR>(
+        &mut self,
+        runner: &CR,
+        alias: &str,
+        branch: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner;
+
+    /// Choose which worktree of the specified source tree gets mounted, or
+    /// pass `None` to mount the main checkout.
+    fn source_worktree_use(&mut self, alias: &str, name: Option<&str>) -> Result<()>;
+
+    /// Remove a worktree from the specified source tree.
+    fn source_worktree_remove<CR>(
+        &mut self,
+        runner: &CR,
+        alias: &str,
+        name: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner;
+
+    /// Use an existing checkout in `dir` for the specified source tree, and
+    /// mount it.
+    fn source_link<CR>(&mut self, runner: &CR, alias: &str, dir: &Path) -> Result<()>
+    where
+        CR: CommandRunner;
+
+    /// Go back to using the checkout in `src/` for the specified source
+    /// tree.
+    fn source_unlink(&mut self, alias: &str) -> Result<()>;
+}
+
+impl CommandSourceGit for Project {
+    fn source_status<CR>(
+        &self,
+        runner: &CR,
+        act_on_sources: ActOnSources,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let sources_dirs = self.sources_dirs();
+        for source in act_on_sources.sources(self.sources()) {
+            println!("{:25} {}", source.alias().green(), source.context());
+            let mounted = if source.mounted() {
+                "mounted"
+            } else {
+                "not mounted"
+            };
+            let status = match source.git_status(runner, &sources_dirs)? {
+                Some(status) => status,
+                None if source.is_cloned_git_repository(&sources_dirs) => {
+                    // We're doing a dry run, so `git status` didn't run.
+                    println!("  Cloned ({})", mounted);
+                    continue;
+                }
+                None if source.is_available_locally(&sources_dirs) => {
+                    println!("  Local directory ({})", mounted);
+                    continue;
+                }
+                None => {
+                    println!("  Not cloned");
+                    continue;
+                }
+            };
+
+            // Compare our branch to the one in our git URL, if any.
+            let branch = match (&status.branch, source.pinned_branch()) {
+                (Some(branch), Some(pinned)) if branch != pinned => format!(
+                    "{} {}",
+                    branch.red().bold(),
+                    format!("(pinned to {})", pinned).red()
+                ),
+                (Some(branch), _) => branch.to_owned(),
+                (None, _) => "(detached HEAD)".yellow().to_string(),
+            };
+            let tracking = match status.upstream {
+                Some(ref upstream) => format!(
+                    ", {} ahead and {} behind {}",
+                    status.ahead, status.behind, upstream
+                ),
+                None => ", no upstream".to_owned(),
+            };
+            println!("  On branch {}{} ({})", branch, tracking, mounted);
+
+            // Uncommitted changes matter most when they're mounted into
+            // running containers.
+            if !status.dirty_files.is_empty() {
+                let summary =
+                    format!("{} uncommitted change(s)", status.dirty_files.len());
+                if source.mounted() {
+                    println!("  {}", summary.red().bold());
+                } else {
+                    println!("  {}", summary.yellow());
+                }
+                for path in &status.dirty_files {
+                    println!("    {}", path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn source_pull<CR>(&self, runner: &CR, act_on_sources: ActOnSources) -> Result<()>
+    where
+        CR: CommandRunner + Sync,
+    {
+        let sources_dirs = self.sources_dirs();
+        let work: Vec<_> = act_on_sources
+            .sources(self.sources())
+            .filter(|source| match act_on_sources {
+                ActOnSources::All => {
+                    matches!(source.context(), dc::Context::GitUrl(_))
+                        && source.is_available_locally(&sources_dirs)
+                }
+                ActOnSources::Named(_) => true,
+            })
+            .collect();
+
+        let results: Vec<Result<PullOutcome>> = work
+            .par_iter()
+            .map(|source| source.pull_source(runner, &sources_dirs))
+            .collect();
+
+        // Summarize what happened, in alias order.
+        let mut failures = 0usize;
+        for (source, result) in work.iter().zip(results) {
+            let alias = format!("{:25}", source.alias());
+            match result {
+                Ok(PullOutcome::Updated { from, to }) => println!(
+                    "  {}      {} updated {}..{}",
+                    "[ok]".green().bold(),
+                    alias,
+                    short_commit(&from),
+                    short_commit(&to),
+                ),
+                Ok(PullOutcome::UpToDate) => println!(
+                    "  {}      {} already up to date",
+                    "[ok]".green().bold(),
+                    alias
+                ),
+                Ok(PullOutcome::Skipped(reason)) => {
+                    println!("  {} {} {}", "[skipped]".yellow().bold(), alias, reason)
+                }
+                Err(e) => {
+                    failures += 1;
+                    eprintln!("  {}   {} {:#}", "[error]".red().bold(), alias, e);
+                }
+            }
+        }
+
+        if failures > 0 {
+            Err(anyhow::anyhow!(
+                "{} source tree(s) failed to update",
+                failures
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn source_lock<CR>(
+        &mut self,
+        runner: &CR,
+        act_on_sources: ActOnSources,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        // We need to run `git` to find out which commits to lock to.
+        if runner.is_dry_run() {
+            println!("Not writing {} during a dry run", SOURCES_LOCK_YML);
+            return Ok(());
+        }
+
+        let sources_dirs = self.sources_dirs();
+        for source in act_on_sources.sources_mut(self.sources_mut()) {
+            if !matches!(source.context(), dc::Context::GitUrl(_)) {
+                continue;
+            }
+            let commit = source.resolve_commit(runner, &sources_dirs)?;
+            println!(
+                "  {} {:25} {}",
+                "[locked]".green().bold(),
+                source.alias(),
+                short_commit(&commit)
+            );
+            source.set_locked_commit(Some(commit));
+        }
+        self.sources().save_lockfile(self.root_dir())?;
+        println!("Wrote {}", SOURCES_LOCK_YML);
+        Ok(())
+    }
+
+    fn source_worktree_add<CR>(
+        &mut self,
+        runner: &CR,
+        alias: &str,
+        branch: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let sources_dirs = self.sources_dirs();
+        let source = self
+            .sources_mut()
+            .find_by_alias_mut(alias)
+            .ok_or_else(|| Error::UnknownSource(alias.to_owned()))?;
+        source.add_worktree(runner, &sources_dirs, branch)?;
+        self.save_settings()?;
+        println!(
+            "Now run `cage source worktree use {} {}` to mount it.",
+            alias, branch
+        );
+        Ok(())
+    }
+
+    fn source_worktree_use(&mut self, alias: &str, name: Option<&str>) -> Result<()> {
+        let source = self
+            .sources_mut()
+            .find_by_alias_mut(alias)
+            .ok_or_else(|| Error::UnknownSource(alias.to_owned()))?;
+        source.set_worktree(name)?;
+        self.save_settings()?;
+        println!("Now run `cage up` for these changes to take effect.");
+        Ok(())
+    }
+
+    fn source_worktree_remove<CR>(
+        &mut self,
+        runner: &CR,
+        alias: &str,
+        name: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let sources_dirs = self.sources_dirs();
+        let source = self
+            .sources_mut()
+            .find_by_alias_mut(alias)
+            .ok_or_else(|| Error::UnknownSource(alias.to_owned()))?;
+        source.remove_worktree(runner, &sources_dirs, name)?;
+        self.save_settings()?;
+        Ok(())
+    }
+
+    fn source_link<CR>(&mut self, runner: &CR, alias: &str, dir: &Path) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let source = self
+            .sources_mut()
+            .find_by_alias_mut(alias)
+            .ok_or_else(|| Error::UnknownSource(alias.to_owned()))?;
+        source.link_to(runner, dir)?;
+        self.save_settings()?;
+        println!("Now run `cage up` for these changes to take effect.");
+        Ok(())
+    }
+
+    fn source_unlink(&mut self, alias: &str) -> Result<()> {
+        let source = self
+            .sources_mut()
+            .find_by_alias_mut(alias)
+            .ok_or_else(|| Error::UnknownSource(alias.to_owned()))?;
+        source.unlink();
+        self.save_settings()?;
+        println!("Now run `cage up` for these changes to take effect.");
+        Ok(())
+    }
+}
+
+/// Abbreviate a commit SHA for display.
+fn short_commit(commit: &str) -> &str {
+    commit.get(..7).unwrap_or(commit)
+}
+
 // No tests because this is a very thin wrapper over `Sources` and `Source`.
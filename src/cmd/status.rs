@@ -8,10 +8,12 @@ use crate::command_runner::CommandRunner;
 use crate::errors::*;
 use crate::ext::port_mapping::PortMappingExt;
 use crate::ext::service::ServiceExt;
-use crate::pod::Pod;
+use crate::pod::{Pod, PodType};
 use crate::project::{PodOrService, Project};
-use crate::runtime_state::{ContainerStatus, RuntimeState};
-use crate::sources::Source;
+use crate::runtime_state::{
+    ContainerInfo, ContainerStatus, HealthStatus, RuntimeState,
+};
+use crate::sources::SOURCES_LOCK_YML;
 
 /// We implement `status` with a trait so we can put it in its own
 /// module.
@@ -24,77 +26,163 @@ pub trait CommandStatus {
 }
 
 impl CommandStatus for Project {
-    fn status<CR>(&self, _runner: &CR, act_on: &args::ActOn) -> Result<()>
+    fn status<CR>(&self, runner: &CR, act_on: &args::ActOn) -> Result<()>
     where
         CR: CommandRunner,
     {
-        let state = RuntimeState::for_project(self)?;
-        for pod_or_service in act_on.pods_or_services(self) {
-            match pod_or_service? {
-                PodOrService::Pod(pod) => self.pod_status(&state, pod)?,
-                PodOrService::Service(pod, service_name) => {
-                    self.pod_header(pod)?;
-                    let service =
-                        pod.service_or_err(self.current_target(), service_name)?;
-                    self.service_status(&state, pod, service_name, &service, true)?;
-                }
+        let status = self.project_status(act_on)?;
+        for pod in &status.pods {
+            pod.print_tree();
+        }
+
+        // Warn about any source trees which have drifted from their locked
+        // commits.
+        let sources_dirs = self.sources_dirs();
+        for source in self.sources().iter() {
+            if let Some(head) = source.lock_drift(runner, &sources_dirs)? {
+                warn!(
+                    "source tree '{}' is at {}, but {} locks it to {}",
+                    source.alias(),
+                    head,
+                    SOURCES_LOCK_YML,
+                    source.locked_commit().unwrap_or_default(),
+                );
             }
         }
         Ok(())
     }
 }
 
-impl Project {
-    /// Display information about a pod, but not any of its services.
-    fn pod_header(&self, pod: &Pod) -> Result<()> {
-        let enabled = if pod.enabled_in(self.current_target()) {
+/// The status of a project, as returned by `Project::project_status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProjectStatus {
+    /// The name of the project.
+    pub name: String,
+
+    /// The name of the current target.
+    pub target: String,
+
+    /// The pods we were asked about, in the order we were asked about them.
+    pub pods: Vec<PodStatus>,
+}
+
+/// The status of a single pod.
+#[derive(Debug, Clone, Serialize)]
+pub struct PodStatus {
+    /// The name of this pod.
+    pub name: String,
+
+    /// Is this pod enabled in the current target?
+    pub enabled: bool,
+
+    /// What kind of pod is this?
+    pub pod_type: PodType,
+
+    /// The services in this pod.  If we were asked about a single service,
+    /// this will only contain that service.
+    pub services: Vec<ServiceStatus>,
+}
+
+/// The status of a single service.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceStatus {
+    /// The name of this service.
+    pub name: String,
+
+    /// Host ports declared for this service in its `ports` section.
+    pub ports: Vec<String>,
+
+    /// The containers which Docker is running for this service.
+    pub containers: Vec<ContainerSummary>,
+
+    /// The source trees used by this service.
+    pub sources: Vec<SourceStatus>,
+}
+
+/// The status of a single Docker container.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerSummary {
+    /// The current state of this container.
+    pub state: ContainerStatus,
+
+    /// The exit code of this container, if it has stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i64>,
+
+    /// The state of this container's health check.
+    pub health: HealthStatus,
+
+    /// Was this container created by `docker-compose run`?
+    pub one_off: bool,
+
+    /// The host addresses where this container's TCP ports are published.
+    pub ports: Vec<String>,
+}
+
+impl ContainerSummary {
+    /// Summarize the information we got from Docker.
+    fn new(info: &ContainerInfo) -> ContainerSummary {
+        ContainerSummary {
+            state: info.state(),
+            exit_code: info.state().exit_code(),
+            health: info.health(),
+            one_off: info.is_one_off(),
+            ports: info
+                .published_tcp_ports()
+                .values()
+                .flatten()
+                .map(|addr| addr.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// The status of a source tree used by a service.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    /// The short alias of this source tree.
+    pub alias: String,
+
+    /// Has this source tree been cloned locally?
+    pub cloned: bool,
+
+    /// Is this source tree currently mounted into the service's
+    /// containers?
+    pub mounted: bool,
+}
+
+impl PodStatus {
+    /// Display information about a pod and its services as a tree.
+    fn print_tree(&self) {
+        let enabled = if self.enabled {
             "enabled".normal()
         } else {
             "disabled".red().bold()
         };
         println!(
             "{:15} {} type:{}",
-            pod.name().blue().bold(),
+            self.name.blue().bold(),
             enabled,
-            pod.pod_type()
+            self.pod_type
         );
-        Ok(())
-    }
-
-    /// Display information about a pod and its services.
-    fn pod_status(&self, state: &RuntimeState, pod: &Pod) -> Result<()> {
-        self.pod_header(pod)?;
-        let file = pod.merged_file(self.current_target())?;
-        for (i, (service_name, service)) in file.services.iter().enumerate() {
-            self.service_status(
-                state,
-                pod,
-                service_name,
-                service,
-                i + 1 == file.services.len(),
-            )?;
+        for (i, service) in self.services.iter().enumerate() {
+            service.print_tree(i + 1 == self.services.len());
         }
-        Ok(())
     }
+}
 
-    /// Display information about a service.
-    fn service_status(
-        &self,
-        state: &RuntimeState,
-        _pod: &Pod,
-        service_name: &str,
-        service: &dc::Service,
-        last: bool,
-    ) -> Result<()> {
+impl ServiceStatus {
+    /// Display a one-line summary of this service.
+    fn print_tree(&self, last: bool) {
         if last {
-            print!("└─ {:12}", service_name.blue().bold());
+            print!("└─ {:12}", self.name.blue().bold());
         } else {
-            print!("├─ {:12}", service_name.blue().bold());
+            print!("├─ {:12}", self.name.blue().bold());
         }
 
         // Print out our runtime status.
-        for container in state.service_containers(service_name) {
-            let text = match container.state() {
+        for container in &self.containers {
+            let text = match container.state {
                 ContainerStatus::Running => "RUNNING".green().bold(),
                 ContainerStatus::Done => "DONE".green(),
                 ContainerStatus::Exited(_) => "EXITED".red().bold(),
@@ -104,6 +192,87 @@ impl Project {
         }
 
         // Print out ports with known host bindings.
+        if !self.ports.is_empty() {
+            print!(" ports:{}", self.ports.join(","));
+        }
+
+        // Print out mounted source code.
+        let source_names: Vec<&str> = self
+            .sources
+            .iter()
+            .filter(|s| s.mounted)
+            .map(|s| s.alias.as_str())
+            .collect();
+        if !source_names.is_empty() {
+            print!(" mounted:{}", source_names.join(","));
+        }
+
+        println!();
+    }
+}
+
+impl Project {
+    /// Get the current status of the project as data, suitable for
+    /// serializing as JSON or YAML.
+    pub fn project_status(&self, act_on: &args::ActOn) -> Result<ProjectStatus> {
+        let state = RuntimeState::for_project(self)?;
+        self.project_status_from_state(&state, act_on)
+    }
+
+    /// Build a `ProjectStatus` using an existing `RuntimeState`.
+    fn project_status_from_state(
+        &self,
+        state: &RuntimeState,
+        act_on: &args::ActOn,
+    ) -> Result<ProjectStatus> {
+        let mut pods = vec![];
+        for pod_or_service in act_on.pods_or_services(self) {
+            match pod_or_service? {
+                PodOrService::Pod(pod) => {
+                    let file = pod.merged_file(self.current_target())?;
+                    let services = file
+                        .services
+                        .iter()
+                        .map(|(name, service)| {
+                            self.service_status(state, name, service)
+                        })
+                        .collect::<Result<_>>()?;
+                    pods.push(self.pod_status(pod, services));
+                }
+                PodOrService::Service(pod, service_name) => {
+                    let service =
+                        pod.service_or_err(self.current_target(), service_name)?;
+                    let services =
+                        vec![self.service_status(state, service_name, &service)?];
+                    pods.push(self.pod_status(pod, services));
+                }
+            }
+        }
+        Ok(ProjectStatus {
+            name: self.name().to_owned(),
+            target: self.current_target().name().to_owned(),
+            pods,
+        })
+    }
+
+    /// Summarize information about a pod.
+    fn pod_status(&self, pod: &Pod, services: Vec<ServiceStatus>) -> PodStatus {
+        PodStatus {
+            name: pod.name().to_owned(),
+            enabled: pod.enabled_in(self.current_target()),
+            pod_type: pod.pod_type(),
+            services,
+        }
+    }
+
+    /// Summarize information about a service.
+    fn service_status(
+        &self,
+        state: &RuntimeState,
+        service_name: &str,
+        service: &dc::Service,
+    ) -> Result<ServiceStatus> {
+        // Collect ports with known host bindings.
         let ports: Vec<String> = service
             .ports
             .iter()
@@ -114,26 +283,72 @@ impl Project {
                 Err(err) => Some(Err(err)),
             })
             .collect::<Result<_>>()?;
-        if !ports.is_empty() {
-            print!(" ports:{}", ports.join(","));
-        }
 
-        // Print out mounted source code.
-        let sources: Vec<&Source> = service
+        // Collect the source code used by this service.
+        let sources_dirs = self.sources_dirs();
+        let sources = service
             .sources(self.sources())?
-            .map(|source_mount| Ok(source_mount.source))
+            .map(|source_mount| {
+                let source = source_mount.source;
+                let cloned = source.is_available_locally(&sources_dirs);
+                Ok(SourceStatus {
+                    alias: source.alias().to_owned(),
+                    cloned,
+                    mounted: cloned && source.mounted(),
+                })
+            })
             .collect::<Result<_>>()?;
-        let sources_dirs = self.sources_dirs();
-        let source_names: Vec<&str> = sources
-            .iter()
-            .filter(|s| s.is_available_locally(&sources_dirs) && s.mounted())
-            .map(|s| s.alias())
-            .collect();
-        if !source_names.is_empty() {
-            print!(" mounted:{}", source_names.join(","));
-        }
 
-        println!();
-        Ok(())
+        Ok(ServiceStatus {
+            name: service_name.to_owned(),
+            ports,
+            containers: state
+                .service_containers(service_name)
+                .iter()
+                .map(ContainerSummary::new)
+                .collect(),
+            sources,
+        })
     }
 }
+
+#[test]
+fn project_status_summarizes_pods_services_and_containers() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("rails_hello").unwrap();
+    let state = RuntimeState::fake(&[
+        ("db", ContainerStatus::Running),
+        ("web", ContainerStatus::Exited(2)),
+    ]);
+    let status = proj
+        .project_status_from_state(&state, &args::ActOn::All)
+        .unwrap();
+
+    assert_eq!(status.target, "development");
+    let pod_names: Vec<&str> = status.pods.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(pod_names, &["db", "frontend", "rake"]);
+
+    let frontend = &status.pods[1];
+    assert!(frontend.enabled);
+    assert_eq!(frontend.pod_type, PodType::Service);
+    let web = &frontend.services[0];
+    assert_eq!(web.name, "web");
+    assert_eq!(web.ports, &["3000"]);
+    assert_eq!(web.containers.len(), 1);
+    assert_eq!(web.containers[0].state, ContainerStatus::Exited(2));
+    assert_eq!(web.containers[0].exit_code, Some(2));
+    let aliases: Vec<&str> = web.sources.iter().map(|s| s.alias.as_str()).collect();
+    assert_eq!(aliases, &["rails_hello", "coffee_rails"]);
+    assert!(web.sources.iter().all(|s| !s.mounted));
+
+    let json = serde_json::to_value(&status).unwrap();
+    assert_eq!(
+        json["pods"][0]["services"][0]["containers"][0]["state"],
+        "running"
+    );
+    assert_eq!(
+        json["pods"][1]["services"][0]["containers"][0]["state"]["exited"],
+        2
+    );
+    assert_eq!(json["pods"][1]["pod_type"], "service");
+}
@@ -3,13 +3,13 @@
 use std::thread;
 use std::time;
 
-use crate::args;
+use crate::args::{self, ToArgs};
 use crate::cmd::{CommandCompose, CommandRun};
 use crate::command_runner::CommandRunner;
 #[cfg(test)]
 use crate::command_runner::TestCommandRunner;
 use crate::errors::*;
-use crate::pod::{Pod, PodType};
+use crate::pod::{Pod, PodType, WaitFor};
 use crate::project::{PodOrService, Project};
 use crate::runtime_state::RuntimeState;
 
@@ -25,6 +25,20 @@ pub trait CommandUp {
     where
         CR: CommandRunner;
 
+    /// Like `up`, but if `Project::jobs` is greater than 1, start
+    /// independent pods in parallel.  By default, this just calls `up`.
+    fn up_parallel<CR>(
+        &self,
+        runner: &CR,
+        act_on: &args::ActOn,
+        opts: &args::opts::Up,
+    ) -> Result<()>
+    where
+        CR: CommandRunner + Sync,
+    {
+        self.up(runner, act_on, opts)
+    }
+
     /// Run the initialization functions for the specified pod.
     fn init_pod<CR>(&self, runner: &CR, pod: &Pod) -> Result<()>
     where
@@ -42,14 +56,11 @@ impl CommandUp for Project {
         CR: CommandRunner,
     {
         let pods_or_services = act_on
-            .pods_or_services(self)
-            // TODO LOW: Refactor this into a `filter_result` helper?
-            .filter(|v| match *v {
-                Ok(ref p_s) => p_s.pod_type() != PodType::Task,
-                Err(_) => true,
-            });
+            .pods_or_services_in_order(self, args::DependencyOrder::Forward)?
+            .into_iter()
+            .filter(|p_s| p_s.pod_type() != PodType::Task);
         for pod_or_service in pods_or_services {
-            match pod_or_service? {
+            match pod_or_service {
                 PodOrService::Pod(pod) => {
                     self.compose_pod(runner, "up", pod, opts)?;
                     if opts.init {
@@ -64,6 +75,47 @@ impl CommandUp for Project {
         Ok(())
     }
 
+    /// Start each stage of pods in parallel, and then initialize the pods in
+    /// that stage one at a time before moving on to the next stage.
+    fn up_parallel<CR>(
+        &self,
+        runner: &CR,
+        act_on: &args::ActOn,
+        opts: &args::opts::Up,
+    ) -> Result<()>
+    where
+        CR: CommandRunner + Sync,
+    {
+        if self.jobs() <= 1 {
+            return self.up(runner, act_on, opts);
+        }
+
+        let args = opts.to_args();
+        let stages = act_on
+            .pods_or_services_in_stages(self, args::DependencyOrder::Forward)?
+            .into_iter()
+            .map(|stage| {
+                stage
+                    .into_iter()
+                    .filter(|p_s| p_s.pod_type() != PodType::Task)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|stage| !stage.is_empty());
+        for stage in stages {
+            self.in_parallel(&stage, |pod_or_service| {
+                self.compose_pod_or_service(runner, "up", pod_or_service, &args, true)
+            })?;
+            if opts.init {
+                for pod_or_service in &stage {
+                    if let PodOrService::Pod(pod) = *pod_or_service {
+                        self.init_pod(runner, pod)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn init_pod<CR>(&self, runner: &CR, pod: &Pod) -> Result<()>
     where
         CR: CommandRunner,
@@ -73,12 +125,35 @@ impl CommandUp for Project {
             return Ok(());
         }
 
-        println!("Waiting for pod '{}' to be running", pod.name());
-        loop {
+        let wait_for = pod.wait_for();
+        let timeout = pod.wait_timeout();
+        let started = time::Instant::now();
+        println!("Waiting for pod '{}' to be {}", pod.name(), wait_for);
+        // Nothing was actually started during a dry run, so don't wait.
+        while !runner.is_dry_run() {
             let state: RuntimeState = RuntimeState::for_project(self)?;
-            if state.all_services_in_pod_are_running(pod) {
+            // Only give up on unhealthy containers if we were asked to
+            // wait for health checks.
+            if wait_for == WaitFor::Healthy {
+                if let Some(service) = state.unhealthy_service_in_pod(pod) {
+                    return Err(Error::ServiceUnhealthy {
+                        pod: pod.name().to_owned(),
+                        service: service.to_owned(),
+                    }
+                    .into());
+                }
+            }
+            if state.all_services_in_pod_are_ready(pod, wait_for) {
                 break;
             }
+            if started.elapsed() >= timeout {
+                return Err(Error::PodNotReady {
+                    pod: pod.name().to_owned(),
+                    wait_for,
+                    seconds: timeout.as_secs(),
+                }
+                .into());
+            }
             thread::sleep(time::Duration::from_millis(250));
         }
 
@@ -128,3 +203,24 @@ fn runs_docker_compose_up_honors_enable_in_targets() {
 
     proj.remove_test_output().unwrap();
 }
+
+#[test]
+fn dry_run_up_init_prints_init_commands_without_waiting() {
+    use crate::command_runner::DryRunCommandRunner;
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("rails_hello").unwrap();
+    let runner = DryRunCommandRunner::new();
+    proj.output("up").unwrap();
+
+    let pod = proj.pod("db").unwrap();
+    proj.init_pod(&runner, pod).unwrap();
+    let cmds = runner.cmds();
+    assert_eq!(cmds.len(), 3);
+    assert!(cmds[0].ends_with(
+        "run db bash -c 'while ! pg_isready -q -h db; do sleep 0.25; done'"
+    ));
+    assert!(cmds[2].ends_with("run rake db:migrate"));
+
+    proj.remove_test_output().unwrap();
+}
@@ -0,0 +1,101 @@
+//! The `vault` command.
+
+use colored::*;
+use std::time::{Duration, SystemTime};
+
+use crate::errors::*;
+use crate::plugins::transform::vault::{CachedToken, Plugin};
+use crate::plugins::PluginNew;
+use crate::project::Project;
+use crate::util::err;
+
+/// We implement `vault` with a trait so we put it in its own module.
+pub trait CommandVault {
+    /// List the Vault tokens which we've issued to services in the current
+    /// target, and how long they have left.
+    fn vault_tokens(&self) -> Result<()>;
+
+    /// Revoke the Vault tokens which we've issued to services in the
+    /// current target, typically because we're tearing the project down.
+    fn vault_revoke(&self) -> Result<()>;
+}
+
+impl CommandVault for Project {
+    fn vault_tokens(&self) -> Result<()> {
+        let tokens = Plugin::cached_tokens(self)?;
+        if tokens.is_empty() {
+            println!("No tokens issued in {}", self.current_target().name());
+        }
+        let now = SystemTime::now();
+        for token in &tokens {
+            let ttl = match token.expires.duration_since(now) {
+                Ok(remaining) => format_ttl(remaining).normal(),
+                Err(_) => "expired".red(),
+            };
+            println!(
+                "{:25} {:12} {}",
+                service_name(token).green(),
+                ttl,
+                policies(token)
+            );
+        }
+        Ok(())
+    }
+
+    fn vault_revoke(&self) -> Result<()> {
+        if !Plugin::is_configured_for(self)? {
+            return Err(err("this project does not use Vault"));
+        }
+        let plugin = Plugin::new(self)?;
+        let revoked = plugin.revoke_cached_tokens(self)?;
+        let verb = if self.dry_run() {
+            "Would revoke"
+        } else {
+            "Revoked"
+        };
+        for token in &revoked {
+            println!("{} token for {}", verb, service_name(token).green());
+        }
+        println!(
+            "{} {} tokens in {}",
+            verb,
+            revoked.len(),
+            self.current_target().name()
+        );
+        Ok(())
+    }
+}
+
+/// The `pod/service` that `token` belongs to.
+fn service_name(token: &CachedToken) -> String {
+    format!("{}/{}", token.pod, token.service)
+}
+
+/// The policies assigned to `token`, separated by commas.
+fn policies(token: &CachedToken) -> String {
+    let policies: Vec<&str> = token.policies.iter().map(|p| p.as_str()).collect();
+    policies.join(", ")
+}
+
+/// Format a remaining TTL using its two largest units, like `29d 23h`.
+fn format_ttl(ttl: Duration) -> String {
+    let secs = ttl.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[test]
+fn formats_ttls_using_two_units() {
+    assert_eq!(format_ttl(Duration::from_secs(2592000 - 3600)), "29d 23h");
+    assert_eq!(format_ttl(Duration::from_secs(3 * 3600 + 125)), "3h 2m");
+    assert_eq!(format_ttl(Duration::from_secs(125)), "2m 5s");
+    assert_eq!(format_ttl(Duration::from_secs(5)), "5s");
+}
@@ -0,0 +1,412 @@
+//! The `watch` command.
+//!
+//! We poll for changes instead of using OS-specific file notification APIs,
+//! which don't work reliably for all the ways that source trees get
+//! mounted, and which would add a lot of dependencies.
+
+use colored::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use crate::args;
+use crate::cmd::{CommandCompose, CommandExec};
+use crate::command_runner::CommandRunner;
+#[cfg(test)]
+use crate::command_runner::TestCommandRunner;
+use crate::errors::*;
+use crate::ext::service::ServiceExt;
+use crate::project::{PodOrService, Project};
+use crate::util::ConductorPathExt;
+
+/// A pod name and the name of one of its services.  Different pods may
+/// contain services with the same name.
+type ServiceKey = (String, String);
+
+/// We implement `watch` with a trait so we put it in its own module.
+pub trait CommandWatch {
+    /// Watch the mounted source trees used by the specified pods or
+    /// services.  When files change, restart the services which use them,
+    /// or run the command in their `io.fdy.cage.watch.command` label.
+    /// Runs until interrupted.
+    fn watch<CR>(
+        &self,
+        runner: &CR,
+        act_on: &args::ActOn,
+        opts: &args::opts::Watch,
+    ) -> Result<()>
+    where
+        CR: CommandRunner;
+}
+
+impl CommandWatch for Project {
+    fn watch<CR>(
+        &self,
+        runner: &CR,
+        act_on: &args::ActOn,
+        opts: &args::opts::Watch,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let mut dirs = self.watched_dirs(act_on)?;
+        if dirs.is_empty() {
+            return Err(err!(
+                "no mounted source trees to watch (try `cage source mount`)"
+            ));
+        }
+        for dir in &dirs {
+            let services: Vec<String> = dir
+                .services
+                .keys()
+                .map(|(pod, service)| format!("{}/{}", pod, service))
+                .collect();
+            println!(
+                "Watching {} for {}",
+                dir.path.display(),
+                services.join(", ").green()
+            );
+        }
+
+        let mut pending: BTreeSet<ServiceKey> = BTreeSet::new();
+        let mut last_change: Option<Instant> = None;
+        loop {
+            thread::sleep(opts.poll_interval);
+            for dir in &mut dirs {
+                let changed = dir.rescan()?;
+                let services = dir.services_affected_by(&changed);
+                if !services.is_empty() {
+                    pending.extend(services);
+                    last_change = Some(Instant::now());
+                }
+            }
+
+            // Wait until things have been quiet for a while before acting.
+            let quiet = last_change.is_some_and(|t| t.elapsed() >= opts.debounce);
+            if quiet {
+                for (pod_name, service_name) in mem::take(&mut pending) {
+                    // Keep watching even if one service fails to restart.
+                    if let Err(e) =
+                        self.on_source_changed(runner, &pod_name, &service_name)
+                    {
+                        eprintln!("{} {:#}", "[error]".red().bold(), e);
+                    }
+                }
+                last_change = None;
+            }
+        }
+    }
+}
+
+impl Project {
+    /// Find the mounted source directories used by the specified pods or
+    /// services.
+    fn watched_dirs(&self, act_on: &args::ActOn) -> Result<Vec<WatchedDir>> {
+        let target = self.current_target();
+        let sources_dirs = self.sources_dirs();
+        let mut dirs: BTreeMap<PathBuf, WatchedDir> = BTreeMap::new();
+        for pod_or_service in act_on.pods_or_services(self) {
+            let (pod, only_service) = match pod_or_service? {
+                PodOrService::Pod(pod) => (pod, None),
+                PodOrService::Service(pod, name) => (pod, Some(name)),
+            };
+            if !pod.enabled_in(target) {
+                continue;
+            }
+            let file = pod.merged_file(target)?;
+            for (service_name, service) in &file.services {
+                if only_service.is_some_and(|name| name != service_name.as_str()) {
+                    continue;
+                }
+                let options = service.source_mount_options()?;
+                for source_mount in service.sources(self.sources())? {
+                    let source = source_mount.source;
+                    if !source.is_available_locally(&sources_dirs) || !source.mounted()
+                    {
+                        continue;
+                    }
+                    let path = source
+                        .path(&sources_dirs)
+                        .join(source_mount.source_subdirectory.unwrap_or_default())
+                        .to_absolute()?;
+
+                    // Changes to excluded directories aren't visible inside
+                    // the container.
+                    let ignored = if source_mount.is_build_context {
+                        options
+                            .excludes
+                            .iter()
+                            .map(|exclude| path.join(exclude.trim_matches('/')))
+                            .collect()
+                    } else {
+                        vec![]
+                    };
+
+                    dirs.entry(path.clone())
+                        .or_insert_with(|| WatchedDir::new(path))
+                        .services
+                        .insert(
+                            (pod.name().to_owned(), service_name.to_owned()),
+                            ignored,
+                        );
+                }
+            }
+        }
+
+        let mut dirs: Vec<WatchedDir> = dirs.into_values().collect();
+        for dir in &mut dirs {
+            dir.rescan()?;
+        }
+        Ok(dirs)
+    }
+
+    /// Restart `service_name` in `pod_name`, or run its
+    /// `io.fdy.cage.watch.command`.
+    fn on_source_changed<CR>(
+        &self,
+        runner: &CR,
+        pod_name: &str,
+        service_name: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let pod = self
+            .pod(pod_name)
+            .ok_or_else(|| Error::UnknownPodOrService(pod_name.to_owned()))?;
+        let file = pod.merged_file(self.current_target())?;
+        let service = file
+            .services
+            .get(service_name)
+            .ok_or_else(|| Error::UnknownService(service_name.to_owned()))?;
+        match service.watch_command()? {
+            Some(command) => {
+                println!(
+                    "{} {}: {}",
+                    "Source changed, running command in".bold(),
+                    service_name,
+                    command.join(" ")
+                );
+                let command = args::Command::new(&command[0]).with_args(&command[1..]);
+                let mut opts = args::opts::Exec::default();
+                opts.allocate_tty = false;
+                // Use the full name, in case another pod has a service with
+                // the same name.
+                let full_name = format!("{}/{}", pod_name, service_name);
+                self.exec(runner, &full_name, &command, &opts)
+            }
+            None => {
+                println!("{} {}", "Source changed, restarting".bold(), service_name);
+                self.compose_service(
+                    runner,
+                    "restart",
+                    pod,
+                    service_name,
+                    &args::opts::Empty,
+                )
+            }
+        }
+    }
+}
+
+/// A mounted source directory, and the services which use it.
+#[derive(Debug)]
+struct WatchedDir {
+    /// The directory on the host.
+    path: PathBuf,
+    /// The services which mount this directory, and the subdirectories
+    /// which each one ignores.
+    services: BTreeMap<ServiceKey, Vec<PathBuf>>,
+    /// The modification times of the files in this directory when we last
+    /// looked.
+    files: BTreeMap<PathBuf, SystemTime>,
+}
+
+impl WatchedDir {
+    /// Create a new `WatchedDir`, which has no services yet.
+    fn new(path: PathBuf) -> WatchedDir {
+        WatchedDir {
+            path,
+            services: BTreeMap::new(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Look for files which have been added, modified or removed since we
+    /// last looked.
+    fn rescan(&mut self) -> Result<Vec<PathBuf>> {
+        let mut files = BTreeMap::new();
+        scan_dir(&self.path, &self.ignored_by_all_services(), &mut files)?;
+        let mut changed: Vec<PathBuf> = files
+            .iter()
+            .filter(|&(path, modified)| self.files.get(path) != Some(modified))
+            .map(|(path, _)| path.to_owned())
+            .collect();
+        changed.extend(
+            self.files
+                .keys()
+                .filter(|path| !files.contains_key(*path))
+                .cloned(),
+        );
+        self.files = files;
+        Ok(changed)
+    }
+
+    /// Which subdirectories are ignored by every service?  There's no point
+    /// in scanning these, and they're often huge.
+    fn ignored_by_all_services(&self) -> Vec<PathBuf> {
+        let first = match self.services.values().next() {
+            Some(ignored) => ignored,
+            None => return vec![],
+        };
+        first
+            .iter()
+            .filter(|dir| {
+                self.services
+                    .values()
+                    .all(|ignored| ignored.iter().any(|other| dir.starts_with(other)))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Which services need to know about changes to `changed`?
+    fn services_affected_by(&self, changed: &[PathBuf]) -> BTreeSet<ServiceKey> {
+        self.services
+            .iter()
+            .filter(|&(_, ignored)| {
+                changed
+                    .iter()
+                    .any(|path| !ignored.iter().any(|dir| path.starts_with(dir)))
+            })
+            .map(|(key, _)| key.to_owned())
+            .collect()
+    }
+}
+
+/// Record the modification time of every file under `dir`, skipping `.git`
+/// directories and anything under `skipped`.  We don't follow symlinks, so
+/// we can't get stuck in loops.
+fn scan_dir(
+    dir: &Path,
+    skipped: &[PathBuf],
+    files: &mut BTreeMap<PathBuf, SystemTime>,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // Files may be deleted while we're scanning.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(
+                anyhow::Error::new(e).context(Error::CouldNotReadFile(dir.to_owned()))
+            )
+        }
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            let is_skipped = skipped.iter().any(|dir| path.starts_with(dir));
+            if entry.file_name() != ".git" && !is_skipped {
+                scan_dir(&path, skipped, files)?;
+            }
+        } else if let Ok(modified) = entry.metadata().and_then(|m| m.modified()) {
+            files.insert(path, modified);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn changed_files_are_mapped_to_services() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_fixture("with_source_mount_options").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    {
+        let source = proj.sources_mut().find_by_alias_mut("rails_hello").unwrap();
+        source.fake_clone_source(&sources_dirs).unwrap();
+    }
+
+    let mut dirs = proj.watched_dirs(&args::ActOn::All).unwrap();
+    assert_eq!(dirs.len(), 1);
+    let dir = &mut dirs[0];
+    let services: Vec<&str> = dir.services.keys().map(|(_, s)| s.as_str()).collect();
+    assert_eq!(services, &["web", "worker"]);
+    assert!(dir.rescan().unwrap().is_empty());
+
+    // `web` excludes `node_modules`, so only `worker` sees changes there.
+    fs::create_dir_all(dir.path.join("node_modules")).unwrap();
+    fs::write(dir.path.join("node_modules/left-pad.js"), "").unwrap();
+    let changed = dir.rescan().unwrap();
+    assert_eq!(changed, vec![dir.path.join("node_modules/left-pad.js")]);
+    let affected: Vec<String> = dir
+        .services_affected_by(&changed)
+        .into_iter()
+        .map(|(_, service)| service)
+        .collect();
+    assert_eq!(affected, &["worker"]);
+
+    // Everybody sees other changes, including deleted files.
+    fs::remove_file(dir.path.join("node_modules/left-pad.js")).unwrap();
+    fs::write(dir.path.join("Gemfile"), "").unwrap();
+    let changed = dir.rescan().unwrap();
+    assert_eq!(changed.len(), 2);
+    let affected: Vec<String> = dir
+        .services_affected_by(&changed)
+        .into_iter()
+        .map(|(_, service)| service)
+        .collect();
+    assert_eq!(affected, &["web", "worker"]);
+
+    // If every service ignores `node_modules`, we don't even look inside it.
+    let node_modules = dir.path.join("node_modules");
+    let worker = ("frontend".to_owned(), "worker".to_owned());
+    dir.services.insert(worker, vec![node_modules.clone()]);
+    assert_eq!(dir.ignored_by_all_services(), vec![node_modules.clone()]);
+    fs::write(node_modules.join("left-pad.js"), "").unwrap();
+    assert!(dir.rescan().unwrap().is_empty());
+
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn changes_restart_services_or_run_watch_commands() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_source_mount_options").unwrap();
+    let runner = TestCommandRunner::new();
+    proj.output("watch").unwrap();
+
+    proj.on_source_changed(&runner, "frontend", "web").unwrap();
+    proj.on_source_changed(&runner, "frontend", "worker")
+        .unwrap();
+    let pod_file = proj.output_dir().join("pods").join("frontend.yml");
+    assert_ran!(runner, {
+        [
+            "docker-compose",
+            "-p",
+            "withsourcemountoptions",
+            "-f",
+            &pod_file,
+            "exec",
+            "-T",
+            "web",
+            "touch",
+            "tmp/restart.txt",
+        ],
+        [
+            "docker-compose",
+            "-p",
+            "withsourcemountoptions",
+            "-f",
+            &pod_file,
+            "restart",
+            "worker",
+        ]
+    });
+
+    proj.remove_test_output().unwrap();
+}
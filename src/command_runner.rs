@@ -3,9 +3,10 @@
 use std::ffi::{OsStr, OsString};
 use std::io::{self, Write};
 use std::marker::PhantomData;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
 
 use colored::Colorize;
 
@@ -21,6 +22,12 @@ pub trait CommandRunner {
 
     /// Build a new command.
     fn build<S: AsRef<OsStr>>(&self, program: S) -> Self::Command;
+
+    /// Does this runner only print commands instead of running them?  If
+    /// so, callers shouldn't wait for those commands to have any effect.
+    fn is_dry_run(&self) -> bool {
+        false
+    }
 }
 
 /// A stripped down interface based on `std::process::Command`.  We use
@@ -72,10 +79,87 @@ pub trait Command {
         self.exec()
     }
 
+    /// Run our command, returning its standard output as a string.  Returns
+    /// an error if the command fails.  Intended for commands which only
+    /// query the state of something, like `git status`.  The default
+    /// implementation returns an error, for runners which can't capture
+    /// output.
+    fn output(&mut self) -> Result<String> {
+        Err(err!("this command runner can't capture command output"))
+    }
+
+    /// Like `output`, but write `input` to the command's standard input, and
+    /// return its standard output as raw bytes.  The default implementation
+    /// returns an error, for runners which can't capture output.
+    fn output_with_input(&mut self, _input: &[u8]) -> Result<Vec<u8>> {
+        Err(err!("this command runner can't capture command output"))
+    }
+
     /// Make an error representing a failure of this command.
     fn command_failed_error(&self) -> anyhow::Error;
 }
 
+/// The part of `CommandRunner` which plugins need.  Plugins are used as
+/// trait objects, so they can't be generic over our runner, but they can
+/// be handed one of these.
+pub trait CaptureOutput {
+    /// Run `program` with `args` in `dir`, and return its standard output.
+    /// During a dry run, we print the command and return `None` instead.
+    fn capture_output(
+        &self,
+        program: &str,
+        args: &[String],
+        dir: &Path,
+    ) -> Result<Option<String>>;
+
+    /// Like `capture_output`, but write `input` to the command's standard
+    /// input, and return its standard output as raw bytes.
+    fn capture_output_with_input(
+        &self,
+        program: &str,
+        _args: &[String],
+        _dir: &Path,
+        _input: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        Err(err!("can't send input to {}", program))
+    }
+}
+
+impl<CR: CommandRunner> CaptureOutput for CR {
+    fn capture_output(
+        &self,
+        program: &str,
+        args: &[String],
+        dir: &Path,
+    ) -> Result<Option<String>> {
+        let mut cmd = self.build(program);
+        cmd.args(args).current_dir(dir);
+        if self.is_dry_run() {
+            cmd.exec()?;
+            Ok(None)
+        } else {
+            Ok(Some(cmd.output()?))
+        }
+    }
+
+    fn capture_output_with_input(
+        &self,
+        program: &str,
+        args: &[String],
+        dir: &Path,
+        input: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let mut cmd = self.build(program);
+        cmd.args(args).current_dir(dir);
+        if self.is_dry_run() {
+            cmd.exec()?;
+            Ok(None)
+        } else {
+            Ok(Some(cmd.output_with_input(input)?))
+        }
+    }
+}
+
 /// Support for running operating system commands.
 #[derive(Debug, Default)]
 #[allow(missing_copy_implementations)]
@@ -174,6 +258,50 @@ impl Command for OsCommand {
         Err(self.command_failed_error())
     }
 
+    fn output(&mut self) -> Result<String> {
+        debug!("Running (for output) {:?}", &self.arg_log);
+        self.command.stdout(Stdio::piped()).stderr(Stdio::inherit());
+        let output = self.command.output().map_err(|e| {
+            anyhow::Error::new(e).context(Error::CommandFailed(self.arg_log.clone()))
+        })?;
+        if !output.status.success() {
+            return Err(self.command_failed_error());
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    fn output_with_input(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        debug!("Running (with input) {:?}", &self.arg_log);
+        let mut child = self
+            .command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| {
+                anyhow::Error::new(e)
+                    .context(Error::CommandFailed(self.arg_log.clone()))
+            })?;
+
+        // Write our input from another thread, so that we don't deadlock if
+        // the command starts writing before it has read everything.
+        let mut stdin = child.stdin.take().expect("stdin should be piped");
+        let input = input.to_owned();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().map_err(|e| {
+            anyhow::Error::new(e).context(Error::CommandFailed(self.arg_log.clone()))
+        })?;
+        if !output.status.success() {
+            return Err(self.command_failed_error());
+        }
+        // The command may legitimately ignore its input, so don't worry if
+        // we couldn't write all of it.
+        if let Ok(Err(e)) = writer.join() {
+            debug!("could not write input to {:?}: {}", &self.arg_log, e);
+        }
+        Ok(output.stdout)
+    }
+
     fn command_failed_error(&self) -> anyhow::Error {
         Error::CommandFailed(self.arg_log.clone()).into()
     }
@@ -184,6 +312,183 @@ fn os_command_runner_runs_commands() {
     let runner = OsCommandRunner::new();
     assert!(runner.build("true").status().unwrap().success());
     assert!(!runner.build("false").status().unwrap().success());
+    assert_eq!(runner.build("echo").arg("hi").output().unwrap(), "hi\n");
+    assert!(runner.build("false").output().is_err());
+    let output = runner.build("cat").output_with_input(b"hi").unwrap();
+    assert_eq!(output, b"hi");
+}
+
+/// Support for printing commands instead of running them, as requested by
+/// `cage --dry-run`.
+#[derive(Debug, Default)]
+pub struct DryRunCommandRunner {
+    /// The commands we would have run, formatted as shell commands.  Like
+    /// `TestCommandRunner`, this is `Send + Sync` so that we can use it
+    /// from parallel code paths.
+    cmds: Arc<Mutex<Vec<String>>>,
+}
+
+impl DryRunCommandRunner {
+    /// Create a new `DryRunCommandRunner`.
+    pub fn new() -> DryRunCommandRunner {
+        DryRunCommandRunner::default()
+    }
+
+    /// Access the list of commands we would have run.
+    pub fn cmds(&self) -> MutexGuard<'_, Vec<String>> {
+        self.cmds
+            .lock()
+            .expect("dry run command runner mutex poisoned")
+    }
+}
+
+impl CommandRunner for DryRunCommandRunner {
+    type Command = DryRunCommand;
+
+    fn build<S: AsRef<OsStr>>(&self, program: S) -> Self::Command {
+        DryRunCommand {
+            cmd: vec![program.as_ref().to_owned()],
+            env: vec![],
+            current_dir: None,
+            cmds: self.cmds.clone(),
+        }
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+/// A command which gets printed instead of run.
+#[derive(Debug)]
+pub struct DryRunCommand {
+    /// The command we're building.
+    cmd: Vec<OsString>,
+    /// Environment variables we would set.
+    env: Vec<(OsString, OsString)>,
+    /// The directory we would run in, if it's not the current one.
+    current_dir: Option<PathBuf>,
+    /// The list of commands we share with our `DryRunCommandRunner`.
+    cmds: Arc<Mutex<Vec<String>>>,
+}
+
+impl DryRunCommand {
+    /// Format this command so that it could be pasted into a shell.
+    fn to_shell_string(&self) -> String {
+        let mut words = vec![];
+        if let Some(ref dir) = self.current_dir {
+            words.push("cd".to_owned());
+            words.push(shell_quote(dir.as_os_str()));
+            words.push("&&".to_owned());
+        }
+        for (key, val) in &self.env {
+            words.push(format!("{}={}", key.to_string_lossy(), shell_quote(val)));
+        }
+        words.extend(self.cmd.iter().map(|arg| shell_quote(arg)));
+        words.join(" ")
+    }
+}
+
+impl Command for DryRunCommand {
+    fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.cmd.push(arg.as_ref().to_owned());
+        self
+    }
+
+    fn env<K, V>(&mut self, key: K, val: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    fn status(&mut self) -> Result<process::ExitStatus> {
+        let line = self.to_shell_string();
+        println!("{} {}", "[dry-run]".yellow().bold(), line);
+        self.cmds
+            .lock()
+            .expect("dry run command runner mutex poisoned")
+            .push(line);
+        Ok(success_status())
+    }
+
+    fn output(&mut self) -> Result<String> {
+        // We print queries just like any other command, but we can't make
+        // up their output, so callers need to check `is_dry_run` first.
+        self.status()?;
+        Err(Error::NoOutputDuringDryRun(self.cmd.clone()).into())
+    }
+
+    fn output_with_input(&mut self, _input: &[u8]) -> Result<Vec<u8>> {
+        self.status()?;
+        Err(Error::NoOutputDuringDryRun(self.cmd.clone()).into())
+    }
+
+    fn command_failed_error(&self) -> anyhow::Error {
+        Error::CommandFailed(self.cmd.clone()).into()
+    }
+}
+
+/// Quote a string for use in a POSIX shell, if necessary.
+fn shell_quote(s: &OsStr) -> String {
+    let s = s.to_string_lossy();
+    match shlex::try_quote(&s) {
+        Ok(quoted) => quoted.into_owned(),
+        // This only happens for strings containing NUL bytes, which can't
+        // appear in real arguments anyway.
+        Err(_) => format!("{:?}", s),
+    }
+}
+
+/// Build a successful `ExitStatus` without running anything.
+#[cfg(unix)]
+fn success_status() -> process::ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    process::ExitStatus::from_raw(0)
+}
+
+/// Build a successful `ExitStatus` without running anything.
+#[cfg(windows)]
+fn success_status() -> process::ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    process::ExitStatus::from_raw(0)
+}
+
+#[test]
+fn dry_run_command_runner_prints_commands_with_env_and_dir() {
+    let runner = DryRunCommandRunner::new();
+    assert!(runner.is_dry_run());
+    runner
+        .build("docker-compose")
+        .args(&["-p", "myproj", "rm", "-v"])
+        .exec()
+        .unwrap();
+    runner
+        .build("hooks/pull.d/hello.hook")
+        .current_dir("/my project")
+        .env("GREETING", "hi there")
+        .exec()
+        .unwrap();
+    assert_eq!(
+        runner.cmds().as_slice(),
+        &[
+            "docker-compose -p myproj rm -v",
+            "cd '/my project' && GREETING='hi there' hooks/pull.d/hello.hook",
+        ]
+    );
+    let err = runner.build("git").arg("status").output().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "can't get the output of 'git status' during a dry run"
+    );
 }
 
 /// Support for running commands in test mode.
@@ -282,6 +587,18 @@ impl Command for TestCommand {
         })
     }
 
+    fn output(&mut self) -> Result<String> {
+        self.record_execution();
+        Ok(String::new())
+    }
+
+    /// We pretend to be `cat`, so that commands which transform their
+    /// input leave it alone.
+    fn output_with_input(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        self.record_execution();
+        Ok(input.to_owned())
+    }
+
     fn command_failed_error(&self) -> anyhow::Error {
         Error::CommandFailed(self.cmd.clone()).into()
     }
@@ -0,0 +1,221 @@
+//! Support for running either the standalone `docker-compose` binary or
+//! the `docker compose` CLI plugin, or for talking directly to the Docker
+//! Engine API.
+
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+
+use crate::command_runner::{Command, CommandRunner};
+use crate::errors::*;
+
+/// The environment variable which can be used to override the backend
+/// specified in `config/project.yml`.
+pub const COMPOSE_BACKEND_ENV_VAR: &str = "CAGE_COMPOSE_BACKEND";
+
+/// Which tool should we use to run `docker-compose` commands?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComposeBackend {
+    /// The standalone `docker-compose` binary.
+    DockerCompose,
+    /// The `docker compose` plugin which ships with modern versions of
+    /// Docker.
+    DockerComposePlugin,
+    /// Talk directly to the Docker Engine API for `up`, `start`, `stop`,
+    /// `restart`, `kill`, `rm`, `run`, `exec` and `logs`, and use the
+    /// `docker compose` plugin for anything else.  See `crate::engine`.
+    EngineApi,
+}
+
+impl ComposeBackend {
+    /// Choose a backend.  The environment variable `CAGE_COMPOSE_BACKEND`
+    /// takes priority, followed by `configured` (normally taken from
+    /// `config/project.yml`).  If neither specifies a backend, or if they
+    /// ask for `"auto"`, we use `docker-compose` if it's in our `PATH`, and
+    /// the `docker compose` plugin otherwise.  We never choose the Engine
+    /// API backend unless somebody asks for it.  We only warn about invalid
+    /// values of `CAGE_COMPOSE_BACKEND`, because we detect our backend
+    /// whenever we load a project, even if we won't need it.
+    pub fn detect(configured: Option<ComposeBackend>) -> Result<ComposeBackend> {
+        let from_env = match env::var(COMPOSE_BACKEND_ENV_VAR) {
+            Ok(value) => parse_env_choice(&value),
+            Err(_) => None,
+        };
+        Ok(Self::select(from_env, configured, || {
+            binary_is_in_path("docker-compose")
+        }))
+    }
+
+    /// Choose a backend, given the choices we found in the environment
+    /// and in our config file.  We only call `has_docker_compose` if we
+    /// need to auto-detect.
+    fn select<F>(
+        from_env: Option<ComposeBackend>,
+        configured: Option<ComposeBackend>,
+        has_docker_compose: F,
+    ) -> ComposeBackend
+    where
+        F: FnOnce() -> bool,
+    {
+        from_env.or(configured).unwrap_or_else(|| {
+            if has_docker_compose() {
+                ComposeBackend::DockerCompose
+            } else {
+                ComposeBackend::DockerComposePlugin
+            }
+        })
+    }
+
+    /// Does this backend talk directly to the Docker Engine API?
+    pub fn is_engine_api(&self) -> bool {
+        *self == ComposeBackend::EngineApi
+    }
+
+    /// Build a command which runs this backend.  Callers should add the
+    /// same arguments they'd pass to `docker-compose`.  For the Engine API
+    /// backend, this runs the `docker compose` plugin, which we use for
+    /// commands we can't handle ourselves.
+    pub fn build<CR>(&self, runner: &CR) -> CR::Command
+    where
+        CR: CommandRunner,
+    {
+        match *self {
+            ComposeBackend::DockerCompose => runner.build("docker-compose"),
+            ComposeBackend::DockerComposePlugin | ComposeBackend::EngineApi => {
+                let mut cmd = runner.build("docker");
+                cmd.arg("compose");
+                cmd
+            }
+        }
+    }
+}
+
+impl fmt::Display for ComposeBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ComposeBackend::DockerCompose => write!(f, "docker-compose"),
+            ComposeBackend::DockerComposePlugin => write!(f, "docker compose"),
+            ComposeBackend::EngineApi => write!(f, "engine-api"),
+        }
+    }
+}
+
+/// Parse a backend name, returning `None` for `"auto"`.
+fn parse_choice(s: &str) -> Result<Option<ComposeBackend>> {
+    match s {
+        "auto" => Ok(None),
+        _ => Ok(Some(s.parse()?)),
+    }
+}
+
+/// Parse the value of `CAGE_COMPOSE_BACKEND`, warning about and ignoring
+/// anything we don't recognize.
+fn parse_env_choice(s: &str) -> Option<ComposeBackend> {
+    parse_choice(s).unwrap_or_else(|e| {
+        warn!("Ignoring {}: {}", COMPOSE_BACKEND_ENV_VAR, e);
+        None
+    })
+}
+
+impl FromStr for ComposeBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "docker-compose" => Ok(ComposeBackend::DockerCompose),
+            "docker compose" => Ok(ComposeBackend::DockerComposePlugin),
+            "engine-api" => Ok(ComposeBackend::EngineApi),
+            _ => Err(Error::parse("compose backend", s)),
+        }
+    }
+}
+
+/// Deserialize an optional backend from `config/project.yml`, treating
+/// `"auto"` as `None`.
+pub(crate) fn deserialize_choice<'de, D>(
+    deserializer: D,
+) -> result::Result<Option<ComposeBackend>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_choice(&s).map_err(|e| serde::de::Error::custom(format!("{}", e)))
+}
+
+/// Is there an executable with the specified name somewhere in our `PATH`?
+/// On Windows, we also look for `name.exe`.
+fn binary_is_in_path(name: &str) -> bool {
+    let with_suffix = format!("{}{}", name, env::consts::EXE_SUFFIX);
+    env::var_os("PATH")
+        .map(|paths| {
+            env::split_paths(&paths).any(|dir| {
+                dir.join(name).is_file() || dir.join(&with_suffix).is_file()
+            })
+        })
+        .unwrap_or(false)
+}
+
+#[test]
+fn environment_overrides_config_which_overrides_detection() {
+    use ComposeBackend::*;
+
+    let no_detect = || -> bool { panic!("should not auto-detect") };
+    assert_eq!(
+        ComposeBackend::select(
+            Some(DockerComposePlugin),
+            Some(DockerCompose),
+            no_detect
+        ),
+        DockerComposePlugin
+    );
+    assert_eq!(
+        ComposeBackend::select(None, Some(DockerCompose), no_detect),
+        DockerCompose
+    );
+    assert_eq!(ComposeBackend::select(None, None, || true), DockerCompose);
+    assert_eq!(
+        ComposeBackend::select(None, None, || false),
+        DockerComposePlugin
+    );
+}
+
+#[test]
+fn backend_names_can_be_parsed() {
+    assert_eq!(
+        parse_choice("docker compose").unwrap(),
+        Some(ComposeBackend::DockerComposePlugin)
+    );
+    assert_eq!(
+        parse_choice("docker-compose").unwrap(),
+        Some(ComposeBackend::DockerCompose)
+    );
+    assert_eq!(
+        parse_choice("engine-api").unwrap(),
+        Some(ComposeBackend::EngineApi)
+    );
+    assert_eq!(parse_choice("auto").unwrap(), None);
+    assert!(parse_choice("podman-compose").is_err());
+
+    // Bad environment variables shouldn't stop us from loading projects.
+    assert_eq!(parse_env_choice("podman-compose"), None);
+    assert_eq!(
+        parse_env_choice("engine-api"),
+        Some(ComposeBackend::EngineApi)
+    );
+}
+
+#[test]
+fn engine_api_backend_falls_back_to_docker_compose_plugin() {
+    use crate::command_runner::TestCommandRunner;
+
+    let runner = TestCommandRunner::new();
+    assert!(ComposeBackend::EngineApi.is_engine_api());
+    ComposeBackend::EngineApi
+        .build(&runner)
+        .arg("pull")
+        .exec()
+        .unwrap();
+    assert_ran!(runner, { ["docker", "compose", "pull"] });
+}
@@ -0,0 +1,161 @@
+//! Support for outputting pods in [Compose Specification][spec] format.
+//!
+//! Internally, we represent everything using `faraday_compose_yml::v2`, and
+//! all our plugins operate on that.  When a project asks for Compose
+//! Specification output, we convert each fully-transformed file at the last
+//! moment, and merge in any keys from the pod's `compose_spec` metadata
+//! which the version 2 format can't represent.
+//!
+//! [spec]: https://compose-spec.io/
+
+use faraday_compose_yml::v2 as dc;
+use serde_yaml::{Mapping, Value};
+use std::path::Path;
+
+use crate::errors::*;
+use crate::util::ConductorPathExt;
+
+/// Top-level keys whose entries may refer to a `file`.
+const KEYS_WITH_FILES: &[&str] = &["configs", "secrets"];
+
+/// Convert `file` to Compose Specification format, merging in
+/// `extensions` if present.  If `base_dir` is specified, we treat relative
+/// `file` paths in `extensions` as relative to it, the same way the
+/// `abs_path` plugin treats paths in the pod itself.
+pub(crate) fn from_v2(
+    file: &dc::File,
+    extensions: Option<&Value>,
+    base_dir: Option<&Path>,
+) -> Result<Value> {
+    let mut spec = serde_yaml::to_value(file)?;
+    let map = spec
+        .as_mapping_mut()
+        .ok_or_else(|| err!("expected docker-compose file to be a mapping"))?;
+
+    // The Compose Specification treats `version` as obsolete, and modern
+    // versions of `docker compose` warn about it.
+    map.remove("version");
+
+    if let Some(extensions) = extensions {
+        let mut extensions = extensions
+            .as_mapping()
+            .ok_or_else(|| {
+                Error::parse("compose_spec mapping", format!("{:?}", extensions))
+            })?
+            .to_owned();
+        if let Some(base_dir) = base_dir {
+            resolve_files(&mut extensions, base_dir)?;
+        }
+        merge(map, &extensions);
+    }
+    Ok(spec)
+}
+
+/// Make any relative `file` paths in top-level `configs` and `secrets`
+/// entries absolute, treating them as relative to `base_dir`.  Otherwise,
+/// they'd be relative to wherever we write our output.
+fn resolve_files(extensions: &mut Mapping, base_dir: &Path) -> Result<()> {
+    for key in KEYS_WITH_FILES {
+        let entries = match extensions.get_mut(*key) {
+            Some(Value::Mapping(entries)) => entries,
+            _ => continue,
+        };
+        for entry in entries.values_mut() {
+            let file = match entry.get_mut("file") {
+                Some(Value::String(file)) => file,
+                _ => continue,
+            };
+            let path = base_dir.join(file.as_str()).to_absolute()?;
+            *file = path
+                .to_str()
+                .ok_or_else(|| err!("non-UTF-8 path {}", path.display()))?
+                .to_owned();
+        }
+    }
+    Ok(())
+}
+
+/// Recursively merge `overrides` into `base`.  Nested mappings are merged,
+/// and any other values in `overrides` replace the values in `base`.
+fn merge(base: &mut Mapping, overrides: &Mapping) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(Value::Mapping(base_value)), Value::Mapping(override_value)) => {
+                merge(base_value, override_value);
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[test]
+fn merge_combines_nested_mappings() {
+    let mut base: Mapping = serde_yaml::from_str(
+        "services: { web: { image: nginx, ports: ['80'] } }\nvolumes: {}",
+    )
+    .unwrap();
+    let overrides: Mapping = serde_yaml::from_str(
+        "services: { web: { ports: ['8080:80'], healthcheck: { test: [CMD, true] } } }\nsecrets: { key: { file: ./key } }",
+    )
+    .unwrap();
+    merge(&mut base, &overrides);
+    let expected: Mapping = serde_yaml::from_str(
+        "services: { web: { image: nginx, ports: ['8080:80'], healthcheck: { test: [CMD, true] } } }\nvolumes: {}\nsecrets: { key: { file: ./key } }",
+    )
+    .unwrap();
+    assert_eq!(base, expected);
+}
+
+#[test]
+fn only_relative_files_are_resolved() {
+    let mut extensions: Mapping = serde_yaml::from_str(
+        "configs: { a: { file: ./a.conf }, b: { file: /etc/b.conf }, c: { external: true } }\nsecrets: { key: { file: key } }",
+    )
+    .unwrap();
+    resolve_files(&mut extensions, Path::new("/project/pods")).unwrap();
+    let expected: Mapping = serde_yaml::from_str(
+        "configs: { a: { file: /project/pods/./a.conf }, b: { file: /etc/b.conf }, c: { external: true } }\nsecrets: { key: { file: /project/pods/key } }",
+    )
+    .unwrap();
+    assert_eq!(extensions, expected);
+}
+
+#[test]
+fn projects_can_output_compose_spec_files() {
+    use crate::project::{ComposeFormat, Project};
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_compose_spec").unwrap();
+    assert_eq!(proj.config().compose_format, ComposeFormat::Spec);
+    proj.output("up").unwrap();
+
+    let path = proj.output_pods_dir().join("frontend.yml");
+    let text = std::fs::read_to_string(path).unwrap();
+    let output: Value = serde_yaml::from_str(&text).unwrap();
+    assert!(output.get("version").is_none());
+
+    // Our transform plugins still apply, just as they would for version 2.
+    let web = &output["services"]["web"];
+    assert_eq!(web["image"], Value::from("nginx"));
+    assert_eq!(web["environment"]["GREETING"], Value::from("hello"));
+    assert_eq!(web["labels"]["io.fdy.cage.pod"], Value::from("frontend"));
+
+    // And our extensions from `frontend.metadata.yml` get merged in.
+    assert_eq!(
+        web["deploy"]["resources"]["limits"]["memory"],
+        Value::from("256M")
+    );
+    assert_eq!(web["healthcheck"]["interval"], Value::from("10s"));
+
+    // Relative paths are resolved against our `pods` directory, not our
+    // output directory.
+    let nginx_conf = proj.pods_dir().join("./nginx.conf").to_absolute().unwrap();
+    assert_eq!(
+        output["configs"]["nginx_conf"]["file"],
+        Value::from(nginx_conf.to_str().unwrap())
+    );
+
+    proj.remove_test_output().unwrap();
+}
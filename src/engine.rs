@@ -0,0 +1,1242 @@
+//! A backend which talks directly to the Docker Engine API using `bollard`,
+//! instead of running `docker-compose`.
+//!
+//! We create containers from the same fully-processed `dc::File` that we
+//! would otherwise write to `.cage/pods`, and we label them the same way
+//! that `docker-compose` does, so that `RuntimeState`, `cage down` and the
+//! `docker compose` plugin can all find them.  We only handle the most
+//! common lifecycle commands here, and we fall back to the `docker compose`
+//! plugin for everything else (see `ComposeBackend::build`).
+
+use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::models::{
+    ContainerCreateBody, EndpointSettings, HostConfig, Mount, MountTypeEnum,
+    NetworkCreateRequest, NetworkingConfig, PortBinding, PortMap, VolumeCreateRequest,
+};
+use bollard::query_parameters::{
+    CreateContainerOptionsBuilder, CreateImageOptionsBuilder, InspectContainerOptions,
+    InspectNetworkOptions, KillContainerOptions, ListContainersOptionsBuilder,
+    LogsOptionsBuilder, RemoveContainerOptionsBuilder, RestartContainerOptions,
+    StartContainerOptions, StopContainerOptions, WaitContainerOptions,
+};
+use faraday_compose_yml::v2 as dc;
+use futures_util::stream::{self, StreamExt};
+use rand::random;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{self, Write};
+#[cfg(all(test, unix))]
+use std::path::Path;
+use tokio::runtime;
+
+use crate::args;
+#[cfg(test)]
+use crate::command_runner::TestCommandRunner;
+use crate::command_runner::{CaptureOutput, Command, CommandRunner};
+use crate::errors::*;
+use crate::k8s::command_line;
+use crate::plugins::Operation;
+use crate::pod::Pod;
+use crate::project::Project;
+use crate::util::ToStrOrErr;
+
+/// The label `docker-compose` uses to record a container's project.
+const PROJECT_LABEL: &str = "com.docker.compose.project";
+
+/// The label `docker-compose` uses to record a container's service.
+const SERVICE_LABEL: &str = "com.docker.compose.service";
+
+/// The label `docker-compose` uses to mark containers created by `run`.
+const ONE_OFF_LABEL: &str = "com.docker.compose.oneoff";
+
+/// The label `docker-compose` uses to number containers within a service.
+const CONTAINER_NUMBER_LABEL: &str = "com.docker.compose.container-number";
+
+/// The label we use to decide whether a container needs to be recreated.
+const CONFIG_HASH_LABEL: &str = "com.docker.compose.config-hash";
+
+/// A `docker-compose` command which we know how to run using the Engine
+/// API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EngineCommand {
+    /// Create and start containers, recreating any whose configuration
+    /// has changed.
+    Up,
+    /// Start existing containers.
+    Start,
+    /// Stop running containers.
+    Stop,
+    /// Restart containers.
+    Restart,
+    /// Kill running containers.
+    Kill,
+    /// Remove stopped containers.
+    Rm {
+        /// Remove anonymous volumes attached to our containers.
+        remove_volumes: bool,
+        /// Stop running containers before removing them.
+        stop: bool,
+    },
+    /// Display container logs.
+    Logs {
+        /// Keep following the logs until interrupted.
+        follow: bool,
+        /// How many lines to show from the end of the logs.
+        tail: Option<String>,
+    },
+}
+
+impl EngineCommand {
+    /// Parse a `docker-compose` subcommand and its arguments, returning
+    /// `None` if we don't support it.
+    pub(crate) fn parse(command: &str, args: &[OsString]) -> Option<EngineCommand> {
+        let args = args
+            .iter()
+            .map(|a| a.to_str())
+            .collect::<Option<Vec<_>>>()?;
+        match command {
+            // We always run `up` detached, so `-d` is a no-op.
+            "up" if args.iter().all(|&a| a == "-d") => Some(EngineCommand::Up),
+            "start" if args.is_empty() => Some(EngineCommand::Start),
+            "stop" if args.is_empty() => Some(EngineCommand::Stop),
+            "restart" if args.is_empty() => Some(EngineCommand::Restart),
+            "kill" if args.is_empty() => Some(EngineCommand::Kill),
+            "rm" => {
+                let mut remove_volumes = false;
+                let mut stop = false;
+                for arg in args {
+                    match arg {
+                        // We never ask for confirmation anyway.
+                        "-f" => {}
+                        "-v" => remove_volumes = true,
+                        "-s" => stop = true,
+                        _ => return None,
+                    }
+                }
+                Some(EngineCommand::Rm {
+                    remove_volumes,
+                    stop,
+                })
+            }
+            "logs" => {
+                let mut follow = false;
+                let mut tail = None;
+                for arg in args {
+                    if arg == "-f" {
+                        follow = true;
+                    } else if let Some(n) = arg.strip_prefix("--tail=") {
+                        tail = Some(n.to_owned());
+                    } else {
+                        return None;
+                    }
+                }
+                Some(EngineCommand::Logs { follow, tail })
+            }
+            _ => None,
+        }
+    }
+
+    /// The `docker-compose` subcommand corresponding to this command.
+    fn name(&self) -> &'static str {
+        match *self {
+            EngineCommand::Up => "up",
+            EngineCommand::Start => "start",
+            EngineCommand::Stop => "stop",
+            EngineCommand::Restart => "restart",
+            EngineCommand::Kill => "kill",
+            EngineCommand::Rm { .. } => "rm",
+            EngineCommand::Logs { .. } => "logs",
+        }
+    }
+}
+
+/// A connection to the Docker Engine API.
+pub(crate) struct Engine {
+    /// Our connection to Docker.
+    docker: bollard::Docker,
+    /// A local `tokio` runtime for making async calls.
+    rt: runtime::Runtime,
+}
+
+impl Engine {
+    /// Connect to the local Docker daemon, using `DOCKER_HOST` if it's set.
+    pub(crate) fn connect() -> Result<Engine> {
+        let docker = bollard::Docker::connect_with_local_defaults()
+            .map_err(|e| engine_error(e, "connect to Docker"))?;
+        Engine::new(docker)
+    }
+
+    /// Connect to a Docker Engine API server listening on a Unix socket.
+    #[cfg(all(test, unix))]
+    fn connect_to_socket(path: &Path) -> Result<Engine> {
+        let docker = bollard::Docker::connect_with_unix(
+            path.to_str_or_err()?,
+            10,
+            bollard::API_DEFAULT_VERSION,
+        )
+        .map_err(|e| engine_error(e, "connect to Docker"))?;
+        Engine::new(docker)
+    }
+
+    /// Wrap a connection to Docker.
+    fn new(docker: bollard::Docker) -> Result<Engine> {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Engine { docker, rt })
+    }
+
+    /// Run `command` on all the services in `pod`, or only on
+    /// `service_name` if it's specified.  Plugins use `runner` to run any
+    /// commands they need.
+    pub(crate) fn compose(
+        &self,
+        runner: &dyn CaptureOutput,
+        project: &Project,
+        pod: &Pod,
+        service_name: Option<&str>,
+        command: &EngineCommand,
+    ) -> Result<()> {
+        let file =
+            project.processed_file(runner, Operation::Output, command.name(), pod)?;
+        let project_name = project.compose_name();
+        self.rt.block_on(self.compose_file(
+            &project_name,
+            &file,
+            service_name,
+            command,
+        ))
+    }
+
+    /// Run `command` on the services in `file`.
+    async fn compose_file(
+        &self,
+        project_name: &str,
+        file: &dc::File,
+        service_name: Option<&str>,
+        command: &EngineCommand,
+    ) -> Result<()> {
+        if *command == EngineCommand::Up {
+            self.create_network(project_name).await?;
+            self.create_volumes(project_name, file).await?;
+        }
+        if let EngineCommand::Logs { follow, ref tail } = *command {
+            let mut ids = vec![];
+            for name in file.services.keys() {
+                if service_name.is_none_or(|s| s == name) {
+                    for id in self.containers(project_name, name, false).await? {
+                        ids.push((name.to_owned(), id));
+                    }
+                }
+            }
+            return self.logs(&ids, follow, tail.as_deref()).await;
+        }
+        for (name, service) in &file.services {
+            if service_name.is_none_or(|s| s == name) {
+                self.compose_service(project_name, file, name, service, command)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `command` on a single service.
+    async fn compose_service(
+        &self,
+        project_name: &str,
+        file: &dc::File,
+        service_name: &str,
+        service: &dc::Service,
+        command: &EngineCommand,
+    ) -> Result<()> {
+        if *command == EngineCommand::Up {
+            let body = container_body(project_name, file, service_name, service)?;
+            return self.up_service(project_name, service_name, body).await;
+        }
+
+        for id in self.containers(project_name, service_name, false).await? {
+            let running = self.is_running(&id).await?;
+            match *command {
+                EngineCommand::Start if !running => {
+                    self.docker
+                        .start_container(&id, None::<StartContainerOptions>)
+                        .await
+                        .map_err(|e| engine_error(e, format!("start {}", id)))?;
+                }
+                EngineCommand::Stop if running => self.stop(&id).await?,
+                EngineCommand::Restart => {
+                    self.docker
+                        .restart_container(&id, None::<RestartContainerOptions>)
+                        .await
+                        .map_err(|e| engine_error(e, format!("restart {}", id)))?;
+                }
+                EngineCommand::Kill if running => {
+                    self.docker
+                        .kill_container(&id, None::<KillContainerOptions>)
+                        .await
+                        .map_err(|e| engine_error(e, format!("kill {}", id)))?;
+                }
+                EngineCommand::Rm {
+                    remove_volumes,
+                    stop,
+                } => {
+                    if running && !stop {
+                        println!(
+                            "Not removing running container for {}",
+                            service_name
+                        );
+                        continue;
+                    } else if running {
+                        self.stop(&id).await?;
+                    }
+                    let opts = RemoveContainerOptionsBuilder::default()
+                        .v(remove_volumes)
+                        .build();
+                    self.docker
+                        .remove_container(&id, Some(opts))
+                        .await
+                        .map_err(|e| engine_error(e, format!("remove {}", id)))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure a service is running with the configuration in `body`,
+    /// recreating its container if the configuration has changed.
+    async fn up_service(
+        &self,
+        project_name: &str,
+        service_name: &str,
+        body: ContainerCreateBody,
+    ) -> Result<()> {
+        let hash = body
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(CONFIG_HASH_LABEL))
+            .cloned();
+        for id in self.containers(project_name, service_name, false).await? {
+            let info = self
+                .docker
+                .inspect_container(&id, None::<InspectContainerOptions>)
+                .await
+                .map_err(|e| engine_error(e, format!("inspect {}", id)))?;
+            let existing_hash = info
+                .config
+                .as_ref()
+                .and_then(|c| c.labels.as_ref())
+                .and_then(|labels| labels.get(CONFIG_HASH_LABEL))
+                .cloned();
+            if existing_hash == hash {
+                let running =
+                    info.state.as_ref().and_then(|s| s.running).unwrap_or(false);
+                if !running {
+                    println!("Starting {}", service_name);
+                    self.docker
+                        .start_container(&id, None::<StartContainerOptions>)
+                        .await
+                        .map_err(|e| engine_error(e, format!("start {}", id)))?;
+                }
+                return Ok(());
+            }
+            println!("Recreating {}", service_name);
+            let opts = RemoveContainerOptionsBuilder::default().force(true).build();
+            self.docker
+                .remove_container(&id, Some(opts))
+                .await
+                .map_err(|e| engine_error(e, format!("remove {}", id)))?;
+        }
+
+        let name = format!("{}_{}_1", project_name, service_name);
+        println!("Creating {}", name);
+        let id = self.create_container(&name, body).await?;
+        self.docker
+            .start_container(&id, None::<StartContainerOptions>)
+            .await
+            .map_err(|e| engine_error(e, format!("start {}", name)))?;
+        Ok(())
+    }
+
+    /// Run a one-off command in a new container, like `docker-compose run`.
+    pub(crate) fn run<CR>(
+        &self,
+        runner: &CR,
+        project: &Project,
+        pod: &Pod,
+        service_name: &str,
+        command: Option<&args::Command>,
+        opts: &args::opts::Run,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let file = project.processed_file(runner, Operation::Output, "run", pod)?;
+        let service = file
+            .services
+            .get(service_name)
+            .ok_or_else(|| Error::UnknownService(service_name.to_owned()))?;
+        let project_name = project.compose_name();
+
+        let mut body = container_body(&project_name, &file, service_name, service)?;
+        if let Some(command) = command {
+            body.cmd = Some(to_strings(&args::ToArgs::to_args(command))?);
+        }
+        if let Some(ref entrypoint) = opts.entrypoint {
+            body.entrypoint =
+                Some(shlex::split(entrypoint).ok_or_else(|| {
+                    Error::parse("entrypoint", entrypoint.to_owned())
+                })?);
+        }
+        let env = body.env.get_or_insert_with(Vec::new);
+        for (var, val) in &opts.environment {
+            env.push(format!("{}={}", var, val));
+        }
+        if let Some(ref user) = opts.user {
+            body.user = Some(user.to_owned());
+        }
+        body.tty = Some(opts.allocate_tty);
+        body.open_stdin = Some(opts.allocate_tty && !opts.detached);
+        if let Some(labels) = body.labels.as_mut() {
+            labels.insert(ONE_OFF_LABEL.to_owned(), "True".to_owned());
+            labels.remove(CONFIG_HASH_LABEL);
+        }
+        // Like `docker-compose run`, don't publish any ports.
+        body.exposed_ports = None;
+        if let Some(host_config) = body.host_config.as_mut() {
+            host_config.port_bindings = None;
+        }
+
+        let name =
+            format!("{}_{}_run_{}", project_name, service_name, random::<u16>());
+        let id = self.rt.block_on(async {
+            self.create_network(&project_name).await?;
+            self.create_volumes(&project_name, &file).await?;
+            self.create_container(&name, body).await
+        })?;
+
+        if opts.allocate_tty && !opts.detached {
+            // We don't try to manage an interactive terminal ourselves.
+            return runner
+                .build("docker")
+                .args(&["start", "--attach", "--interactive"])
+                .arg(&id)
+                .exec();
+        }
+        self.rt
+            .block_on(self.run_one_off(service_name, &name, &id, opts.detached))
+    }
+
+    /// Start a one-off container, and unless it's `detached`, display its
+    /// output and wait for it to finish.
+    async fn run_one_off(
+        &self,
+        service_name: &str,
+        name: &str,
+        id: &str,
+        detached: bool,
+    ) -> Result<()> {
+        self.docker
+            .start_container(id, None::<StartContainerOptions>)
+            .await
+            .map_err(|e| engine_error(e, format!("start {}", name)))?;
+        if detached {
+            println!("{}", name);
+            return Ok(());
+        }
+        self.logs(&[(service_name.to_owned(), id.to_owned())], true, None)
+            .await?;
+        match self.wait(id).await? {
+            0 => Ok(()),
+            code => Err(Error::ContainerExited {
+                container: name.to_owned(),
+                code,
+            }
+            .into()),
+        }
+    }
+
+    /// Run a command inside a service's running container, like
+    /// `docker-compose exec`.
+    pub(crate) fn exec<CR>(
+        &self,
+        runner: &CR,
+        project: &Project,
+        service_name: &str,
+        command: &args::Command,
+        opts: &args::opts::Exec,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let project_name = project.compose_name();
+        let cmd = to_strings(&args::ToArgs::to_args(command))?;
+        self.rt.block_on(self.exec_in_service(
+            runner,
+            &project_name,
+            service_name,
+            cmd,
+            opts,
+        ))
+    }
+
+    /// The async implementation of `exec`.
+    async fn exec_in_service<CR>(
+        &self,
+        runner: &CR,
+        project_name: &str,
+        service_name: &str,
+        cmd: Vec<String>,
+        opts: &args::opts::Exec,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let mut id = None;
+        for candidate in self.containers(project_name, service_name, false).await? {
+            if self.is_running(&candidate).await? {
+                id = Some(candidate);
+                break;
+            }
+        }
+        let id =
+            id.ok_or_else(|| err!("service '{}' is not running", service_name))?;
+
+        // Let the `docker` CLI handle interactive terminals and
+        // detached processes.
+        if opts.allocate_tty || opts.detached {
+            let mut docker = runner.build("docker");
+            docker.arg("exec");
+            if opts.detached {
+                docker.arg("--detach");
+            }
+            if opts.allocate_tty {
+                docker.args(&["--interactive", "--tty"]);
+            }
+            if let Some(ref user) = opts.user {
+                docker.arg("--user").arg(user);
+            }
+            if opts.privileged {
+                docker.arg("--privileged");
+            }
+            return docker.arg(&id).args(&cmd).exec();
+        }
+
+        let config = CreateExecOptions {
+            cmd: Some(cmd),
+            user: opts.user.clone(),
+            privileged: Some(opts.privileged),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..Default::default()
+        };
+        let exec = self
+            .docker
+            .create_exec(&id, config)
+            .await
+            .map_err(|e| engine_error(e, format!("exec in {}", service_name)))?;
+        let started = self
+            .docker
+            .start_exec(&exec.id, None::<StartExecOptions>)
+            .await
+            .map_err(|e| engine_error(e, format!("exec in {}", service_name)))?;
+        if let StartExecResults::Attached { mut output, .. } = started {
+            while let Some(chunk) = output.next().await {
+                let chunk = chunk.map_err(|e| {
+                    engine_error(e, format!("exec in {}", service_name))
+                })?;
+                print!("{}", chunk);
+            }
+            io::stdout().flush()?;
+        }
+        let info = self
+            .docker
+            .inspect_exec(&exec.id)
+            .await
+            .map_err(|e| engine_error(e, format!("exec in {}", service_name)))?;
+        match info.exit_code {
+            None | Some(0) => Ok(()),
+            Some(code) => Err(Error::ContainerExited {
+                container: service_name.to_owned(),
+                code,
+            }
+            .into()),
+        }
+    }
+
+    /// Find the IDs of all containers belonging to `service_name`.
+    async fn containers(
+        &self,
+        project_name: &str,
+        service_name: &str,
+        one_off: bool,
+    ) -> Result<Vec<String>> {
+        let opts = ListContainersOptionsBuilder::default().all(true).build();
+        let containers = self
+            .docker
+            .list_containers(Some(opts))
+            .await
+            .map_err(|e| engine_error(e, "list containers"))?;
+        let one_off = if one_off { "True" } else { "False" };
+        Ok(containers
+            .into_iter()
+            .filter(|container| {
+                let labels = match container.labels {
+                    Some(ref labels) => labels,
+                    None => return false,
+                };
+                let label = |name: &str| labels.get(name).map(|s| s.as_str());
+                label(PROJECT_LABEL) == Some(project_name)
+                    && label(SERVICE_LABEL) == Some(service_name)
+                    && label(ONE_OFF_LABEL).unwrap_or("False") == one_off
+            })
+            .filter_map(|container| container.id)
+            .collect())
+    }
+
+    /// Is the specified container running?
+    async fn is_running(&self, id: &str) -> Result<bool> {
+        let info = self
+            .docker
+            .inspect_container(id, None::<InspectContainerOptions>)
+            .await
+            .map_err(|e| engine_error(e, format!("inspect {}", id)))?;
+        Ok(info.state.and_then(|s| s.running).unwrap_or(false))
+    }
+
+    /// Stop the specified container.
+    async fn stop(&self, id: &str) -> Result<()> {
+        self.docker
+            .stop_container(id, None::<StopContainerOptions>)
+            .await
+            .map_err(|e| engine_error(e, format!("stop {}", id)))
+    }
+
+    /// Create a container, pulling its image first if necessary.  Returns
+    /// the ID of the new container.
+    async fn create_container(
+        &self,
+        name: &str,
+        body: ContainerCreateBody,
+    ) -> Result<String> {
+        let opts = || CreateContainerOptionsBuilder::default().name(name).build();
+        match self
+            .docker
+            .create_container(Some(opts()), body.clone())
+            .await
+        {
+            Ok(response) => return Ok(response.id),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404,
+                ..
+            }) => {}
+            Err(e) => return Err(engine_error(e, format!("create {}", name))),
+        }
+
+        // Docker doesn't have our image, so pull it and try again.
+        let image = body.image.clone().unwrap_or_default();
+        self.pull(&image).await?;
+        let response = self
+            .docker
+            .create_container(Some(opts()), body)
+            .await
+            .map_err(|e| engine_error(e, format!("create {}", name)))?;
+        Ok(response.id)
+    }
+
+    /// Pull an image, displaying progress as we go.
+    async fn pull(&self, image: &str) -> Result<()> {
+        println!("Pulling {}", image);
+        let mut opts = CreateImageOptionsBuilder::default().from_image(image);
+        // If we don't specify a tag, Docker will pull every tag.
+        if !image_has_tag(image) {
+            opts = opts.tag("latest");
+        }
+        let mut progress =
+            Box::pin(self.docker.create_image(Some(opts.build()), None, None));
+        while let Some(info) = progress.next().await {
+            let info = info.map_err(|e| engine_error(e, format!("pull {}", image)))?;
+            // Skip the very chatty download progress updates, which are the
+            // only ones that report how far along they are.
+            let is_progress_update = info
+                .progress_detail
+                .as_ref()
+                .is_some_and(|detail| detail.current.is_some());
+            if is_progress_update {
+                continue;
+            }
+            match (info.id, info.status) {
+                (Some(id), Some(status)) => println!("{}: {}", id, status),
+                (None, Some(status)) => println!("{}", status),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the default network for our project, if it doesn't already
+    /// exist.
+    async fn create_network(&self, project_name: &str) -> Result<()> {
+        let name = network_name(project_name);
+        match self
+            .docker
+            .inspect_network(&name, None::<InspectNetworkOptions>)
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404,
+                ..
+            }) => {}
+            Err(e) => {
+                return Err(engine_error(e, format!("inspect network {}", name)))
+            }
+        }
+        let request = NetworkCreateRequest {
+            name: name.clone(),
+            labels: Some(project_labels(project_name)),
+            ..Default::default()
+        };
+        self.docker
+            .create_network(request)
+            .await
+            .map_err(|e| engine_error(e, format!("create network {}", name)))?;
+        Ok(())
+    }
+
+    /// Create any named volumes declared by `file`.  Docker will leave
+    /// existing volumes alone.
+    async fn create_volumes(&self, project_name: &str, file: &dc::File) -> Result<()> {
+        for (volume_name, volume) in &file.volumes {
+            let raw = serde_yaml::to_value(volume)?;
+            if raw.get("external").is_some() {
+                continue;
+            }
+            let name = project_volume_name(project_name, volume_name);
+            let opts = VolumeCreateRequest {
+                name: Some(name.clone()),
+                labels: Some(project_labels(project_name)),
+                ..Default::default()
+            };
+            self.docker
+                .create_volume(opts)
+                .await
+                .map_err(|e| engine_error(e, format!("create volume {}", name)))?;
+        }
+        Ok(())
+    }
+
+    /// Print the logs for each `(service_name, container_id)` pair,
+    /// prefixing each line with the service name.
+    async fn logs(
+        &self,
+        containers: &[(String, String)],
+        follow: bool,
+        tail: Option<&str>,
+    ) -> Result<()> {
+        let streams = containers.iter().map(|(service_name, id)| {
+            let opts = LogsOptionsBuilder::default()
+                .stdout(true)
+                .stderr(true)
+                .follow(follow)
+                .tail(tail.unwrap_or("all"))
+                .build();
+            Box::pin(
+                self.docker
+                    .logs(id, Some(opts))
+                    .map(move |output| (service_name, output)),
+            )
+        });
+        let mut all = stream::select_all(streams);
+        let stdout = io::stdout();
+        while let Some((service_name, output)) = all.next().await {
+            let output = output.map_err(|e| {
+                engine_error(e, format!("get logs for {}", service_name))
+            })?;
+            let mut out = stdout.lock();
+            for line in output.to_string().lines() {
+                writeln!(out, "{:12} | {}", service_name, line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for a container to exit, and return its exit code.
+    async fn wait(&self, id: &str) -> Result<i64> {
+        let mut responses =
+            Box::pin(self.docker.wait_container(id, None::<WaitContainerOptions>));
+        let mut code = 0;
+        while let Some(response) = responses.next().await {
+            match response {
+                Ok(response) => code = response.status_code,
+                // `bollard` reports non-zero exit codes as errors.
+                Err(bollard::errors::Error::DockerContainerWaitError {
+                    code: exit_code,
+                    ..
+                }) => code = exit_code,
+                Err(e) => return Err(engine_error(e, format!("wait for {}", id))),
+            }
+        }
+        Ok(code)
+    }
+}
+
+/// Wrap an error from `bollard`, explaining what we were trying to do.
+fn engine_error<S>(err: bollard::errors::Error, action: S) -> anyhow::Error
+where
+    S: Into<String>,
+{
+    anyhow::Error::new(err).context(Error::EngineApi(action.into()))
+}
+
+/// The name of the default network for our project.
+fn network_name(project_name: &str) -> String {
+    format!("{}_default", project_name)
+}
+
+/// The name Docker uses for a volume declared by a project.
+fn project_volume_name(project_name: &str, volume_name: &str) -> String {
+    format!("{}_{}", project_name, volume_name)
+}
+
+/// Labels identifying a network or volume as belonging to our project.
+fn project_labels(project_name: &str) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    labels.insert(PROJECT_LABEL.to_owned(), project_name.to_owned());
+    labels
+}
+
+/// Does an image name include a tag or digest?
+fn image_has_tag(image: &str) -> bool {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    name.contains(':') || name.contains('@')
+}
+
+/// Hash `data` using 64-bit FNV-1a.  Unlike `DefaultHasher`, this
+/// algorithm is fully specified, so the hashes we store in container labels
+/// won't change when we upgrade Rust.
+fn fnv1a_64(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    data.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Convert command-line arguments to strings.
+fn to_strings(args: &[OsString]) -> Result<Vec<String>> {
+    args.iter()
+        .map(|arg| Ok(arg.to_str_or_err()?.to_owned()))
+        .collect()
+}
+
+/// Build the configuration for a service's container.
+fn container_body(
+    project_name: &str,
+    file: &dc::File,
+    service_name: &str,
+    service: &dc::Service,
+) -> Result<ContainerCreateBody> {
+    let raw = serde_yaml::to_value(service)?;
+    let image = raw.get("image").and_then(|v| v.as_str()).ok_or_else(|| {
+        err!(
+            "service '{}' has no image, and the Docker Engine API backend can't build images",
+            service_name
+        )
+    })?;
+
+    let mut env = vec![];
+    for (name, value) in &service.environment {
+        env.push(format!("{}={}", name, value.value()?));
+    }
+
+    // Any change to our service definition means that we need to recreate
+    // our container, so we store a hash of it in a label.
+    let config = format!("{}\0{}", project_name, serde_yaml::to_string(&raw)?);
+
+    let mut labels = project_labels(project_name);
+    for (name, value) in &service.labels {
+        labels.insert(name.to_owned(), value.value()?.to_owned());
+    }
+    labels.insert(SERVICE_LABEL.to_owned(), service_name.to_owned());
+    labels.insert(ONE_OFF_LABEL.to_owned(), "False".to_owned());
+    labels.insert(CONTAINER_NUMBER_LABEL.to_owned(), "1".to_owned());
+    labels.insert(
+        CONFIG_HASH_LABEL.to_owned(),
+        format!("{:016x}", fnv1a_64(config.as_bytes())),
+    );
+
+    let mut binds = vec![];
+    let mut mounts = vec![];
+    for volume in raw
+        .get("volumes")
+        .and_then(|v| v.as_sequence())
+        .into_iter()
+        .flatten()
+    {
+        let spec = volume
+            .as_str()
+            .ok_or_else(|| Error::parse("volume", format!("{:?}", volume)))?;
+        match spec.split_once(':') {
+            Some((source, rest)) if file.volumes.contains_key(source) => {
+                let name = project_volume_name(project_name, source);
+                binds.push(format!("{}:{}", name, rest));
+            }
+            Some(_) => binds.push(spec.to_owned()),
+            // An anonymous volume.
+            None => mounts.push(Mount {
+                target: Some(spec.to_owned()),
+                typ: Some(MountTypeEnum::VOLUME),
+                ..Default::default()
+            }),
+        }
+    }
+
+    let port_bindings = port_bindings(raw.get("ports"))?;
+    let exposed_ports = port_bindings
+        .keys()
+        .map(|port| (port.to_owned(), HashMap::new()))
+        .collect();
+
+    let network = network_name(project_name);
+    let mut endpoints = HashMap::new();
+    endpoints.insert(
+        network.clone(),
+        EndpointSettings {
+            aliases: Some(vec![service_name.to_owned()]),
+            ..Default::default()
+        },
+    );
+
+    let as_string =
+        |key: &str| raw.get(key).and_then(|v| v.as_str()).map(|s| s.to_owned());
+    Ok(ContainerCreateBody {
+        image: Some(image.to_owned()),
+        cmd: command_line(raw.get("command"))?,
+        entrypoint: command_line(raw.get("entrypoint"))?,
+        env: Some(env),
+        labels: Some(labels),
+        working_dir: as_string("working_dir"),
+        user: as_string("user"),
+        exposed_ports: Some(exposed_ports),
+        host_config: Some(HostConfig {
+            binds: Some(binds),
+            mounts: Some(mounts),
+            port_bindings: Some(port_bindings),
+            network_mode: Some(network),
+            ..Default::default()
+        }),
+        networking_config: Some(NetworkingConfig {
+            endpoints_config: Some(endpoints),
+        }),
+        ..Default::default()
+    })
+}
+
+/// Convert a service's `ports` to Docker port bindings.
+fn port_bindings(ports: Option<&serde_yaml::Value>) -> Result<PortMap> {
+    let mut map = PortMap::new();
+    for port in ports.and_then(|v| v.as_sequence()).into_iter().flatten() {
+        let spec = match port {
+            serde_yaml::Value::String(s) => s.to_owned(),
+            serde_yaml::Value::Number(n) => n.to_string(),
+            _ => {
+                return Err(Error::parse("port mapping", format!("{:?}", port)).into())
+            }
+        };
+        let (spec, protocol) = match spec.split_once('/') {
+            Some((spec, protocol)) => (spec, protocol),
+            None => (&spec[..], "tcp"),
+        };
+        // We have `[[host_ip:]host_port:]container_port`, in reverse.
+        let parts = spec.rsplitn(3, ':').collect::<Vec<_>>();
+        let binding = PortBinding {
+            host_ip: parts.get(2).map(|s| (*s).to_owned()),
+            host_port: Some(parts.get(1).copied().unwrap_or("").to_owned()),
+        };
+        map.entry(format!("{}/{}", parts[0], protocol))
+            .or_insert_with(|| Some(vec![]))
+            .get_or_insert_with(Vec::new)
+            .push(binding);
+    }
+    Ok(map)
+}
+
+/// A fake Docker Engine API server listening on a Unix socket.  It
+/// records each request and answers it using a canned response.
+#[cfg(all(test, unix))]
+struct FakeEngine {
+    /// The socket we're listening on.
+    socket: std::path::PathBuf,
+    /// Each request we've received, as `"METHOD /path"`, with its query
+    /// string and body.
+    requests: std::sync::Arc<std::sync::Mutex<Vec<(String, String, String)>>>,
+}
+
+#[cfg(all(test, unix))]
+impl FakeEngine {
+    /// Start a server which calls `respond` with the method and path of
+    /// each request, and returns the HTTP status and JSON body to send.
+    fn start(respond: fn(&str, &str) -> (u16, &'static str)) -> FakeEngine {
+        use std::os::unix::net::UnixListener;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        let socket = std::env::temp_dir()
+            .join(format!("cage-fake-engine-{}.sock", random::<u32>()));
+        let listener = UnixListener::bind(&socket).unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let recorded = recorded.clone();
+                thread::spawn(move || {
+                    let _ = FakeEngine::serve(stream, respond, &recorded);
+                });
+            }
+        });
+        FakeEngine { socket, requests }
+    }
+
+    /// Answer HTTP/1.1 requests on a connection until the client hangs up.
+    fn serve(
+        stream: std::os::unix::net::UnixStream,
+        respond: fn(&str, &str) -> (u16, &'static str),
+        recorded: &std::sync::Mutex<Vec<(String, String, String)>>,
+    ) -> io::Result<()> {
+        use std::io::{BufRead, BufReader, Read};
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line)? == 0 {
+                return Ok(());
+            }
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or("").to_owned();
+            let uri = parts.next().unwrap_or("");
+            let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+            // Strip the API version, as in `/v1.47/containers/json`.
+            let path = match path.strip_prefix("/v") {
+                Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => {
+                    &rest[rest.find('/').unwrap_or(rest.len())..]
+                }
+                _ => path,
+            };
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header)?;
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+
+            recorded.lock().unwrap().push((
+                format!("{} {}", method, path),
+                query.to_owned(),
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+            let (status, response) = respond(&method, path);
+            write!(
+                writer,
+                "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            )?;
+        }
+    }
+
+    /// Connect to this server.
+    fn engine(&self) -> Engine {
+        Engine::connect_to_socket(&self.socket).unwrap()
+    }
+
+    /// The requests we've received, as `"METHOD /path"`.
+    fn requests(&self) -> Vec<String> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|(r, _, _)| r.to_owned()).collect()
+    }
+
+    /// The query string and body of the first request matching `request`.
+    fn request(&self, request: &str) -> (String, serde_json::Value) {
+        let requests = self.requests.lock().unwrap();
+        let (_, query, body) = requests.iter().find(|(r, _, _)| r == request).unwrap();
+        (query.to_owned(), serde_json::from_str(body).unwrap())
+    }
+}
+
+#[cfg(all(test, unix))]
+impl Drop for FakeEngine {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+#[test]
+fn engine_commands_are_parsed_from_compose_args() {
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+    assert_eq!(
+        EngineCommand::parse("up", &args(&["-d"])),
+        Some(EngineCommand::Up)
+    );
+    assert_eq!(
+        EngineCommand::parse("rm", &args(&["-f", "-v", "-s"])),
+        Some(EngineCommand::Rm {
+            remove_volumes: true,
+            stop: true,
+        })
+    );
+    assert_eq!(
+        EngineCommand::parse("logs", &args(&["-f", "--tail=12"])),
+        Some(EngineCommand::Logs {
+            follow: true,
+            tail: Some("12".to_owned()),
+        })
+    );
+    assert_eq!(EngineCommand::parse("build", &args(&[])), None);
+    assert_eq!(EngineCommand::parse("stop", &args(&["--timeout=1"])), None);
+}
+
+#[test]
+fn config_hashes_use_fnv1a() {
+    // Reference values from the FNV specification.
+    assert_eq!(fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a_64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a_64(b"foobar"), 0x8594_4171_f739_67e8);
+}
+
+#[test]
+fn port_bindings_are_parsed() {
+    let ports: serde_yaml::Value =
+        serde_yaml::from_str("['3000:3000', '127.0.0.1:53:53/udp', 8080]").unwrap();
+    let map = port_bindings(Some(&ports)).unwrap();
+    let binding = |port: &str| map[port].as_ref().unwrap()[0].clone();
+    assert_eq!(binding("3000/tcp").host_port.as_deref(), Some("3000"));
+    assert_eq!(binding("3000/tcp").host_ip, None);
+    assert_eq!(binding("53/udp").host_ip.as_deref(), Some("127.0.0.1"));
+    assert_eq!(binding("8080/tcp").host_port.as_deref(), Some(""));
+}
+
+#[test]
+#[cfg(unix)]
+fn up_creates_network_volumes_and_labeled_containers() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("rails_hello").unwrap();
+    let server = FakeEngine::start(|method, path| match (method, path) {
+        ("GET", "/containers/json") => (200, "[]"),
+        ("GET", "/networks/railshello_default") => (404, r#"{"message":"missing"}"#),
+        ("POST", "/networks/create") => (201, r#"{"Id":"net1","Warning":""}"#),
+        ("POST", "/volumes/create") => (
+            201,
+            r#"{"Name":"railshello_db","Driver":"local","Mountpoint":"/db",
+                "Labels":{},"Scope":"local","Options":{}}"#,
+        ),
+        ("POST", "/containers/create") => (201, r#"{"Id":"c1","Warnings":[]}"#),
+        ("POST", "/containers/c1/start") => (204, ""),
+        _ => (500, r#"{"message":"unexpected request"}"#),
+    });
+
+    let pod = proj.pod("db").unwrap();
+    server
+        .engine()
+        .compose(
+            &TestCommandRunner::new(),
+            &proj,
+            pod,
+            None,
+            &EngineCommand::Up,
+        )
+        .unwrap();
+    assert_eq!(
+        server.requests(),
+        &[
+            "GET /networks/railshello_default",
+            "POST /networks/create",
+            "POST /volumes/create",
+            "GET /containers/json",
+            "POST /containers/create",
+            "POST /containers/c1/start",
+        ]
+    );
+
+    let (_, network) = server.request("POST /networks/create");
+    assert_eq!(network["Name"], "railshello_default");
+    let (_, volume) = server.request("POST /volumes/create");
+    assert_eq!(volume["Name"], "railshello_db");
+    assert_eq!(volume["Labels"][PROJECT_LABEL], "railshello");
+
+    let (query, container) = server.request("POST /containers/create");
+    assert!(query.contains("name=railshello_db_1"));
+    assert_eq!(container["Image"], "postgres");
+    assert_eq!(container["Labels"][PROJECT_LABEL], "railshello");
+    assert_eq!(container["Labels"][SERVICE_LABEL], "db");
+    assert_eq!(container["Labels"]["io.fdy.cage.target"], "development");
+    assert_eq!(
+        container["HostConfig"]["Binds"][0],
+        "railshello_db:/var/lib/postgresql/data"
+    );
+    assert_eq!(container["HostConfig"]["NetworkMode"], "railshello_default");
+}
+
+#[test]
+#[cfg(unix)]
+fn rm_stops_and_removes_existing_containers() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("rails_hello").unwrap();
+    let server = FakeEngine::start(|method, path| match (method, path) {
+        ("GET", "/containers/json") => (
+            200,
+            r#"[{"Id":"c1","Names":["/railshello_db_1"],"Labels":{
+                "com.docker.compose.project":"railshello",
+                "com.docker.compose.service":"db",
+                "com.docker.compose.oneoff":"False"}},
+               {"Id":"c2","Names":["/other_db_1"],"Labels":{
+                "com.docker.compose.project":"other",
+                "com.docker.compose.service":"db"}}]"#,
+        ),
+        ("GET", "/containers/c1/json") => (
+            200,
+            r#"{"Id":"c1","State":{"Running":true,"Status":"running"}}"#,
+        ),
+        ("POST", "/containers/c1/stop") => (204, ""),
+        ("DELETE", "/containers/c1") => (204, ""),
+        _ => (500, r#"{"message":"unexpected request"}"#),
+    });
+
+    let pod = proj.pod("db").unwrap();
+    let command = EngineCommand::Rm {
+        remove_volumes: true,
+        stop: true,
+    };
+    server
+        .engine()
+        .compose(&TestCommandRunner::new(), &proj, pod, None, &command)
+        .unwrap();
+    assert_eq!(
+        server.requests(),
+        &[
+            "GET /containers/json",
+            "GET /containers/c1/json",
+            "POST /containers/c1/stop",
+            "DELETE /containers/c1",
+        ]
+    );
+    let requests = server.requests.lock().unwrap();
+    assert!(requests[3].1.contains("v=true"));
+}
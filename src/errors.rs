@@ -10,6 +10,7 @@ use std::ffi::OsString;
 use std::path::PathBuf;
 use thiserror::Error;
 
+use crate::pod::WaitFor;
 use crate::project::PROJECT_CONFIG_PATH;
 use crate::version;
 
@@ -20,6 +21,9 @@ pub enum Error {
     #[error("error running '{}'", command_to_string(.0))]
     CommandFailed(Vec<OsString>),
 
+    #[error("container '{}' exited with status {}", .container, .code)]
+    ContainerExited { container: String, code: i64 },
+
     #[error("error getting the project's state from Docker")]
     CouldNotGetRuntimeState,
 
@@ -29,6 +33,16 @@ pub enum Error {
         input: String,
     },
 
+    #[error("could not decrypt '{}' using any of our keys", .0.display())]
+    CouldNotDecrypt(PathBuf),
+
+    #[error("could not get secret {} for service '{}' in pod '{}'", .var, .service, .pod)]
+    CouldNotResolveSecret {
+        pod: String,
+        service: String,
+        var: String,
+    },
+
     #[error("could not read '{}'", .0.display())]
     CouldNotReadDirectory(PathBuf),
 
@@ -38,15 +52,31 @@ pub enum Error {
     #[error("could not write to '{}'", .0.display())]
     CouldNotWriteFile(PathBuf),
 
+    #[error("error talking to the Docker Engine API while trying to {}", .0)]
+    EngineApi(String),
+
     #[error("this feature was disabled when the application was compiled (you may want to rebuild from source)")]
     FeatureDisabled,
 
     #[error("{} specifies cage_version {}, but you have {}", PROJECT_CONFIG_PATH.display(), .0, version())]
     MismatchedVersion(semver::VersionReq),
 
+    #[error("can't get the output of '{}' during a dry run", command_to_string(.0))]
+    NoOutputDuringDryRun(Vec<OsString>),
+
     #[error("output directory {} already exists (please delete)", .0.display())]
     OutputDirectoryExists(PathBuf),
 
+    #[error("pods have a circular dependency: {}", .0.join(" -> "))]
+    PodDependencyCycle(Vec<String>),
+
+    #[error("timed out after {}s waiting for pod '{}' to be {}", .seconds, .pod, .wait_for)]
+    PodNotReady {
+        pod: String,
+        wait_for: WaitFor,
+        seconds: u64,
+    },
+
     #[error("plugin '{}' failed", .0)]
     PluginFailed(String),
 
@@ -63,9 +93,15 @@ pub enum Error {
     #[error("library '{}' may not specify a subdirectory in its git URL", .0)]
     LibHasRepoSubdirectory(String),
 
+    #[error("service '{}' in pod '{}' failed its health check", .service, .pod)]
+    ServiceUnhealthy { pod: String, service: String },
+
     #[error("unknown target '{}'", .0)]
     UnknownTarget(String),
 
+    #[error("pod '{}' depends on unknown pod '{}'", .pod, .dependency)]
+    UnknownPodDependency { pod: String, dependency: String },
+
     #[error("unknown pod or service '{}'", .0)]
     UnknownPodOrService(String),
 
@@ -75,6 +111,9 @@ pub enum Error {
     #[error("unknown short alias '{}' for source tree (try `cage source ls`)", .0)]
     UnknownSource(String),
 
+    #[error("source tree '{}' has no worktree named '{}'", .alias, .worktree)]
+    UnknownWorktree { alias: String, worktree: String },
+
     #[error("an error occurred talking to the Vault server at {}", .0)]
     VaultError(String),
 }
@@ -10,6 +10,18 @@ use crate::errors::*;
 pub trait GitUrlExt {
     /// Turn this URL into arguments to `git clone`.
     fn clone_args(&self) -> Result<Vec<OsString>>;
+
+    /// The branch specified after the `#` in this URL, if any.
+    fn branch(&self) -> Option<&str>
+    where
+        Self: AsRef<str>,
+    {
+        let url_str: &str = self.as_ref();
+        url_str
+            .find('#')
+            .map(|pos| &url_str[pos + 1..])
+            .filter(|branch| !branch.is_empty())
+    }
 }
 
 impl GitUrlExt for dc::GitUrl {
@@ -38,3 +50,14 @@ fn clone_args_handles_branch() {
         vec!["-b".into(), "dev".into(), expected_url]
     );
 }
+
+#[test]
+fn branch_is_parsed_from_fragment() {
+    let master =
+        dc::GitUrl::new("https://github.com/faradayio/rails_hello.git").unwrap();
+    assert_eq!(master.branch(), None);
+
+    let branch =
+        dc::GitUrl::new("https://github.com/faradayio/rails_hello.git#dev").unwrap();
+    assert_eq!(branch.branch(), Some("dev"));
+}
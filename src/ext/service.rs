@@ -21,6 +21,13 @@ pub trait ServiceExt {
     /// out.
     fn source_mount_dir(&self) -> Result<String>;
 
+    /// How should we mount source trees into this service?  See
+    /// `SourceMountOptions` for the labels we read.  By default, we use the
+    /// default options.
+    fn source_mount_options(&self) -> Result<SourceMountOptions> {
+        Ok(SourceMountOptions::default())
+    }
+
     /// The subdirectory inside the source where the code for this service is located.
     /// `Ok(None)` either means that this service has no build context, or that
     /// its context is not a git repository, or that its context is a git repository
@@ -35,6 +42,14 @@ pub trait ServiceExt {
     /// Get the test command associated with this service.
     fn test_command(&self) -> Result<Vec<String>>;
 
+    /// Get the command to run inside this service's container when its
+    /// mounted source code changes, as specified by the label
+    /// `io.fdy.cage.watch.command`.  If this is `None`, `cage watch`
+    /// restarts the service instead, which is also the default.
+    fn watch_command(&self) -> Result<Option<Vec<String>>> {
+        Ok(None)
+    }
+
     /// All the `Source` trees which can be mounted into this `Service`.
     /// Note that this iterator does not hold any references to this
     /// `Service` object, so you can use it to decide how you want to
@@ -58,6 +73,64 @@ impl ServiceExt for dc::Service {
         Ok(srcdir.value()?.to_owned())
     }
 
+    fn source_mount_options(&self) -> Result<SourceMountOptions> {
+        let label = |name: &str| -> Result<Option<&str>> {
+            match self.labels.get(name) {
+                Some(value) => Ok(Some(value.value()?)),
+                None => Ok(None),
+            }
+        };
+        let words = |value: &str| -> Vec<String> {
+            value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(|word| word.to_owned())
+                .collect()
+        };
+
+        let mut options = SourceMountOptions::default();
+        match label("io.fdy.cage.src.readonly")? {
+            None | Some("false") => {}
+            Some("true") => options.read_only = true,
+            Some(other) => {
+                return Err(Error::parse("io.fdy.cage.src.readonly", other).into())
+            }
+        }
+        match label("io.fdy.cage.src.consistency")? {
+            None => {}
+            Some(mode @ "consistent")
+            | Some(mode @ "cached")
+            | Some(mode @ "delegated") => {
+                options.consistency = Some(mode.to_owned());
+            }
+            Some(other) => {
+                return Err(Error::parse("io.fdy.cage.src.consistency", other).into())
+            }
+        }
+        if let Some(mounts) = label("io.fdy.cage.src.mounts")? {
+            for mount in words(mounts) {
+                match mount.split_once(':') {
+                    Some((subpath, container_path))
+                        if !subpath.is_empty() && !container_path.is_empty() =>
+                    {
+                        options
+                            .extra_mounts
+                            .push((subpath.to_owned(), container_path.to_owned()));
+                    }
+                    _ => {
+                        return Err(
+                            Error::parse("io.fdy.cage.src.mounts", mount).into()
+                        )
+                    }
+                }
+            }
+        }
+        if let Some(excludes) = label("io.fdy.cage.src.exclude")? {
+            options.excludes = words(excludes);
+        }
+        Ok(options)
+    }
+
     fn repository_subdirectory(&self) -> Result<Option<String>> {
         if let Some(context) = self.context()? {
             return match *context {
@@ -89,6 +162,20 @@ impl ServiceExt for dc::Service {
         }
     }
 
+    fn watch_command(&self) -> Result<Option<Vec<String>>> {
+        let raw = match self.labels.get("io.fdy.cage.watch.command") {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let mut lexer = shlex::Shlex::new(raw.value()?);
+        let result: Vec<String> = lexer.by_ref().collect();
+        if lexer.had_error || result.is_empty() {
+            Err(err!("cannot parse <{}> into shell words", raw))
+        } else {
+            Ok(Some(result))
+        }
+    }
+
     fn sources<'b>(&self, sources: &'b sources::Sources) -> Result<Sources<'b>> {
         // Get our `context`, if any.
         let container_path = self.source_mount_dir()?;
@@ -107,6 +194,7 @@ impl ServiceExt for dc::Service {
                 container_path,
                 source,
                 source_subdirectory,
+                is_build_context: true,
             });
 
         // Get our library keys and mount points.
@@ -124,6 +212,7 @@ impl ServiceExt for dc::Service {
                     container_path: mount_as.value()?.to_owned(),
                     source,
                     source_subdirectory: None,
+                    is_build_context: false,
                 })
             }
         }
@@ -145,10 +234,67 @@ pub struct Sources<'a> {
 }
 
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct SourceMount<'a> {
     pub container_path: String,
     pub source: &'a Source,
     pub source_subdirectory: Option<String>,
+    /// Is this the source tree for the service's own `build` context,
+    /// rather than a library?
+    pub is_build_context: bool,
+}
+
+impl<'a> SourceMount<'a> {
+    /// Mount `source` as a library at `container_path`.  Set
+    /// `is_build_context` afterwards if it's the service's own source tree.
+    pub fn new(
+        container_path: String,
+        source: &'a Source,
+        source_subdirectory: Option<String>,
+    ) -> SourceMount<'a> {
+        SourceMount {
+            container_path,
+            source,
+            source_subdirectory,
+            is_build_context: false,
+        }
+    }
+}
+
+/// Per-service options for mounting source trees, read from labels.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMountOptions {
+    /// Mount source trees read-only (`io.fdy.cage.src.readonly: "true"`).
+    pub read_only: bool,
+    /// The consistency mode for bind mounts, one of `consistent`, `cached`
+    /// or `delegated` (`io.fdy.cage.src.consistency`).
+    pub consistency: Option<String>,
+    /// Extra mounts of the form `(subpath, container_path)`, where
+    /// `subpath` is relative to the root of the build context's source tree
+    /// (`io.fdy.cage.src.mounts: "db/migrate:/migrations"`).
+    pub extra_mounts: Vec<(String, String)>,
+    /// Directories inside the build context's mount point which should be
+    /// hidden from the host using anonymous volumes
+    /// (`io.fdy.cage.src.exclude: "node_modules tmp/cache"`).
+    pub excludes: Vec<String>,
+}
+
+impl SourceMountOptions {
+    /// The mode to append to a bind mount, such as `ro,cached`, if any.
+    pub fn mode(&self) -> Option<String> {
+        let mut modes = vec![];
+        if self.read_only {
+            modes.push("ro");
+        }
+        if let Some(ref consistency) = self.consistency {
+            modes.push(consistency);
+        }
+        if modes.is_empty() {
+            None
+        } else {
+            Some(modes.join(","))
+        }
+    }
 }
 
 impl<'a> Iterator for Sources<'a> {
@@ -223,3 +369,30 @@ fn shell_returns_preferred_shell_for_this_service() {
     let proxy = merged.services.get("proxy").unwrap();
     assert_eq!(proxy.shell().unwrap(), "/bin/sh");
 }
+
+#[test]
+fn source_mount_options_are_read_from_labels() {
+    let _ = env_logger::try_init();
+    let proj: Project = Project::from_fixture("with_source_mount_options").unwrap();
+    let target = proj.target("development").unwrap();
+    let frontend = proj.pod("frontend").unwrap();
+    let merged = frontend.merged_file(target).unwrap();
+
+    // Default value.
+    let worker = merged.services.get("worker").unwrap();
+    let options = worker.source_mount_options().unwrap();
+    assert_eq!(options, SourceMountOptions::default());
+    assert_eq!(options.mode(), None);
+
+    // Custom values.
+    let web = merged.services.get("web").unwrap();
+    let options = web.source_mount_options().unwrap();
+    assert!(options.read_only);
+    assert_eq!(options.consistency.as_deref(), Some("cached"));
+    assert_eq!(options.mode().as_deref(), Some("ro,cached"));
+    assert_eq!(
+        options.extra_mounts,
+        vec![("db/migrate".to_owned(), "/migrations".to_owned())]
+    );
+    assert_eq!(options.excludes, vec!["node_modules", "tmp/cache"]);
+}
@@ -0,0 +1,586 @@
+//! Export a project as Kubernetes manifests.
+//!
+//! Each service in a service or placeholder pod becomes a `Deployment`,
+//! plus a `Service` if it has any ports.  Each task pod becomes a `Job`.
+//! Any `*.env` files become `ConfigMap` objects, and values from
+//! `config/secrets.yml` become `Secret` objects, both of which are
+//! referenced using `envFrom`.  Values from `config/secrets.yml.age` are only
+//! included if `Project::export_secrets` is set.
+
+use faraday_compose_yml::v2 as dc;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::result;
+
+use crate::command_runner::CaptureOutput;
+use crate::errors::*;
+use crate::plugins::transform::secrets;
+use crate::plugins::{Operation, PluginNew};
+use crate::pod::{Pod, PodType};
+use crate::project::Project;
+use crate::util::ConductorPathExt;
+
+/// Export `project` as a directory of Kubernetes manifests, using `runner`
+/// to run any commands our plugins need.
+pub(crate) fn export(
+    project: &Project,
+    runner: &dyn CaptureOutput,
+    export_dir: &Path,
+) -> Result<()> {
+    let secrets = if secrets::Plugin::is_configured_for(project)? {
+        Some(secrets::Plugin::new(project)?)
+    } else {
+        None
+    };
+
+    let mut env_files = BTreeSet::new();
+    for pod in project.pods() {
+        if !pod.enabled_in(project.current_target()) {
+            continue;
+        }
+
+        // We need to look at `env_file` before `make_standalone` has a
+        // chance to fold it into `environment`.
+        let merged = pod.merged_file(project.current_target())?;
+        let file = project.processed_file(runner, Operation::Export, "export", pod)?;
+        let exporter = PodExporter {
+            project,
+            pod,
+            secrets: secrets.as_ref(),
+            runner,
+        };
+        let docs = exporter.manifests(&merged, &file, &mut env_files)?;
+
+        let file_name = format!("{}.yml", pod.name());
+        let rel_path = match pod.pod_type() {
+            PodType::Task => Path::new("tasks").join(file_name),
+            _ => PathBuf::from(file_name),
+        };
+        write_manifests(&export_dir.join(rel_path), &docs)?;
+    }
+
+    let config_maps = env_files
+        .iter()
+        .map(|rel_path| config_map(project, rel_path))
+        .collect::<Result<Vec<_>>>()?;
+    if !config_maps.is_empty() {
+        write_manifests(&export_dir.join("configmaps.yml"), &config_maps)?;
+    }
+    Ok(())
+}
+
+/// Converts the services in a single pod to Kubernetes manifests.
+struct PodExporter<'a> {
+    /// The project we're exporting.
+    project: &'a Project,
+    /// The pod we're exporting.
+    pod: &'a Pod,
+    /// Our secrets plugin, if it's configured for this project.
+    secrets: Option<&'a secrets::Plugin>,
+    /// Used to look up secrets which come from commands.
+    runner: &'a dyn CaptureOutput,
+}
+
+impl<'a> PodExporter<'a> {
+    /// Build all the manifests for this pod.  `merged` is the pod's file
+    /// before it was made standalone, and `file` is the fully-processed
+    /// version.  We add any env files we reference to `env_files`.
+    fn manifests(
+        &self,
+        merged: &dc::File,
+        file: &dc::File,
+        env_files: &mut BTreeSet<PathBuf>,
+    ) -> Result<Vec<Value>> {
+        let mut docs = vec![];
+        let mut containers = vec![];
+        let pods_dir = self.project.pods_dir();
+        for (service_name, service) in &file.services {
+            // We automatically add `common.env` files to every service, so
+            // skip any which don't actually exist.
+            let service_env_files = match merged.services.get(service_name) {
+                Some(merged_service) => merged_service
+                    .env_files
+                    .iter()
+                    .map(|path| Ok(path.value()?.to_owned()))
+                    .filter(|path: &Result<PathBuf>| match path {
+                        Ok(path) => pods_dir.join(path).exists(),
+                        Err(_) => true,
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+                None => vec![],
+            };
+            env_files.extend(service_env_files.iter().cloned());
+
+            let secrets = match self.secrets {
+                Some(plugin) => plugin.service_secrets(
+                    self.runner,
+                    Operation::Export,
+                    self.project,
+                    self.pod.name(),
+                    service_name,
+                )?,
+                None => BTreeMap::new(),
+            };
+            if !secrets.is_empty() {
+                docs.push(json!({
+                    "apiVersion": "v1",
+                    "kind": "Secret",
+                    "metadata": {
+                        "name": self.secret_name(service_name),
+                        "labels": self.labels(service_name),
+                    },
+                    "type": "Opaque",
+                    "stringData": secrets,
+                }));
+            }
+
+            let container =
+                self.container(service_name, service, &service_env_files, &secrets)?;
+            match self.pod.pod_type() {
+                PodType::Task => containers.push(container),
+                _ => {
+                    docs.push(self.deployment(service_name, container.value));
+                    if !container.ports.is_empty() {
+                        docs.push(self.service(service_name, &container.ports));
+                    }
+                }
+            }
+        }
+
+        if self.pod.pod_type() == PodType::Task && !containers.is_empty() {
+            docs.push(self.job(containers.into_iter().map(|c| c.value).collect()));
+        }
+        Ok(docs)
+    }
+
+    /// Build a Kubernetes container definition for a service.
+    fn container(
+        &self,
+        service_name: &str,
+        service: &dc::Service,
+        env_files: &[PathBuf],
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<Container> {
+        let raw = serde_yaml::to_value(service)?;
+        let mut container = json!({ "name": k8s_name(service_name) });
+        if let Some(image) = raw.get("image").and_then(|v| v.as_str()) {
+            container["image"] = json!(image);
+        }
+        if let Some(entrypoint) = command_line(raw.get("entrypoint"))? {
+            container["command"] = json!(entrypoint);
+        }
+        if let Some(command) = command_line(raw.get("command"))? {
+            container["args"] = json!(command);
+        }
+        if let Some(working_dir) = raw.get("working_dir").and_then(|v| v.as_str()) {
+            container["workingDir"] = json!(working_dir);
+        }
+        if raw.get("volumes").is_some() {
+            warn!(
+                "Not exporting volumes for service '{}' to Kubernetes",
+                service_name
+            );
+        }
+
+        // Anything we can get from a `ConfigMap` or `Secret` doesn't need to
+        // be repeated inline.  Later sources take precedence, just like they
+        // do for `env_file`.
+        let mut env_from = vec![];
+        let mut inherited = BTreeMap::new();
+        for rel_path in env_files {
+            inherited.extend(read_env_file(&self.project.pods_dir().join(rel_path))?);
+            env_from.push(json!({
+                "configMapRef": { "name": config_map_name(self.project, rel_path) },
+            }));
+        }
+        if !secrets.is_empty() {
+            inherited.extend(secrets.clone());
+            env_from.push(json!({
+                "secretRef": { "name": self.secret_name(service_name) },
+            }));
+        }
+        let mut env = vec![];
+        for (name, value) in &service.environment {
+            let value = value.value()?;
+            if inherited.get(name) != Some(value) {
+                env.push(json!({ "name": name, "value": value }));
+            }
+        }
+        if !env_from.is_empty() {
+            container["envFrom"] = json!(env_from);
+        }
+        if !env.is_empty() {
+            container["env"] = json!(env);
+        }
+
+        let mut ports = vec![];
+        for key in &["ports", "expose"] {
+            if let Some(seq) = raw.get(key).and_then(|v| v.as_sequence()) {
+                for port in seq {
+                    if let Some(port) = container_port(port)? {
+                        if !ports.contains(&port) {
+                            ports.push(port);
+                        }
+                    }
+                }
+            }
+        }
+        if !ports.is_empty() {
+            container["ports"] = json!(ports
+                .iter()
+                .map(|p| json!({ "containerPort": p.port, "protocol": p.protocol }))
+                .collect::<Vec<_>>());
+        }
+
+        Ok(Container {
+            value: container,
+            ports,
+        })
+    }
+
+    /// Build a `Deployment` for a service.
+    fn deployment(&self, service_name: &str, container: Value) -> Value {
+        json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": {
+                "name": k8s_name(service_name),
+                "labels": self.labels(service_name),
+            },
+            "spec": {
+                "replicas": 1,
+                "selector": { "matchLabels": self.selector(service_name) },
+                "template": {
+                    "metadata": { "labels": self.labels(service_name) },
+                    "spec": { "containers": [container] },
+                },
+            },
+        })
+    }
+
+    /// Build a `Service` for a service, so that other services can reach
+    /// it using the same host name they'd use under `docker-compose`.
+    fn service(&self, service_name: &str, ports: &[ContainerPort]) -> Value {
+        let ports = ports
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": format!("{}-{}", p.protocol.to_lowercase(), p.port),
+                    "port": p.port,
+                    "targetPort": p.port,
+                    "protocol": p.protocol,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": {
+                "name": k8s_name(service_name),
+                "labels": self.labels(service_name),
+            },
+            "spec": {
+                "selector": self.selector(service_name),
+                "ports": ports,
+            },
+        })
+    }
+
+    /// Build a `Job` which runs all the containers in a task pod.
+    fn job(&self, containers: Vec<Value>) -> Value {
+        let name = format!(
+            "{}-{}",
+            k8s_name(self.project.name()),
+            k8s_name(self.pod.name())
+        );
+        let labels = json!({
+            "app.kubernetes.io/part-of": k8s_name(self.project.name()),
+            "io.fdy.cage.pod": self.pod.name(),
+        });
+        json!({
+            "apiVersion": "batch/v1",
+            "kind": "Job",
+            "metadata": { "name": name, "labels": labels },
+            "spec": {
+                "backoffLimit": 0,
+                "template": {
+                    "metadata": { "labels": labels },
+                    "spec": {
+                        "restartPolicy": "Never",
+                        "containers": containers,
+                    },
+                },
+            },
+        })
+    }
+
+    /// The name of the `Secret` holding the secrets for a service.
+    fn secret_name(&self, service_name: &str) -> String {
+        format!(
+            "{}-{}-secrets",
+            k8s_name(self.project.name()),
+            k8s_name(service_name)
+        )
+    }
+
+    /// Labels used to select the pods for a service.
+    fn selector(&self, service_name: &str) -> Value {
+        json!({
+            "app.kubernetes.io/name": k8s_name(service_name),
+            "app.kubernetes.io/part-of": k8s_name(self.project.name()),
+        })
+    }
+
+    /// Labels to apply to every object we generate for a service.
+    fn labels(&self, service_name: &str) -> Value {
+        let mut labels = self.selector(service_name);
+        labels["io.fdy.cage.pod"] = json!(self.pod.name());
+        labels
+    }
+}
+
+/// A Kubernetes container definition, plus the ports it exposes.
+struct Container {
+    /// The container definition itself.
+    value: Value,
+    /// The ports exposed by this container.
+    ports: Vec<ContainerPort>,
+}
+
+/// A port exposed by a container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ContainerPort {
+    /// The port number inside the container.
+    port: u16,
+    /// `"TCP"` or `"UDP"`.
+    protocol: &'static str,
+}
+
+/// Parse a `ports` or `expose` entry, and return the port inside the
+/// container.  We skip port ranges, which Kubernetes doesn't support.
+fn container_port(value: &serde_yaml::Value) -> Result<Option<ContainerPort>> {
+    let spec = match value {
+        serde_yaml::Value::String(s) => s.to_owned(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        _ => return Err(Error::parse("port mapping", format!("{:?}", value)).into()),
+    };
+    let (spec, protocol) = match spec.split_once('/') {
+        Some((spec, "udp")) => (spec, "UDP"),
+        Some((spec, _)) => (spec, "TCP"),
+        None => (&spec[..], "TCP"),
+    };
+    let container = spec.rsplit(':').next().unwrap_or(spec);
+    if container.contains('-') {
+        warn!("Not exporting port range {} to Kubernetes", container);
+        return Ok(None);
+    }
+    let port = container
+        .parse::<u16>()
+        .map_err(|_| Error::parse("port mapping", spec))?;
+    Ok(Some(ContainerPort { port, protocol }))
+}
+
+/// Convert a `command` or `entrypoint` value to a list of arguments.
+pub(crate) fn command_line(
+    value: Option<&serde_yaml::Value>,
+) -> Result<Option<Vec<String>>> {
+    match value {
+        None | Some(serde_yaml::Value::Null) => Ok(None),
+        Some(serde_yaml::Value::String(s)) => shlex::split(s)
+            .map(Some)
+            .ok_or_else(|| Error::parse("command line", s.to_owned()).into()),
+        Some(serde_yaml::Value::Sequence(seq)) => seq
+            .iter()
+            .map(|arg| {
+                arg.as_str()
+                    .map(|s| s.to_owned())
+                    .ok_or_else(|| Error::parse("command line", format!("{:?}", arg)))
+            })
+            .collect::<result::Result<Vec<_>, _>>()
+            .map(Some)
+            .map_err(|e| e.into()),
+        Some(other) => {
+            Err(Error::parse("command line", format!("{:?}", other)).into())
+        }
+    }
+}
+
+/// Build a `ConfigMap` from an env file.
+fn config_map(project: &Project, rel_path: &Path) -> Result<Value> {
+    let data = read_env_file(&project.pods_dir().join(rel_path))?;
+    Ok(json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": {
+            "name": config_map_name(project, rel_path),
+            "labels": { "app.kubernetes.io/part-of": k8s_name(project.name()) },
+        },
+        "data": data,
+    }))
+}
+
+/// The name of the `ConfigMap` for an env file.  `targets/production/common.env`
+/// becomes `$PROJECT-production-common`.
+fn config_map_name(project: &Project, rel_path: &Path) -> String {
+    let mut parts = vec![k8s_name(project.name())];
+    let components = rel_path.with_extension("");
+    for component in components.components() {
+        if let Component::Normal(part) = component {
+            let part = part.to_string_lossy();
+            if part != "targets" {
+                parts.push(k8s_name(&part));
+            }
+        }
+    }
+    parts.join("-")
+}
+
+/// Read a `docker-compose` env file containing `VAR=value` lines.
+fn read_env_file(path: &Path) -> Result<BTreeMap<String, String>> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotReadFile(path.to_owned()))
+    })?;
+    let mut vars = BTreeMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| Error::parse("env file line", line))?;
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        vars.insert(name.trim().to_owned(), value.to_owned());
+    }
+    Ok(vars)
+}
+
+/// Convert a name into something Kubernetes will accept as an object name
+/// or label value.
+fn k8s_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let name = name.trim_matches('-');
+    name[..name.len().min(63)].trim_end_matches('-').to_owned()
+}
+
+/// Write a list of Kubernetes objects to a multi-document YAML file.
+fn write_manifests(path: &Path, docs: &[Value]) -> Result<()> {
+    let mut out = String::new();
+    for doc in docs {
+        out.push_str("---\n");
+        out.push_str(&serde_yaml::to_string(doc)?);
+    }
+    let path = path
+        .with_guaranteed_parent()
+        .map_err(|e| e.context(Error::CouldNotWriteFile(path.to_owned())))?;
+    fs::write(&path, out).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotWriteFile(path.to_owned()))
+    })
+}
+
+#[cfg(test)]
+fn read_manifests(path: &Path) -> Vec<Value> {
+    use serde::Deserialize;
+
+    let text = fs::read_to_string(path).unwrap();
+    serde_yaml::Deserializer::from_str(&text)
+        .map(|doc| Value::deserialize(doc).unwrap())
+        .collect()
+}
+
+#[test]
+fn k8s_names_are_sanitized() {
+    assert_eq!(k8s_name("rails_hello"), "rails-hello");
+    assert_eq!(k8s_name("_Web.Server_"), "web-server");
+}
+
+#[test]
+fn container_ports_are_parsed() {
+    let port = |s: &str| container_port(&serde_yaml::Value::String(s.to_owned()));
+    let tcp = |port| ContainerPort {
+        port,
+        protocol: "TCP",
+    };
+    assert_eq!(port("3000").unwrap(), Some(tcp(3000)));
+    assert_eq!(port("8080:80").unwrap(), Some(tcp(80)));
+    assert_eq!(port("127.0.0.1:8080:80").unwrap(), Some(tcp(80)));
+    assert_eq!(
+        port("53:53/udp").unwrap(),
+        Some(ContainerPort {
+            port: 53,
+            protocol: "UDP",
+        })
+    );
+    assert_eq!(port("3000-3005").unwrap(), None);
+    assert!(port("http").is_err());
+}
+
+#[test]
+fn export_creates_kubernetes_manifests() {
+    use crate::project::ExportFormat;
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("rails_hello").unwrap();
+    let export_dir = proj.output_dir().join("k8s_export");
+    proj.set_current_target_name("production").unwrap();
+    proj.export_as(&export_dir, ExportFormat::Kubernetes)
+        .unwrap();
+
+    // Our `db` pod is a placeholder which isn't enabled in production.
+    assert!(!export_dir.join("db.yml").exists());
+
+    let frontend = read_manifests(&export_dir.join("frontend.yml"));
+    let kinds: Vec<&str> = frontend
+        .iter()
+        .map(|doc| doc["kind"].as_str().unwrap())
+        .collect();
+    assert_eq!(kinds, &["Secret", "Deployment", "Service"]);
+
+    let secret = &frontend[0];
+    assert_eq!(secret["metadata"]["name"], "rails-hello-web-secrets");
+    assert_eq!(secret["stringData"]["SOME_PASSWORD"], "production secret");
+
+    let container = &frontend[1]["spec"]["template"]["spec"]["containers"][0];
+    assert_eq!(container["name"], "web");
+    assert_eq!(container["image"], "faraday/rails_hello");
+    assert_eq!(
+        container["envFrom"],
+        json!([
+            { "configMapRef": { "name": "rails-hello-common" } },
+            { "configMapRef": { "name": "rails-hello-production-common" } },
+            { "secretRef": { "name": "rails-hello-web-secrets" } },
+        ])
+    );
+    // Secrets must never appear inline.
+    let inline_env = container["env"].as_array().cloned().unwrap_or_default();
+    assert!(inline_env.iter().all(|var| var["name"] != "SOME_PASSWORD"));
+
+    let service = &frontend[2];
+    assert_eq!(service["metadata"]["name"], "web");
+    assert_eq!(service["spec"]["ports"][0]["port"], 3000);
+
+    let rake = read_manifests(&export_dir.join("tasks").join("rake.yml"));
+    let job = rake.iter().find(|doc| doc["kind"] == "Job").unwrap();
+    assert_eq!(job["metadata"]["name"], "rails-hello-rake");
+    let job_spec = &job["spec"]["template"]["spec"];
+    assert_eq!(job_spec["restartPolicy"], "Never");
+    assert_eq!(job_spec["containers"][0]["command"], json!(["rake"]));
+
+    let config_maps = read_manifests(&export_dir.join("configmaps.yml"));
+    let production = config_maps
+        .iter()
+        .find(|doc| doc["metadata"]["name"] == "rails-hello-production-common")
+        .unwrap();
+    assert_eq!(production["data"]["RAILS_ENV"], "production");
+
+    proj.remove_test_output().unwrap();
+}
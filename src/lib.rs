@@ -51,13 +51,16 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub use crate::compose_backend::ComposeBackend;
 pub use crate::default_tags::DefaultTags;
 pub use crate::errors::*;
-pub use crate::pod::{AllFiles, Pod, PodType, TargetFiles};
-pub use crate::project::{PodOrService, Pods, Project, ProjectConfig, Targets};
-pub use crate::runtime_state::RuntimeState;
+pub use crate::pod::{AllFiles, Pod, PodType, TargetFiles, WaitFor};
+pub use crate::project::{
+    ComposeFormat, ExportFormat, PodOrService, Pods, Project, ProjectConfig, Targets,
+};
+pub use crate::runtime_state::{ContainerStatus, HealthStatus, RuntimeState};
 pub use crate::sources::Iter as SourceIter;
-pub use crate::sources::{Source, Sources};
+pub use crate::sources::{CloneOptions, GitStatus, PullOutcome, Source, Sources};
 pub use crate::target::Target;
 pub use crate::util::err;
 
@@ -67,11 +70,15 @@ pub mod args;
 #[macro_use]
 pub mod command_runner;
 pub mod cmd;
+mod compose_backend;
+mod compose_spec;
 mod default_tags;
 pub mod dir;
+mod engine;
 mod errors;
 mod ext;
 pub mod hook;
+mod k8s;
 pub mod plugins;
 mod pod;
 mod project;
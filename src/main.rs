@@ -4,7 +4,7 @@
 
 use cage::{
     cmd::*,
-    command_runner::{Command, CommandRunner, OsCommandRunner},
+    command_runner::{Command, CommandRunner, DryRunCommandRunner, OsCommandRunner},
     Error, Project, Result,
 };
 use clap::{Parser, Subcommand, ValueEnum};
@@ -15,7 +15,7 @@ use std::{
     env, fs,
     io::{self, Write},
     path::Path,
-    process,
+    process, time,
 };
 
 #[macro_use]
@@ -70,6 +70,21 @@ struct Cli {
     )]
     default_tags: Option<String>,
 
+    #[arg(
+        long = "dry-run",
+        help = "Print the commands which would be run, without running them.  Still updates .cage/pods."
+    )]
+    dry_run: bool,
+
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        value_name = "N",
+        default_value = "1",
+        help = "How many pods `up`, `stop`, `restart` and `rm -f` may work on at once"
+    )]
+    jobs: usize,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -87,6 +102,15 @@ enum Commands {
 
     #[command(about = "Print out the status of the current project")]
     Status {
+        #[arg(
+            long = "format",
+            value_enum,
+            value_name = "FORMAT",
+            default_value = "tree",
+            help = "How to display the project's status"
+        )]
+        format: StatusFormat,
+
         #[arg(
             value_name = "POD_OR_SERVICE",
             help = "Pod or service names.  Defaults to all."
@@ -139,6 +163,33 @@ enum Commands {
         pod_or_service: Vec<String>,
     },
 
+    #[command(
+        about = "Restart services, or run their io.fdy.cage.watch.command, when their mounted source code changes"
+    )]
+    Watch {
+        #[arg(
+            long = "debounce",
+            value_name = "MILLISECONDS",
+            default_value = "500",
+            help = "How long to wait after the last change before acting"
+        )]
+        debounce: u64,
+
+        #[arg(
+            long = "poll-interval",
+            value_name = "MILLISECONDS",
+            default_value = "250",
+            help = "How often to check for changes"
+        )]
+        poll_interval: u64,
+
+        #[arg(
+            value_name = "POD_OR_SERVICE",
+            help = "Pod or service names.  Defaults to all."
+        )]
+        pod_or_service: Vec<String>,
+    },
+
     #[command(about = "Stop all containers associated with this project")]
     Stop {
         #[arg(
@@ -148,6 +199,24 @@ enum Commands {
         pod_or_service: Vec<String>,
     },
 
+    #[command(
+        about = "Stop and remove all containers associated with this project, including one-off containers"
+    )]
+    Down {
+        #[arg(
+            short = 'v',
+            long = "volumes",
+            help = "Also remove volumes created for this project"
+        )]
+        remove_volumes: bool,
+
+        #[arg(
+            long = "networks",
+            help = "Also remove networks created for this project"
+        )]
+        remove_networks: bool,
+    },
+
     #[command(about = "Remove the containers associated with a pod or service")]
     Rm {
         #[arg(short = 'f', long = "force", help = "Remove without confirming first")]
@@ -353,24 +422,149 @@ command:
         command: SourceCommands,
     },
 
+    #[command(about = "Commands for working with encrypted secrets")]
+    Secrets {
+        #[command(subcommand)]
+        command: SecretsCommands,
+    },
+
+    #[command(about = "Commands for managing the Vault tokens issued to services")]
+    Vault {
+        #[command(subcommand)]
+        command: VaultCommands,
+    },
+
     #[command(about = "Commands for generating new source files")]
     Generate {
         #[command(subcommand)]
         command: GenerateCommands,
     },
 
-    #[command(about = "Export project as flattened *.yml files")]
+    #[command(
+        about = "Export project as flattened *.yml files or Kubernetes manifests"
+    )]
     Export {
+        #[arg(
+            long = "format",
+            value_enum,
+            value_name = "FORMAT",
+            default_value = "compose",
+            help = "The kind of files to export"
+        )]
+        format: ExportFormat,
+
+        #[arg(
+            long = "include-secrets",
+            help = "Include decrypted secrets from config/secrets.yml.age"
+        )]
+        include_secrets: bool,
+
         #[arg(value_name = "DIR", help = "The name of the directory to create")]
         dir: String,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum SecretsCommands {
+    #[command(
+        about = "Decrypt config/secrets.yml.age, edit it using $EDITOR, and re-encrypt it"
+    )]
+    Edit,
+}
+
+#[derive(Subcommand, Debug)]
+enum VaultCommands {
+    #[command(about = "List the tokens issued to services and their remaining TTLs")]
+    Tokens,
+    #[command(
+        about = "Revoke the tokens issued to services, when tearing down a project"
+    )]
+    Revoke,
+}
+
+#[derive(Subcommand, Debug)]
+enum WorktreeCommands {
+    #[command(about = "Check out a branch of a cloned source tree in a new worktree")]
+    Add {
+        #[arg(
+            value_name = "ALIAS",
+            help = "The short alias of the source tree (see `source list`)"
+        )]
+        alias: String,
+
+        #[arg(
+            value_name = "BRANCH",
+            help = "The branch to check out, which is also used as the worktree name"
+        )]
+        branch: String,
+    },
+
+    #[command(about = "Choose which worktree of a source tree gets mounted")]
+    Use {
+        #[arg(
+            value_name = "ALIAS",
+            help = "The short alias of the source tree (see `source list`)"
+        )]
+        alias: String,
+
+        #[arg(
+            value_name = "NAME",
+            help = "The worktree to mount.  Omit this to mount the main checkout."
+        )]
+        name: Option<String>,
+    },
+
+    #[command(about = "Remove a worktree from a source tree")]
+    Rm {
+        #[arg(
+            value_name = "ALIAS",
+            help = "The short alias of the source tree (see `source list`)"
+        )]
+        alias: String,
+
+        #[arg(value_name = "NAME", help = "The worktree to remove")]
+        name: String,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum SourceCommands {
     #[command(about = "List all known source tree aliases and URLs")]
     Ls,
 
+    #[command(
+        about = "Show the git branch, uncommitted changes and mount state of source trees"
+    )]
+    Status {
+        #[arg(
+            value_name = "ALIASES",
+            help = "The short aliases of the source trees to show (see `source list`).  Defaults to all source trees."
+        )]
+        aliases: Vec<String>,
+    },
+
+    #[command(
+        about = "Fetch and fast-forward cloned source trees which have no uncommitted changes"
+    )]
+    Pull {
+        #[arg(
+            value_name = "ALIASES",
+            help = "The short aliases of the source trees to update (see `source list`).  Defaults to all cloned source trees."
+        )]
+        aliases: Vec<String>,
+    },
+
+    #[command(
+        about = "Record the commit each source tree is at in config/sources.lock.yml"
+    )]
+    Lock {
+        #[arg(
+            value_name = "ALIASES",
+            help = "The short aliases of the source trees to lock (see `source list`).  Defaults to all source trees."
+        )]
+        aliases: Vec<String>,
+    },
+
     #[command(
         about = "Clone a git repository using its short alias and mount it into the containers that use it"
     )]
@@ -382,6 +576,14 @@ enum SourceCommands {
         alias: String,
     },
 
+    #[command(
+        about = "Check out other branches of a source tree using git worktrees"
+    )]
+    Worktree {
+        #[command(subcommand)]
+        command: WorktreeCommands,
+    },
+
     #[command(about = "Mount a source tree into the containers that use it")]
     Mount {
         #[arg(
@@ -415,6 +617,34 @@ enum SourceCommands {
         )]
         all: bool,
     },
+
+    #[command(
+        about = "Mount an existing checkout from outside src/ into the containers that use it"
+    )]
+    Link {
+        #[arg(
+            value_name = "ALIAS",
+            help = "The short alias of the source tree to link (see `source list`)"
+        )]
+        alias: String,
+
+        #[arg(
+            value_name = "DIR",
+            help = "A git checkout with a remote pointing at the source tree's git URL"
+        )]
+        dir: String,
+    },
+
+    #[command(
+        about = "Go back to mounting the checkout in src/ for a linked source tree"
+    )]
+    Unlink {
+        #[arg(
+            value_name = "ALIAS",
+            help = "The short alias of the source tree to unlink (see `source list`)"
+        )]
+        alias: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -459,9 +689,27 @@ enum Shell {
     Fish,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Compose,
+    K8s,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum StatusFormat {
+    Tree,
+    Json,
+    Yaml,
+}
+
 impl Cli {
     fn should_output_project(&self) -> bool {
-        !matches!(self.command, Commands::Export { .. })
+        !matches!(
+            self.command,
+            Commands::Export { .. }
+                | Commands::Secrets { .. }
+                | Commands::Vault { .. }
+        )
     }
 
     fn target_name(&self) -> &str {
@@ -571,6 +819,16 @@ fn to_logs_options(follow: bool, number: &Option<String>) -> cage::args::opts::L
     opts
 }
 
+fn to_down_options(
+    remove_volumes: bool,
+    remove_networks: bool,
+) -> cage::args::opts::Down {
+    let mut opts = cage::args::opts::Down::default();
+    opts.remove_volumes = remove_volumes;
+    opts.remove_networks = remove_networks;
+    opts
+}
+
 fn to_rm_options(force: bool, remove_volumes: bool) -> cage::args::opts::Rm {
     let mut opts = cage::args::opts::Rm::default();
     opts.force = force;
@@ -629,6 +887,8 @@ fn run(cli: &Cli) -> Result<()> {
         proj.set_default_tags(cage::DefaultTags::read(reader)?);
     }
     proj.set_current_target_name(cli.target_name())?;
+    proj.set_jobs(cli.jobs);
+    proj.set_dry_run(cli.dry_run);
 
     // Output our project's `*.yml` files for `docker-compose` if we'll need it.
     let subcommand_name = match &cli.command {
@@ -639,7 +899,9 @@ fn run(cli: &Cli) -> Result<()> {
         Commands::Pull { .. } => "pull",
         Commands::Up { .. } => "up",
         Commands::Restart { .. } => "restart",
+        Commands::Watch { .. } => "watch",
         Commands::Stop { .. } => "stop",
+        Commands::Down { .. } => "down",
         Commands::Rm { .. } => "rm",
         Commands::Run { .. } => "run",
         Commands::RunScript { .. } => "run-script",
@@ -647,6 +909,8 @@ fn run(cli: &Cli) -> Result<()> {
         Commands::Shell { .. } => "shell",
         Commands::Test { .. } => "test",
         Commands::Source { .. } => "source",
+        Commands::Secrets { .. } => "secrets",
+        Commands::Vault { .. } => "vault",
         Commands::Generate { .. } => "generate",
         Commands::Logs { .. } => "logs",
         Commands::Export { .. } => "export",
@@ -657,11 +921,37 @@ fn run(cli: &Cli) -> Result<()> {
     }
 
     // Handle our subcommands that require a `Project`.
-    let runner = OsCommandRunner::new();
+    if cli.dry_run {
+        run_project_command(&DryRunCommandRunner::new(), cli, &mut proj)
+    } else {
+        run_project_command(&OsCommandRunner::new(), cli, &mut proj)
+    }
+}
+
+/// Run a subcommand which requires a `Project`, using `runner` to run any
+/// external commands.
+fn run_project_command<CR>(runner: &CR, cli: &Cli, proj: &mut Project) -> Result<()>
+where
+    CR: CommandRunner + Sync,
+{
     match &cli.command {
-        Commands::Status { pod_or_service } => {
+        Commands::Status {
+            format,
+            pod_or_service,
+        } => {
             let acts_on = to_acts_on(pod_or_service, true);
-            proj.status(&runner, &acts_on)?;
+            match format {
+                StatusFormat::Tree => proj.status(runner, &acts_on)?,
+                StatusFormat::Json => {
+                    let status = proj.project_status(&acts_on)?;
+                    serde_json::to_writer_pretty(io::stdout(), &status)?;
+                    println!();
+                }
+                StatusFormat::Yaml => {
+                    let status = proj.project_status(&acts_on)?;
+                    serde_yaml::to_writer(io::stdout(), &status)?;
+                }
+            }
         }
         Commands::Pull {
             quiet,
@@ -670,12 +960,12 @@ fn run(cli: &Cli) -> Result<()> {
             let acts_on = to_acts_on(pod_or_service, true);
             let mut opts = cage::args::opts::Pull::default();
             opts.quiet = *quiet;
-            proj.pull(&runner, &acts_on, &opts)?;
+            proj.pull(runner, &acts_on, &opts)?;
         }
         Commands::Build { pod_or_service } => {
             let acts_on = to_acts_on(pod_or_service, true);
             let opts = cage::args::opts::Empty;
-            proj.compose(&runner, "build", &acts_on, &opts)?;
+            proj.compose(runner, "build", &acts_on, &opts)?;
         }
         Commands::Up {
             init,
@@ -683,17 +973,35 @@ fn run(cli: &Cli) -> Result<()> {
         } => {
             let acts_on = to_acts_on(pod_or_service, false);
             let opts = cage::args::opts::Up::new(*init);
-            proj.up(&runner, &acts_on, &opts)?;
+            proj.up_parallel(runner, &acts_on, &opts)?;
         }
         Commands::Restart { pod_or_service } => {
             let acts_on = to_acts_on(pod_or_service, false);
             let opts = cage::args::opts::Empty;
-            proj.compose(&runner, "restart", &acts_on, &opts)?;
+            proj.compose_parallel(runner, "restart", &acts_on, &opts)?;
+        }
+        Commands::Watch {
+            debounce,
+            poll_interval,
+            pod_or_service,
+        } => {
+            let acts_on = to_acts_on(pod_or_service, false);
+            let mut opts =
+                cage::args::opts::Watch::new(time::Duration::from_millis(*debounce));
+            opts.poll_interval = time::Duration::from_millis(*poll_interval);
+            proj.watch(runner, &acts_on, &opts)?;
         }
         Commands::Stop { pod_or_service } => {
             let acts_on = to_acts_on(pod_or_service, false);
             let opts = cage::args::opts::Empty;
-            proj.compose(&runner, "stop", &acts_on, &opts)?;
+            proj.compose_parallel(runner, "stop", &acts_on, &opts)?;
+        }
+        Commands::Down {
+            remove_volumes,
+            remove_networks,
+        } => {
+            let opts = to_down_options(*remove_volumes, *remove_networks);
+            proj.down(runner, &opts)?;
         }
         Commands::Rm {
             force,
@@ -702,7 +1010,7 @@ fn run(cli: &Cli) -> Result<()> {
         } => {
             let acts_on = to_acts_on(pod_or_service, true);
             let opts = to_rm_options(*force, *remove_volumes);
-            proj.compose(&runner, "rm", &acts_on, &opts)?;
+            proj.compose_parallel(runner, "rm", &acts_on, &opts)?;
         }
         Commands::Run {
             detached,
@@ -713,7 +1021,7 @@ fn run(cli: &Cli) -> Result<()> {
             service,
             command,
         } => {
-            warn_if_pods_are_enabled_but_not_running(&proj)?;
+            warn_if_pods_are_enabled_but_not_running(proj)?;
             let opts = to_run_options(
                 *detached,
                 user,
@@ -723,17 +1031,17 @@ fn run(cli: &Cli) -> Result<()> {
                 false,
             );
             let cmd = to_exec_command(command);
-            proj.run(&runner, service, cmd.as_ref(), &opts)?;
+            proj.run(runner, service, cmd.as_ref(), &opts)?;
         }
         Commands::RunScript {
             no_deps,
             script_name,
             pod_or_service,
         } => {
-            warn_if_pods_are_enabled_but_not_running(&proj)?;
+            warn_if_pods_are_enabled_but_not_running(proj)?;
             let opts = to_run_options(false, &None, false, &None, &[], *no_deps);
             let acts_on = to_acts_on(pod_or_service, true);
-            proj.run_script(&runner, &acts_on, script_name.as_ref(), &opts)?;
+            proj.run_script(runner, &acts_on, script_name.as_ref(), &opts)?;
         }
         Commands::Exec {
             detached,
@@ -743,10 +1051,10 @@ fn run(cli: &Cli) -> Result<()> {
             service,
             command,
         } => {
-            warn_if_pods_are_enabled_but_not_running(&proj)?;
+            warn_if_pods_are_enabled_but_not_running(proj)?;
             let opts = to_exec_options(*detached, user, *no_allocate_tty, *privileged);
             let cmd = to_exec_command(command).unwrap();
-            proj.exec(&runner, service, &cmd, &opts)?;
+            proj.exec(runner, service, &cmd, &opts)?;
         }
         Commands::Shell {
             detached,
@@ -755,22 +1063,29 @@ fn run(cli: &Cli) -> Result<()> {
             privileged,
             service,
         } => {
-            warn_if_pods_are_enabled_but_not_running(&proj)?;
+            warn_if_pods_are_enabled_but_not_running(proj)?;
             let opts = to_exec_options(*detached, user, *no_allocate_tty, *privileged);
-            proj.shell(&runner, service, &opts)?;
+            proj.shell(runner, service, &opts)?;
         }
         Commands::Test {
             export_test_output,
             service,
             command,
         } => {
-            warn_if_pods_are_enabled_but_not_running(&proj)?;
+            warn_if_pods_are_enabled_but_not_running(proj)?;
             let opts = to_test_options(*export_test_output);
             let cmd = to_exec_command(command);
-            proj.test(&runner, service, cmd.as_ref(), &opts)?;
+            proj.test(runner, service, cmd.as_ref(), &opts)?;
         }
-        Commands::Source { command } => run_source(&runner, &mut proj, command)?,
-        Commands::Generate { command } => run_generate(&runner, &proj, command)?,
+        Commands::Source { command } => run_source(runner, proj, command)?,
+        Commands::Secrets { command } => match command {
+            SecretsCommands::Edit => proj.secrets_edit(runner)?,
+        },
+        Commands::Vault { command } => match command {
+            VaultCommands::Tokens => proj.vault_tokens()?,
+            VaultCommands::Revoke => proj.vault_revoke()?,
+        },
+        Commands::Generate { command } => run_generate(runner, proj, command)?,
         Commands::Logs {
             follow,
             number,
@@ -778,10 +1093,19 @@ fn run(cli: &Cli) -> Result<()> {
         } => {
             let acts_on = to_acts_on(pod_or_service, true);
             let opts = to_logs_options(*follow, number);
-            proj.logs(&runner, &acts_on, &opts)?;
+            proj.logs(runner, &acts_on, &opts)?;
         }
-        Commands::Export { dir } => {
-            proj.export(Path::new(dir))?;
+        Commands::Export {
+            format,
+            include_secrets,
+            dir,
+        } => {
+            let format = match format {
+                ExportFormat::Compose => cage::ExportFormat::Compose,
+                ExportFormat::K8s => cage::ExportFormat::Kubernetes,
+            };
+            proj.set_export_secrets(*include_secrets);
+            proj.export_as(Path::new(dir), format)?;
         }
         _ => unreachable!(),
     }
@@ -796,10 +1120,16 @@ fn run_source<R>(
     command: &SourceCommands,
 ) -> Result<()>
 where
-    R: CommandRunner,
+    R: CommandRunner + Sync,
 {
     let subcommand_name = match command {
         SourceCommands::Ls => "ls",
+        SourceCommands::Status { .. } => "status",
+        SourceCommands::Pull { .. } => "pull",
+        SourceCommands::Lock { .. } => "lock",
+        SourceCommands::Link { .. } => "link",
+        SourceCommands::Unlink { .. } => "unlink",
+        SourceCommands::Worktree { .. } => "worktree",
         SourceCommands::Clone { .. } => "clone",
         SourceCommands::Mount { .. } => "mount",
         SourceCommands::Unmount { .. } => "unmount",
@@ -812,6 +1142,42 @@ where
             re_output = false;
             proj.source_list(runner)?;
         }
+        SourceCommands::Status { aliases } => {
+            re_output = false;
+            let act_on_sources =
+                to_acts_on_sources(aliases, aliases.is_empty(), proj)?;
+            proj.source_status(runner, act_on_sources)?;
+        }
+        SourceCommands::Pull { aliases } => {
+            re_output = false;
+            let act_on_sources =
+                to_acts_on_sources(aliases, aliases.is_empty(), proj)?;
+            proj.source_pull(runner, act_on_sources)?;
+        }
+        SourceCommands::Lock { aliases } => {
+            re_output = false;
+            let act_on_sources =
+                to_acts_on_sources(aliases, aliases.is_empty(), proj)?;
+            proj.source_lock(runner, act_on_sources)?;
+        }
+        SourceCommands::Link { alias, dir } => {
+            proj.source_link(runner, alias, Path::new(dir))?;
+        }
+        SourceCommands::Unlink { alias } => {
+            proj.source_unlink(alias)?;
+        }
+        SourceCommands::Worktree { command } => match command {
+            WorktreeCommands::Add { alias, branch } => {
+                re_output = false;
+                proj.source_worktree_add(runner, alias, branch)?;
+            }
+            WorktreeCommands::Use { alias, name } => {
+                proj.source_worktree_use(alias, name.as_deref())?;
+            }
+            WorktreeCommands::Rm { alias, name } => {
+                proj.source_worktree_remove(runner, alias, name)?;
+            }
+        },
         SourceCommands::Clone { alias } => {
             proj.source_clone(runner, alias)?;
         }
@@ -874,9 +1240,13 @@ fn all_versions() -> Result<()> {
     version();
 
     let runner = OsCommandRunner::new();
-    for tool in &["docker", "docker-compose", "git"] {
+    for tool in &["docker", "git"] {
         runner.build(tool).arg("--version").exec()?;
     }
+    cage::ComposeBackend::detect(None)?
+        .build(&runner)
+        .arg("version")
+        .exec()?;
     Ok(())
 }
 
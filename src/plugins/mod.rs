@@ -5,6 +5,7 @@ use std::fmt;
 use std::io;
 use std::marker::PhantomData;
 
+use crate::command_runner::{CaptureOutput, OsCommandRunner};
 use crate::errors::*;
 use crate::pod::Pod;
 use crate::project::Project;
@@ -12,8 +13,12 @@ use crate::template::Template;
 
 pub mod transform;
 
+/// The runner used by `Context::new`.
+static OS_COMMAND_RUNNER: OsCommandRunner = OsCommandRunner {
+    _nonexhaustive: PhantomData,
+};
+
 /// The context in which a plugin is being applied.
-#[derive(Debug)]
 pub struct Context<'a> {
     /// The project to which we're applying this plugin.
     pub project: &'a Project,
@@ -21,25 +26,52 @@ pub struct Context<'a> {
     pub pod: &'a Pod,
     /// The subcommand to which we're applying this plugin.
     pub subcommand: String,
+    /// Plugins which need to run commands should use this, so that they
+    /// respect `--dry-run`.
+    pub(crate) runner: &'a dyn CaptureOutput,
     /// PRIVATE. Allow future extensibility without breaking the API.
     _nonexclusive: PhantomData<()>,
 }
 
 impl<'a> Context<'a> {
-    /// Create a new plugin context.
+    /// Create a new plugin context.  Plugins will run any commands they
+    /// need using an `OsCommandRunner`.
     pub fn new(project: &'a Project, pod: &'a Pod, subcommand: &str) -> Context<'a> {
+        Context::with_runner(project, pod, subcommand, &OS_COMMAND_RUNNER)
+    }
+
+    /// Create a new plugin context, which uses `runner` to run any commands
+    /// that plugins need.
+    pub fn with_runner(
+        project: &'a Project,
+        pod: &'a Pod,
+        subcommand: &str,
+        runner: &'a dyn CaptureOutput,
+    ) -> Context<'a> {
         Context {
             project,
             pod,
             subcommand: subcommand.to_string(),
+            runner,
             _nonexclusive: PhantomData,
         }
     }
 }
 
+impl fmt::Debug for Context<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("project", &self.project)
+            .field("pod", &self.pod)
+            .field("subcommand", &self.subcommand)
+            .finish_non_exhaustive()
+    }
+}
+
 /// What kind of transform operation are we performing?  (Adding new kinds
 /// of operations will be a breaking API change for plugins.)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Operation {
     /// We're outputting a file for our internal usage.
     Output,
@@ -137,6 +169,12 @@ impl Manager {
         manager.register_transform::<transform::remove_build::Plugin>(proj)?;
         manager.register_transform::<transform::vault::Plugin>(proj)?;
 
+        // Run any plugins supplied by the project or installed in our `PATH`
+        // after our own, so that they see the final file.
+        for plugin in transform::external::Plugin::discover(proj)? {
+            manager.transforms.push(Box::new(plugin));
+        }
+
         // Run this last, in case it wants to remove any labels used by
         // other plugins.
         manager.register_transform::<transform::labels::Plugin>(proj)?;
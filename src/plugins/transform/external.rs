@@ -0,0 +1,349 @@
+//! Plugins which run external programs to transform a `dc::File`.
+//!
+//! Each plugin is an executable which reads a JSON request from standard
+//! input, and prints the transformed file as JSON on standard output.  The
+//! request looks like:
+//!
+//! ```json
+//! {
+//!   "operation": "output",
+//!   "subcommand": "up",
+//!   "target": "development",
+//!   "pod": "frontend",
+//!   "file": { "version": "2", "services": { ... } }
+//! }
+//! ```
+//!
+//! `operation` is `"output"` when we're generating files for our own use,
+//! and `"export"` when we're running `cage export`.  If the plugin exits
+//! with a non-zero status, the transform fails.
+
+use faraday_compose_yml::v2 as dc;
+use std::{
+    collections::BTreeSet,
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::errors::*;
+use crate::plugins::{self, Operation, PluginTransform};
+use crate::project::Project;
+use crate::serde_helpers::load_yaml;
+use crate::util::{ConductorPathExt, ToStrOrErr};
+
+/// The prefix of plugins which are installed in our `PATH`.
+const PATH_PREFIX: &str = "cage-plugin-";
+
+/// The contents of `config/plugins.yml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The plugins to run, in order.
+    #[serde(default)]
+    plugins: Vec<PluginConfig>,
+}
+
+/// The configuration for a single external plugin.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginConfig {
+    /// The name of the plugin.  We look for `config/plugins/$NAME`, and
+    /// then for `cage-plugin-$NAME` in our `PATH`.
+    name: String,
+
+    /// Only apply this plugin in the specified targets.  If this field is
+    /// omitted, we apply the plugin in all targets.
+    enable_in_targets: Option<Vec<String>>,
+}
+
+/// The message we send to an external plugin.
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    /// The kind of transform we're performing.
+    operation: Operation,
+    /// The subcommand we're running.
+    subcommand: &'a str,
+    /// The current target.
+    target: &'a str,
+    /// The name of the pod we're transforming.
+    pod: &'a str,
+    /// The file to transform.
+    file: &'a dc::File,
+}
+
+/// Runs an external program to transform each pod's `dc::File`.
+#[derive(Debug)]
+pub struct Plugin {
+    /// The name of this plugin.  `plugins::Plugin::name` needs a `'static`
+    /// string, and we only discover each plugin once per project, so we
+    /// leak it.
+    name: &'static str,
+    /// The executable to run.
+    path: PathBuf,
+    /// Only apply this plugin in the specified targets.
+    enable_in_targets: Option<Vec<String>>,
+}
+
+impl Plugin {
+    /// Get the path to the file listing our plugins.
+    fn config_path(project: &Project) -> PathBuf {
+        project.root_dir().join("config").join("plugins.yml")
+    }
+
+    /// Get the directory containing plugins supplied by the project.
+    fn plugins_dir(project: &Project) -> PathBuf {
+        project.root_dir().join("config").join("plugins")
+    }
+
+    /// Find the external plugins used by `project`, in the order in which
+    /// they should run.  If there's a `config/plugins.yml` file, we use the
+    /// plugins it lists.  Otherwise, we use every executable in
+    /// `config/plugins/`, sorted by name, followed by every `cage-plugin-*`
+    /// executable in our `PATH` which the project doesn't override.
+    pub(crate) fn discover(project: &Project) -> Result<Vec<Plugin>> {
+        Self::discover_in_path(project, env::var_os("PATH"))
+    }
+
+    /// Like `discover`, but search `search_path` (which has the same format
+    /// as `$PATH`) instead of our `PATH`.
+    fn discover_in_path(
+        project: &Project,
+        search_path: Option<OsString>,
+    ) -> Result<Vec<Plugin>> {
+        let config_path = Self::config_path(project);
+        let plugins_dir = Self::plugins_dir(project);
+        let config: Config = if config_path.exists() {
+            load_yaml(&config_path)?
+        } else {
+            let mut names = if plugins_dir.exists() {
+                executables_in(&plugins_dir, "")?
+            } else {
+                vec![]
+            };
+            let mut installed = BTreeSet::new();
+            for dir in search_path.iter().flat_map(env::split_paths) {
+                // `PATH` often contains directories which don't exist.
+                if let Ok(found) = executables_in(&dir, PATH_PREFIX) {
+                    installed.extend(found);
+                }
+            }
+            installed.retain(|name| !names.contains(name));
+            names.extend(installed);
+            Config {
+                plugins: names
+                    .into_iter()
+                    .map(|name| PluginConfig {
+                        name,
+                        enable_in_targets: None,
+                    })
+                    .collect(),
+            }
+        };
+
+        config
+            .plugins
+            .into_iter()
+            .map(|plugin| -> Result<Plugin> {
+                // We run plugins from the project's root directory, so we need
+                // an absolute path.
+                let path = plugins_dir.join(&plugin.name);
+                let path = if is_executable(&path) {
+                    path.to_absolute()
+                } else {
+                    let file_name = format!("{}{}", PATH_PREFIX, plugin.name);
+                    find_in_path(&file_name, search_path.as_deref()).ok_or_else(|| {
+                        err!(
+                            "could not find {} or {} in PATH",
+                            path.display(),
+                            file_name
+                        )
+                    })
+                }
+                .map_err(|e| e.context(Error::PluginFailed(plugin.name.clone())))?;
+                Ok(Plugin {
+                    name: Box::leak(plugin.name.into_boxed_str()),
+                    path,
+                    enable_in_targets: plugin.enable_in_targets,
+                })
+            })
+            .collect()
+    }
+}
+
+impl plugins::Plugin for Plugin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl PluginTransform for Plugin {
+    fn transform(
+        &self,
+        op: Operation,
+        ctx: &plugins::Context<'_>,
+        file: &mut dc::File,
+    ) -> Result<()> {
+        // Should this plugin be excluded in this target?
+        let target = ctx.project.current_target();
+        if !target.is_enabled_by(&self.enable_in_targets) {
+            return Ok(());
+        }
+
+        let request = Request {
+            operation: op,
+            subcommand: &ctx.subcommand,
+            target: target.name(),
+            pod: ctx.pod.name(),
+            file,
+        };
+        let input = serde_json::to_vec(&request)?;
+
+        // During a dry run, we only print the plugin's command line, and
+        // leave the file alone.
+        let output = ctx.runner.capture_output_with_input(
+            self.path.to_str_or_err()?,
+            &[],
+            ctx.project.root_dir(),
+            &input,
+        )?;
+        let output = match output {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        *file = serde_json::from_slice(&output).map_err(|e| {
+            anyhow::Error::new(e)
+                .context(format!("error parsing output of {}", self.path.display()))
+        })?;
+        Ok(())
+    }
+}
+
+/// Is `path` a file we can run?
+fn is_executable(path: &Path) -> bool {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return false,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        true
+    }
+}
+
+/// List the executables in `dir` whose names start with `prefix`, sorted
+/// and with `prefix` removed.
+fn executables_in(dir: &Path, prefix: &str) -> Result<Vec<String>> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotReadFile(dir.to_owned()))
+    })?;
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if !is_executable(&path) {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(prefix));
+        if let Some(name) = name.filter(|n| !n.is_empty()) {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Find the executable named `name` in `search_path`, which has the same
+/// format as `$PATH`.
+fn find_in_path(name: &str, search_path: Option<&OsStr>) -> Option<PathBuf> {
+    env::split_paths(search_path?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+#[test]
+fn runs_external_plugins_in_order_in_enabled_targets() {
+    use crate::command_runner::DryRunCommandRunner;
+    use crate::plugins::Context;
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_fixture("with_external_plugins").unwrap();
+    let plugins = Plugin::discover(&proj).unwrap();
+    let names: Vec<&str> = plugins.iter().map(|p| p.name).collect();
+    assert_eq!(names, &["retag", "fail"]);
+
+    // In development, only `retag` runs.
+    proj.set_current_target_name("development").unwrap();
+    let frontend = proj.pod("frontend").unwrap();
+    let ctx = Context::new(&proj, frontend, "up");
+    let mut file = frontend.merged_file(proj.current_target()).unwrap();
+    proj.plugins()
+        .transform(Operation::Output, &ctx, &mut file)
+        .unwrap();
+    let web = file.services.get("web").unwrap();
+    assert_eq!(
+        web.image.as_ref().unwrap().value().unwrap(),
+        &dc::Image::new("faraday/rails_hello:development").unwrap()
+    );
+
+    // In production, `fail` runs too, and its error names it.
+    proj.set_current_target_name("production").unwrap();
+    let frontend = proj.pod("frontend").unwrap();
+    let ctx = Context::new(&proj, frontend, "up");
+    let mut file = frontend.merged_file(proj.current_target()).unwrap();
+    let err = proj
+        .plugins()
+        .transform(Operation::Output, &ctx, &mut file)
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("plugin 'fail' failed"));
+
+    // During a dry run, we only print which plugins we'd run.
+    let runner = DryRunCommandRunner::new();
+    let ctx = Context::with_runner(&proj, frontend, "up", &runner);
+    let mut file = frontend.merged_file(proj.current_target()).unwrap();
+    let original = serde_yaml::to_string(&file).unwrap();
+    proj.plugins()
+        .transform(Operation::Output, &ctx, &mut file)
+        .unwrap();
+    assert_eq!(serde_yaml::to_string(&file).unwrap(), original);
+    let cmds = runner.cmds();
+    assert_eq!(cmds.len(), 2);
+    assert!(cmds[0].ends_with("config/plugins/retag"));
+    assert!(cmds[1].ends_with("config/plugins/fail"));
+
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn discovers_plugins_installed_in_path() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_example("hello").unwrap();
+    let bin = proj.output_dir().join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    for name in &["cage-plugin-zap", "cage-plugin-annotate", "unrelated"] {
+        let path = bin.join(name);
+        fs::write(&path, "#!/bin/sh\ncat\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    // Directories in `PATH` which don't exist are ignored.
+    let search_path = env::join_paths([bin.clone(), bin.join("missing")]).unwrap();
+    let plugins = Plugin::discover_in_path(&proj, Some(search_path)).unwrap();
+    let names: Vec<&str> = plugins.iter().map(|p| p.name).collect();
+    assert_eq!(names, &["annotate", "zap"]);
+    assert_eq!(plugins[0].path, bin.join("cage-plugin-annotate"));
+
+    proj.remove_test_output().unwrap();
+}
@@ -2,6 +2,7 @@
 
 pub mod abs_path;
 pub mod default_tags;
+pub mod external;
 pub mod host_dns;
 pub mod labels;
 pub mod remove_build;
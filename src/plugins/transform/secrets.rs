@@ -1,36 +1,175 @@
 //! Plugin which loads secrets from `config/secrets.yml` and adds them to a
 //! project.
+//!
+//! Secrets may also be stored in `config/secrets.yml.age`, which is
+//! encrypted using [age](https://age-encryption.org/) and which can safely
+//! be checked into version control.  We decrypt it using the key in
+//! `$CAGE_SECRETS_KEY`, or in the key file named by `$CAGE_SECRETS_KEY_FILE`
+//! (which defaults to `cage/secrets.key` in the user's config directory).
 
+use age::x25519;
 use faraday_compose_yml::v2 as dc;
 use std::collections::BTreeMap;
-#[cfg(test)]
-use std::path::Path;
-use std::path::PathBuf;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::result;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
 
+use crate::command_runner::CaptureOutput;
+#[cfg(test)]
+use crate::command_runner::{DryRunCommandRunner, TestCommandRunner};
 use crate::errors::*;
 use crate::plugins;
 use crate::plugins::{Operation, PluginGenerate, PluginNew, PluginTransform};
 use crate::project::Project;
 use crate::serde_helpers::load_yaml;
+use crate::util::err;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+/// A function which looks up an environment variable.  Tests replace this
+/// so that they don't need to change our real environment.
+type EnvVarLookup = fn(&str) -> Option<String>;
+
+/// Look up an environment variable in our real environment.
+fn real_env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+/// A secret value in `config/secrets.yml`.  Most secrets are literal
+/// strings, but they may also be tagged to look up their value elsewhere:
+///
+/// ```yaml
+/// common:
+///   API_KEY: !env API_KEY
+///   TLS_KEY: !file config/tls.key
+///   DATABASE_PASSWORD: !cmd "pass show myapp/database"
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SecretValue {
+    /// A literal value.
+    Literal(String),
+    /// The value of an environment variable.
+    Env(String),
+    /// The contents of a file, relative to the project root.
+    File(PathBuf),
+    /// The output of a command.  This is split into arguments like a shell
+    /// would, but it isn't run by a shell, so use `sh -c` for pipes.
+    Cmd(String),
+}
+
+impl SecretValue {
+    /// Look up the actual value of this secret, using `runner` to run any
+    /// commands, and `env_var` to look up environment variables.
+    fn resolve(
+        &self,
+        runner: &dyn CaptureOutput,
+        env_var: EnvVarLookup,
+        project: &Project,
+    ) -> Result<String> {
+        match *self {
+            SecretValue::Literal(ref value) => Ok(value.to_owned()),
+            SecretValue::Env(ref name) => env_var(name)
+                .ok_or_else(|| err!("environment variable {} is not set", name)),
+            SecretValue::File(ref path) => {
+                let path = project.root_dir().join(path);
+                let value = fs::read_to_string(&path).map_err(|e| {
+                    anyhow::Error::new(e)
+                        .context(Error::CouldNotReadFile(path.clone()))
+                })?;
+                Ok(trim_newline(&value).to_owned())
+            }
+            SecretValue::Cmd(ref command) => {
+                let args = shlex::split(command)
+                    .filter(|args| !args.is_empty())
+                    .ok_or_else(|| Error::parse("command", command.to_owned()))?;
+                // During a dry run, we only print the command, so we leave
+                // the secret empty.
+                let output = runner
+                    .capture_output(&args[0], &args[1..], project.root_dir())?
+                    .unwrap_or_default();
+                Ok(trim_newline(&output).to_owned())
+            }
+        }
+    }
+}
+
+/// Remove a single trailing newline, the way a shell's `$(...)` would.
+fn trim_newline(value: &str) -> &str {
+    let value = value.strip_suffix('\n').unwrap_or(value);
+    value.strip_suffix('\r').unwrap_or(value)
+}
+
+impl<'de> Deserialize<'de> for SecretValue {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error as DeError;
+
+        match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Tagged(tagged) => {
+                let serde_yaml::value::TaggedValue { tag, value } = *tagged;
+                let arg: String =
+                    serde_yaml::from_value(value).map_err(D::Error::custom)?;
+                if tag == "env" {
+                    Ok(SecretValue::Env(arg))
+                } else if tag == "file" {
+                    Ok(SecretValue::File(PathBuf::from(arg)))
+                } else if tag == "cmd" {
+                    Ok(SecretValue::Cmd(arg))
+                } else {
+                    Err(D::Error::custom(format!(
+                        "unknown secret tag {} (expected !env, !file or !cmd)",
+                        tag
+                    )))
+                }
+            }
+            value => serde_yaml::from_value(value)
+                .map(SecretValue::Literal)
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+impl Serialize for SecretValue {
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (tag, value) = match *self {
+            SecretValue::Literal(ref value) => return value.serialize(serializer),
+            SecretValue::Env(ref name) => ("env", name.to_owned()),
+            SecretValue::File(ref path) => ("file", path.display().to_string()),
+            SecretValue::Cmd(ref command) => ("cmd", command.to_owned()),
+        };
+        serde_yaml::value::TaggedValue {
+            tag: serde_yaml::value::Tag::new(tag),
+            value: serde_yaml::Value::String(value),
+        }
+        .serialize(serializer)
+    }
+}
+
 /// The secrets for a single service.  We implement this as a very thin
 /// wrapper around `BTreeMap` so that we can add methods.
 #[derive(Default, Debug, PartialEq, Eq)]
 struct ServiceSecrets {
-    secrets: BTreeMap<String, String>,
+    secrets: BTreeMap<String, SecretValue>,
 }
 
-impl ServiceSecrets {
-    fn to_compose_env(&self) -> BTreeMap<String, dc::RawOr<String>> {
-        let mut env = BTreeMap::new();
-        for (var, val) in &self.secrets {
-            let val = dc::escape(val).expect("escape string should never fail");
-            env.insert(var.to_owned(), val);
-        }
-        env
+/// Convert resolved secrets into environment variables.
+fn to_compose_env(
+    secrets: &BTreeMap<String, String>,
+) -> BTreeMap<String, dc::RawOr<String>> {
+    let mut env = BTreeMap::new();
+    for (var, val) in secrets {
+        let val = dc::escape(val).expect("escape string should never fail");
+        env.insert(var.to_owned(), val);
     }
+    env
 }
 
 impl<'de> Deserialize<'de> for ServiceSecrets {
@@ -88,17 +227,187 @@ fn can_deserialize_config() {
     let config: Config = load_yaml(path).unwrap();
     assert_eq!(
         config.common.secrets.get("GLOBAL_PASSWORD").unwrap(),
-        "magic"
+        &SecretValue::Literal("magic".to_owned())
     );
 }
 
+/// The environment variable which may contain a key for decrypting
+/// `config/secrets.yml.age`.
+const KEY_VAR: &str = "CAGE_SECRETS_KEY";
+
+/// The environment variable which may contain the path to a key file.
+const KEY_FILE_VAR: &str = "CAGE_SECRETS_KEY_FILE";
+
+/// Keys for decrypting secrets.  We wrap these so that our `Debug` output
+/// never includes them.
+pub(crate) struct Keys(Vec<x25519::Identity>);
+
+impl Keys {
+    /// Load our keys from `$CAGE_SECRETS_KEY`, or from a key file in the
+    /// format written by `age-keygen`.  Returns no keys if we can't find
+    /// any.
+    pub(crate) fn load() -> Result<Keys> {
+        if let Ok(text) = env::var(KEY_VAR) {
+            return Keys::parse(&text)
+                .map_err(|_| err!("{} is not a valid key", KEY_VAR));
+        }
+        let path = Keys::path()?;
+        if !path.exists() {
+            return Ok(Keys(vec![]));
+        }
+        let text = fs::read_to_string(&path).map_err(|e| {
+            anyhow::Error::new(e).context(Error::CouldNotReadFile(path.clone()))
+        })?;
+        Keys::parse(&text)
+            .map_err(|_| err!("{} does not contain valid keys", path.display()))
+    }
+
+    /// The key file we use if `$CAGE_SECRETS_KEY` isn't set.
+    pub(crate) fn path() -> Result<PathBuf> {
+        match env::var_os(KEY_FILE_VAR) {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Ok(dirs::config_dir()
+                .ok_or_else(|| err("You do not appear to have a config directory"))?
+                .join("cage")
+                .join("secrets.key")),
+        }
+    }
+
+    /// Parse one key per line, skipping blank lines and `#` comments.  We
+    /// don't include the offending line in our error, because it may be a
+    /// key.
+    pub(crate) fn parse(text: &str) -> Result<Keys> {
+        let keys = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                x25519::Identity::from_str(line).map_err(|_| err("invalid key"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Keys(keys))
+    }
+
+    /// Generate a new key, and save it to `path` so only the current user
+    /// can read it.
+    pub(crate) fn generate(path: &Path) -> Result<Keys> {
+        use age::secrecy::ExposeSecret;
+
+        let key = x25519::Identity::generate();
+        let text = format!(
+            "# public key: {}\n{}\n",
+            key.to_public(),
+            key.to_string().expose_secret()
+        );
+        let parent = path.parent().expect("key path should have a parent");
+        fs::create_dir_all(parent).map_err(|e| {
+            anyhow::Error::new(e).context(Error::CouldNotWriteFile(path.to_owned()))
+        })?;
+        write_private_file(path, text.as_bytes())?;
+        Ok(Keys(vec![key]))
+    }
+
+    /// Decrypt the file at `path` using whichever of our keys works.
+    /// Returns the plaintext and the key which decrypted it.
+    pub(crate) fn decrypt(&self, path: &Path) -> Result<(String, &x25519::Identity)> {
+        if self.0.is_empty() {
+            return Err(err!(
+                "no key for decrypting {} (set {} or {}, or put a key in {})",
+                path.display(),
+                KEY_VAR,
+                KEY_FILE_VAR,
+                Keys::path()?.display()
+            ));
+        }
+        let ciphertext = fs::read(path).map_err(|e| {
+            anyhow::Error::new(e).context(Error::CouldNotReadFile(path.to_owned()))
+        })?;
+        for key in &self.0 {
+            if let Ok(plaintext) = age::decrypt(key, &ciphertext) {
+                let plaintext = String::from_utf8(plaintext)
+                    .map_err(|_| Error::CouldNotDecrypt(path.to_owned()))?;
+                return Ok((plaintext, key));
+            }
+        }
+        Err(Error::CouldNotDecrypt(path.to_owned()).into())
+    }
+
+    /// The key we use to encrypt new files.
+    pub(crate) fn first(&self) -> Option<&x25519::Identity> {
+        self.0.first()
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Keys(<{} redacted>)", self.0.len())
+    }
+}
+
+/// Encrypt `plaintext` so that it can be decrypted using `key`, and write
+/// it to `path` in ASCII-armored format so it diffs reasonably.
+pub(crate) fn encrypt_to_file(
+    path: &Path,
+    plaintext: &str,
+    key: &x25519::Identity,
+) -> Result<()> {
+    let ciphertext = age::encrypt_and_armor(&key.to_public(), plaintext.as_bytes())
+        .map_err(|e| err!("could not encrypt {}: {}", path.display(), e))?;
+    fs::write(path, ciphertext).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotWriteFile(path.to_owned()))
+    })
+}
+
+/// Create a new file at `path` which only the current user can read.
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(path).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotWriteFile(path.to_owned()))
+    })?;
+    f.write_all(contents).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotWriteFile(path.to_owned()))
+    })
+}
+
+/// Make sure that `yaml` is a valid secrets config file.
+pub(crate) fn check_config(path: &Path, yaml: &str) -> Result<()> {
+    parse_config(path, yaml).map(|_| ())
+}
+
+/// Parse a secrets config file which we've already read into memory.
+fn parse_config(path: &Path, yaml: &str) -> Result<Config> {
+    serde_yaml::from_str(yaml).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotReadFile(path.to_owned()))
+    })
+}
+
 /// Loads a `config/secrets.yml` file and merges in into a project.
 #[derive(Debug)]
 pub struct Plugin {
     /// Our `config/secrets.yml` YAML file, parsed and read into memory.
-    /// Optional because if we're being run as a `PluginGenerate`, we won't
-    /// have it (but it's guaranteed otherwise).
+    /// Optional because if we're being run as a `PluginGenerate`, or if
+    /// we only have encrypted secrets, we won't have it.
     config: Option<Config>,
+    /// The path to `config/secrets.yml.age`, which may not exist.
+    encrypted_path: PathBuf,
+    /// Our decrypted secrets.  We only decrypt these when we first need
+    /// them, so that commands which don't use them don't need a key.
+    encrypted_config: OnceLock<Config>,
+    /// Keys to use instead of looking them up in the environment.
+    keys: Option<Keys>,
+    /// Secrets which we've already looked up using `!env`, `!file` or
+    /// `!cmd`, so that we only run each command once per invocation.
+    resolved: Mutex<BTreeMap<SecretValue, String>>,
+    /// How we look up `!env` secrets.
+    env_var: EnvVarLookup,
 }
 
 impl Plugin {
@@ -106,6 +415,33 @@ impl Plugin {
     fn config_path(project: &Project) -> PathBuf {
         project.root_dir().join("config").join("secrets.yml")
     }
+
+    /// Get the path to our encrypted config file.
+    pub(crate) fn encrypted_config_path(project: &Project) -> PathBuf {
+        project.root_dir().join("config").join("secrets.yml.age")
+    }
+
+    /// Decrypt `config/secrets.yml.age`, if it exists.
+    fn encrypted_config(&self) -> Result<Option<&Config>> {
+        if let Some(config) = self.encrypted_config.get() {
+            return Ok(Some(config));
+        }
+        if !self.encrypted_path.exists() {
+            return Ok(None);
+        }
+        let loaded;
+        let keys = match self.keys {
+            Some(ref keys) => keys,
+            None => {
+                loaded = Keys::load()?;
+                &loaded
+            }
+        };
+        let (yaml, _) = keys.decrypt(&self.encrypted_path)?;
+        let config = parse_config(&self.encrypted_path, &yaml)?;
+        // If another thread got here first, we'll just use its copy.
+        Ok(Some(self.encrypted_config.get_or_init(|| config)))
+    }
 }
 
 impl plugins::Plugin for Plugin {
@@ -120,8 +456,8 @@ impl PluginNew for Plugin {
     }
 
     fn is_configured_for(project: &Project) -> Result<bool> {
-        let path = Self::config_path(project);
-        Ok(path.exists())
+        Ok(Self::config_path(project).exists()
+            || Self::encrypted_config_path(project).exists())
     }
 
     fn new(project: &Project) -> Result<Self> {
@@ -131,7 +467,14 @@ impl PluginNew for Plugin {
         } else {
             None
         };
-        Ok(Plugin { config })
+        Ok(Plugin {
+            config,
+            encrypted_path: Self::encrypted_config_path(project),
+            encrypted_config: OnceLock::new(),
+            keys: None,
+            resolved: Mutex::new(BTreeMap::new()),
+            env_var: real_env_var,
+        })
     }
 }
 
@@ -141,39 +484,126 @@ impl PluginGenerate for Plugin {
     }
 }
 
-impl PluginTransform for Plugin {
-    fn transform(
+impl Plugin {
+    /// Get the secrets which apply to the specified service in the
+    /// project's current target, and look up any which refer to the
+    /// environment, files or commands.  We use `runner` to run commands.
+    pub(crate) fn service_secrets(
         &self,
-        _op: Operation,
-        ctx: &plugins::Context<'_>,
-        file: &mut dc::File,
-    ) -> Result<()> {
-        let config = self
-            .config
-            .as_ref()
-            .expect("config should always be present for transform");
-
-        let append_service =
-            |service: &mut dc::Service, pods: &BTreeMap<_, PodSecrets>, name| {
-                let opt_env = pods.get(ctx.pod.name()).and_then(|p| p.get(name));
+        runner: &dyn CaptureOutput,
+        op: Operation,
+        project: &Project,
+        pod_name: &str,
+        service_name: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        let secrets = self.raw_service_secrets(op, project, pod_name, service_name)?;
+        self.resolve_secrets(runner, project, pod_name, service_name, &secrets)
+    }
+
+    /// Get the unresolved secrets which apply to the specified service in
+    /// the project's current target.  Later sources of secrets override
+    /// earlier ones, and encrypted secrets override plaintext ones.  We only
+    /// export encrypted secrets, or secrets which we'd need to look up using
+    /// `!env`, `!file` or `!cmd`, if the project asks us to.
+    fn raw_service_secrets(
+        &self,
+        op: Operation,
+        project: &Project,
+        pod_name: &str,
+        service_name: &str,
+    ) -> Result<BTreeMap<String, SecretValue>> {
+        let omit_secrets = op == Operation::Export && !project.export_secrets();
+        let encrypted_config = if omit_secrets {
+            None
+        } else {
+            self.encrypted_config()?
+        };
+
+        let mut secrets = BTreeMap::new();
+        let mut append_service =
+            |common: &ServiceSecrets, pods: &BTreeMap<_, PodSecrets>| {
+                secrets.extend(common.secrets.clone());
+                let opt_env = pods.get(pod_name).and_then(|p| p.get(service_name));
                 if let Some(env) = opt_env {
-                    service.environment.append(&mut env.to_compose_env());
+                    secrets.extend(env.secrets.clone());
                 }
             };
 
-        for (name, service) in &mut file.services {
-            service
-                .environment
-                .append(&mut config.common.to_compose_env());
-            append_service(service, &config.pods, name);
-            let target_name = ctx.project.current_target().name();
+        let target_name = project.current_target().name();
+        for config in self.config.iter().chain(encrypted_config) {
+            append_service(&config.common, &config.pods);
             if let Some(target) = config.targets.get(target_name) {
-                service
-                    .environment
-                    .append(&mut target.common.to_compose_env());
-                append_service(service, &target.pods, name);
+                append_service(&target.common, &target.pods);
             }
         }
+
+        // Don't even look these up, because `!cmd` may run anything.
+        if omit_secrets {
+            secrets.retain(|var, value| {
+                let keep = matches!(value, SecretValue::Literal(_));
+                if !keep {
+                    debug!("Not exporting secret {} for {}", var, service_name);
+                }
+                keep
+            });
+        }
+        Ok(secrets)
+    }
+
+    /// Look up the actual values of `secrets`, using `runner` to run any
+    /// commands.  We only do this after merging, so we only look up the
+    /// secrets which the current target actually uses.
+    fn resolve_secrets(
+        &self,
+        runner: &dyn CaptureOutput,
+        project: &Project,
+        pod_name: &str,
+        service_name: &str,
+        secrets: &BTreeMap<String, SecretValue>,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut resolved = BTreeMap::new();
+        for (var, value) in secrets {
+            if let SecretValue::Literal(ref value) = *value {
+                resolved.insert(var.to_owned(), value.to_owned());
+                continue;
+            }
+            // Hold our lock while we look things up, so that parallel
+            // transforms don't run the same command twice.
+            let mut cache = self.resolved.lock().expect("lock poisoned");
+            if !cache.contains_key(value) {
+                let result =
+                    value.resolve(runner, self.env_var, project).map_err(|e| {
+                        e.context(Error::CouldNotResolveSecret {
+                            pod: pod_name.to_owned(),
+                            service: service_name.to_owned(),
+                            var: var.to_owned(),
+                        })
+                    })?;
+                cache.insert(value.to_owned(), result);
+            }
+            resolved.insert(var.to_owned(), cache[value].to_owned());
+        }
+        Ok(resolved)
+    }
+}
+
+impl PluginTransform for Plugin {
+    fn transform(
+        &self,
+        op: Operation,
+        ctx: &plugins::Context<'_>,
+        file: &mut dc::File,
+    ) -> Result<()> {
+        for (name, service) in &mut file.services {
+            let secrets = self.service_secrets(
+                ctx.runner,
+                op,
+                ctx.project,
+                ctx.pod.name(),
+                name,
+            )?;
+            service.environment.append(&mut to_compose_env(&secrets));
+        }
         Ok(())
     }
 }
@@ -213,3 +643,165 @@ fn injects_secrets_into_services() {
         .expect("has SOME_PASSWORD");
     assert_eq!(some_password.value().unwrap(), "production secret");
 }
+
+#[test]
+fn keys_are_parsed_from_key_files() {
+    use age::secrecy::ExposeSecret;
+
+    let key = x25519::Identity::generate();
+    let text = format!(
+        "# created: 2026-10-17\n\n{}\n",
+        key.to_string().expose_secret()
+    );
+    let keys = Keys::parse(&text).unwrap();
+    assert_eq!(keys.0.len(), 1);
+    assert_eq!(
+        keys.first().unwrap().to_public().to_string(),
+        key.to_public().to_string()
+    );
+    assert!(Keys::parse("not a key").is_err());
+}
+
+#[test]
+fn decrypts_encrypted_secrets_but_only_exports_them_when_asked() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("rails_hello").unwrap();
+    proj.set_current_target_name("production").unwrap();
+
+    // Encrypt some secrets using a throwaway key.
+    let key = x25519::Identity::generate();
+    let encrypted_path = proj.output_dir().join("secrets.yml.age");
+    fs::create_dir_all(proj.output_dir()).unwrap();
+    let yaml = "pods:\n  frontend:\n    web:\n      SOME_PASSWORD: encrypted secret\n";
+    encrypt_to_file(&encrypted_path, yaml, &key).unwrap();
+    let ciphertext = fs::read_to_string(&encrypted_path).unwrap();
+    assert!(!ciphertext.contains("encrypted secret"));
+
+    let runner = TestCommandRunner::new();
+    let mut plugin = Plugin::new(&proj).unwrap();
+    plugin.encrypted_path = encrypted_path.clone();
+    plugin.keys = Some(Keys(vec![key]));
+
+    // Encrypted secrets override plaintext ones.
+    let secrets = plugin
+        .service_secrets(&runner, Operation::Output, &proj, "frontend", "web")
+        .unwrap();
+    assert_eq!(secrets["SOME_PASSWORD"], "encrypted secret");
+    assert_eq!(secrets["GLOBAL_PASSWORD"], "more magic");
+
+    // But we don't export them unless asked to.
+    let secrets = plugin
+        .service_secrets(&runner, Operation::Export, &proj, "frontend", "web")
+        .unwrap();
+    assert_eq!(secrets["SOME_PASSWORD"], "production secret");
+    proj.set_export_secrets(true);
+    let secrets = plugin
+        .service_secrets(&runner, Operation::Export, &proj, "frontend", "web")
+        .unwrap();
+    assert_eq!(secrets["SOME_PASSWORD"], "encrypted secret");
+
+    // We can't decrypt secrets using the wrong key.
+    let mut plugin = Plugin::new(&proj).unwrap();
+    plugin.encrypted_path = encrypted_path;
+    plugin.keys = Some(Keys(vec![x25519::Identity::generate()]));
+    assert!(plugin
+        .service_secrets(&runner, Operation::Output, &proj, "frontend", "web")
+        .is_err());
+
+    proj.remove_test_output().unwrap();
+}
+
+/// (Test only.) Pretend that `CAGE_TEST_SECRET_SOURCE` is set.
+#[cfg(test)]
+fn fake_env_var(name: &str) -> Option<String> {
+    (name == "CAGE_TEST_SECRET_SOURCE").then(|| "from env".to_owned())
+}
+
+#[test]
+fn resolves_secrets_from_env_files_and_commands() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_secret_sources").unwrap();
+    let mut plugin = Plugin::new(&proj).unwrap();
+    plugin.env_var = fake_env_var;
+    let runner = TestCommandRunner::new();
+
+    let raw = plugin
+        .raw_service_secrets(Operation::Output, &proj, "frontend", "web")
+        .unwrap();
+    assert_eq!(
+        raw["API_KEY"],
+        SecretValue::Env("CAGE_TEST_SECRET_SOURCE".into())
+    );
+    assert_eq!(raw["TLS_KEY"], SecretValue::File("config/tls.key".into()));
+    assert_eq!(
+        raw["DATABASE_PASSWORD"],
+        SecretValue::Cmd("pass show withsecretsources/database".into())
+    );
+
+    let secrets = plugin
+        .resolve_secrets(&runner, &proj, "frontend", "web", &raw)
+        .unwrap();
+    assert_eq!(secrets["API_KEY"], "from env");
+    assert_eq!(secrets["TLS_KEY"], "not really a key");
+    assert_eq!(secrets["DATABASE_PASSWORD"], "");
+    assert_eq!(secrets["PLAIN"], "literal");
+
+    // We only run each command once, even if it's used by several services.
+    let raw = plugin
+        .raw_service_secrets(Operation::Output, &proj, "frontend", "worker")
+        .unwrap();
+    plugin
+        .resolve_secrets(&runner, &proj, "frontend", "worker", &raw)
+        .unwrap();
+    assert_ran!(runner, { ["pass", "show", "withsecretsources/database"] });
+
+    // During a dry run, we only print our commands.
+    let mut plugin = Plugin::new(&proj).unwrap();
+    plugin.env_var = fake_env_var;
+    let runner = DryRunCommandRunner::new();
+    let secrets = plugin
+        .resolve_secrets(&runner, &proj, "frontend", "worker", &raw)
+        .unwrap();
+    assert_eq!(secrets["DATABASE_PASSWORD"], "");
+    let cmds = runner.cmds();
+    assert_eq!(cmds.len(), 1);
+    assert!(cmds[0].ends_with("pass show withsecretsources/database"));
+
+    // We don't export secrets we'd have to look up unless asked to, and we
+    // don't run any commands while deciding.
+    let mut proj = proj;
+    let mut plugin = Plugin::new(&proj).unwrap();
+    plugin.env_var = fake_env_var;
+    let runner = TestCommandRunner::new();
+    let secrets = plugin
+        .service_secrets(&runner, Operation::Export, &proj, "frontend", "web")
+        .unwrap();
+    let vars: Vec<&str> = secrets.keys().map(|v| v.as_str()).collect();
+    assert_eq!(vars, &["PLAIN"]);
+    assert!(runner.cmds().is_empty());
+    proj.set_export_secrets(true);
+    let secrets = plugin
+        .service_secrets(&runner, Operation::Export, &proj, "frontend", "web")
+        .unwrap();
+    assert_eq!(secrets["API_KEY"], "from env");
+    assert_ran!(runner, { ["pass", "show", "withsecretsources/database"] });
+}
+
+#[test]
+fn errors_name_the_secret_which_could_not_be_resolved() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_secret_sources").unwrap();
+    let plugin = Plugin::new(&proj).unwrap();
+    let runner = TestCommandRunner::new();
+
+    let raw = plugin
+        .raw_service_secrets(Operation::Output, &proj, "frontend", "broken")
+        .unwrap();
+    let err = plugin
+        .resolve_secrets(&runner, &proj, "frontend", "broken", &raw)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "could not get secret MISSING for service 'broken' in pod 'frontend'"
+    );
+}
@@ -4,7 +4,6 @@
 
 use faraday_compose_yml::v2 as dc;
 use std::marker::PhantomData;
-#[cfg(test)]
 use std::path::Path;
 
 use crate::errors::*;
@@ -63,6 +62,8 @@ impl PluginTransform for Plugin {
         let project = ctx.project;
         let sources_dirs = project.sources_dirs();
         for service in &mut file.services.values_mut() {
+            let options = service.source_mount_options()?;
+            let mode = options.mode();
             for source_mount in service.sources(project.sources())? {
                 let source = source_mount.source;
                 if source.is_available_locally(&sources_dirs) && source.mounted() {
@@ -76,9 +77,37 @@ impl PluginTransform for Plugin {
                         .to_absolute()?;
 
                     // Add a mount point to the container.
-                    let mount =
-                        dc::VolumeMount::host(&path, source_mount.container_path);
-                    service.volumes.push(dc::value(mount));
+                    service.volumes.push(bind_mount(
+                        &path,
+                        &source_mount.container_path,
+                        mode.as_deref(),
+                    )?);
+
+                    // Apply any extra mounts and exclusions for the
+                    // service's own source tree.
+                    if source_mount.is_build_context {
+                        for (subpath, container_path) in &options.extra_mounts {
+                            let extra_path = source
+                                .path(&sources_dirs)
+                                .join(subpath)
+                                .to_absolute()?;
+                            service.volumes.push(bind_mount(
+                                &extra_path,
+                                container_path,
+                                mode.as_deref(),
+                            )?);
+                        }
+                        for exclude in &options.excludes {
+                            // Mounting an anonymous volume over a directory
+                            // hides the host's copy from the container.
+                            let container_path = format!(
+                                "{}/{}",
+                                source_mount.container_path.trim_end_matches('/'),
+                                exclude.trim_matches('/'),
+                            );
+                            service.volumes.push(dc::escape(container_path)?);
+                        }
+                    }
 
                     // Update the `build` field if it's present and it
                     // corresponds to this `Source`.
@@ -94,6 +123,24 @@ impl PluginTransform for Plugin {
     }
 }
 
+/// Build a bind mount of `host_path` at `container_path`, with an optional
+/// mode like `ro` or `cached`.
+fn bind_mount(
+    host_path: &Path,
+    container_path: &str,
+    mode: Option<&str>,
+) -> Result<dc::RawOr<dc::VolumeMount>> {
+    match mode {
+        None => Ok(dc::value(dc::VolumeMount::host(host_path, container_path))),
+        Some(mode) => Ok(dc::escape(format!(
+            "{}:{}:{}",
+            host_path.display(),
+            container_path,
+            mode
+        ))?),
+    }
+}
+
 #[test]
 fn adds_a_volume_with_a_subdirectory() {
     let _ = env_logger::try_init();
@@ -125,3 +172,50 @@ fn adds_a_volume_with_a_subdirectory() {
     assert!(host_path.ends_with(Path::new("src/rails_hello/myfolder")));
     assert_eq!(src_volume.container, "/usr/src/app");
 }
+
+#[test]
+fn applies_per_service_mount_options() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_fixture("with_source_mount_options").unwrap();
+    let plugin = Plugin::new(&proj).unwrap();
+
+    let sources_dirs = proj.sources_dirs();
+    {
+        let source = proj.sources_mut().find_by_alias_mut("rails_hello").unwrap();
+        source.fake_clone_source(&sources_dirs).unwrap();
+    }
+
+    let target = proj.current_target();
+    let frontend = proj.pod("frontend").unwrap();
+    let ctx = plugins::Context::new(&proj, frontend, "up");
+    let mut file = frontend.merged_file(target).unwrap();
+
+    plugin
+        .transform(Operation::Output, &ctx, &mut file)
+        .unwrap();
+
+    let src_path = proj.src_dir().join("rails_hello").to_absolute().unwrap();
+    let volumes: Vec<String> = serde_yaml::from_value(
+        serde_yaml::to_value(&file.services["web"].volumes).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        volumes,
+        vec![
+            format!("{}:/usr/src/app:ro,cached", src_path.display()),
+            format!(
+                "{}:/migrations:ro,cached",
+                src_path.join("db/migrate").display()
+            ),
+            "/usr/src/app/node_modules".to_owned(),
+            "/usr/src/app/tmp/cache".to_owned(),
+        ]
+    );
+
+    // Services without options get a plain read-write mount.
+    let worker = file.services["worker"].volumes[0].value().unwrap();
+    assert_eq!(worker.host, Some(dc::HostVolume::Path(src_path)));
+    assert_eq!(worker.container, "/usr/src/app");
+
+    proj.remove_test_output().unwrap();
+}
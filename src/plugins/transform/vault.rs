@@ -1,17 +1,20 @@
 //! Plugin which issues vault tokens to services.
 
 use faraday_compose_yml::v2 as dc;
+use serde_json::json;
 use std::{
     collections::{btree_map::Entry, BTreeMap, BTreeSet},
     env,
     fmt::Debug,
     fs,
     io::{self, Read},
-    path::PathBuf,
+    mem,
+    path::{Path, PathBuf},
     result,
+    sync::OnceLock,
     time::{Duration, SystemTime},
 };
-use vault::client::VaultDuration;
+use vault::client::{EndpointResponse, HttpVerb, VaultDuration};
 
 use crate::errors::*;
 use crate::plugins;
@@ -26,6 +29,18 @@ use crate::Target;
 /// at all? Defaults to 30 days.
 const DEFAULT_TTL: u64 = 30 * 24 * 60 * 60;
 
+/// How many seconds should a wrapped secret ID be valid for, if the user
+/// doesn't say?
+const DEFAULT_WRAP_TTL: u64 = 5 * 60;
+
+/// Where the AppRole auth method we use to issue wrapped secret IDs is
+/// mounted, if the user doesn't say.
+const DEFAULT_APPROLE_MOUNT: &str = "approle";
+
+/// The subcommands which create containers.  Wrapped secret IDs can only be
+/// used once, so we don't generate them for anything else.
+const CONTAINER_CREATING_SUBCOMMANDS: &[&str] = &["up", "run", "run-script", "test"];
+
 /// How should our applications authenticate themselves with vault?
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum AuthType {
@@ -33,6 +48,251 @@ enum AuthType {
     /// appropriate policies on each token.
     #[serde(rename = "token")]
     Token,
+    /// Give each service the `VAULT_ROLE_ID` of an AppRole, and a
+    /// response-wrapped secret ID in `VAULT_WRAPPED_SECRET_ID`, which the
+    /// service can unwrap once and use to log in.  Policies are attached to
+    /// the role, not to anything we issue.
+    #[serde(rename = "wrapped_secret_id")]
+    WrappedSecretId,
+}
+
+/// How should `cage` itself log into Vault?
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+enum Login {
+    /// Use `VAULT_MASTER_TOKEN`, `VAULT_TOKEN` or `~/.vault-token`.
+    Token,
+    /// Log in using an AppRole.  The secret ID is always read from the
+    /// environment.
+    AppRole {
+        /// Where the auth method is mounted.  Defaults to `approle`.
+        #[serde(default)]
+        mount: Option<String>,
+        /// The role ID, if we don't want to read it from the environment.
+        #[serde(default)]
+        role_id: Option<String>,
+        /// The variable containing our role ID.  Defaults to `VAULT_ROLE_ID`.
+        #[serde(default)]
+        role_id_env: Option<String>,
+        /// The variable containing our secret ID.  Defaults to
+        /// `VAULT_SECRET_ID`.
+        #[serde(default)]
+        secret_id_env: Option<String>,
+    },
+    /// Log in using a JWT, such as the ones issued by CI systems.
+    Jwt {
+        /// Where the auth method is mounted.  Defaults to `jwt`.
+        #[serde(default)]
+        mount: Option<String>,
+        /// The role to log in as.
+        role: String,
+        /// The variable containing our JWT.  Defaults to `VAULT_JWT`.
+        #[serde(default)]
+        jwt_env: Option<String>,
+        /// A file containing our JWT, which we use instead of `jwt_env`.
+        #[serde(default)]
+        jwt_file: Option<PathBuf>,
+    },
+    /// Log in using a Kubernetes service account token.
+    Kubernetes {
+        /// Where the auth method is mounted.  Defaults to `kubernetes`.
+        #[serde(default)]
+        mount: Option<String>,
+        /// The role to log in as.
+        role: String,
+        /// The service account token.  Defaults to the one Kubernetes
+        /// mounts into every pod.
+        #[serde(default)]
+        jwt_file: Option<PathBuf>,
+    },
+}
+
+impl Login {
+    /// The auth method to log in with, and the request to send it, using
+    /// `env_var` to look up environment variables.  Returns `None` if we
+    /// should use an existing token instead.
+    fn credentials(
+        &self,
+        env_var: fn(&str) -> Option<String>,
+    ) -> Result<Option<(String, serde_json::Value)>> {
+        match *self {
+            Login::Token => Ok(None),
+            Login::AppRole {
+                ref mount,
+                ref role_id,
+                ref role_id_env,
+                ref secret_id_env,
+            } => {
+                let role_id = match *role_id {
+                    Some(ref role_id) => role_id.to_owned(),
+                    None => login_var(
+                        env_var,
+                        role_id_env.as_deref().unwrap_or("VAULT_ROLE_ID"),
+                    )?,
+                };
+                let secret_id = login_var(
+                    env_var,
+                    secret_id_env.as_deref().unwrap_or("VAULT_SECRET_ID"),
+                )?;
+                Ok(Some((
+                    mount.as_deref().unwrap_or("approle").to_owned(),
+                    json!({ "role_id": role_id, "secret_id": secret_id }),
+                )))
+            }
+            Login::Jwt {
+                ref mount,
+                ref role,
+                ref jwt_env,
+                ref jwt_file,
+            } => {
+                let jwt = match *jwt_file {
+                    Some(ref path) => login_file(path)?,
+                    None => {
+                        login_var(env_var, jwt_env.as_deref().unwrap_or("VAULT_JWT"))?
+                    }
+                };
+                Ok(Some((
+                    mount.as_deref().unwrap_or("jwt").to_owned(),
+                    json!({ "role": role, "jwt": jwt }),
+                )))
+            }
+            Login::Kubernetes {
+                ref mount,
+                ref role,
+                ref jwt_file,
+            } => {
+                let default_path =
+                    Path::new("/var/run/secrets/kubernetes.io/serviceaccount/token");
+                let jwt = login_file(jwt_file.as_deref().unwrap_or(default_path))?;
+                Ok(Some((
+                    mount.as_deref().unwrap_or("kubernetes").to_owned(),
+                    json!({ "role": role, "jwt": jwt }),
+                )))
+            }
+        }
+    }
+
+    /// Log into the Vault server at `addr`, and return the token we should
+    /// use to issue new tokens.
+    fn token(&self, addr: &str) -> Result<String> {
+        let (mount, body) = match self.credentials(|name| env::var(name).ok())? {
+            Some(credentials) => credentials,
+            None => return find_vault_token(),
+        };
+        let client = vault::Client::new_no_lookup(addr, "").map_err(|e| {
+            anyhow::anyhow!("{}: {}", Error::VaultError(addr.to_owned()), e)
+        })?;
+        let endpoint = format!("auth/{}/login", mount);
+        let response = client
+            .call_endpoint::<serde_json::Value>(
+                HttpVerb::POST,
+                &endpoint,
+                None,
+                Some(&body.to_string()),
+            )
+            .map_err(|e| {
+                anyhow::anyhow!("{}: {}", Error::VaultError(addr.to_owned()), e)
+            })?;
+        match response {
+            EndpointResponse::VaultResponse(response) => response
+                .auth
+                .map(|auth| auth.client_token)
+                .ok_or_else(|| err!("could not log into {} using {}", addr, endpoint)),
+            EndpointResponse::Empty => {
+                Err(err!("could not log into {} using {}", addr, endpoint))
+            }
+        }
+    }
+}
+
+/// Read a login credential from the environment variable `name`, using
+/// `env_var` to look it up.
+fn login_var(env_var: fn(&str) -> Option<String>, name: &str) -> Result<String> {
+    env_var(name).ok_or_else(|| {
+        err!(
+            "Please set the environment variable {} to log into vault",
+            name
+        )
+    })
+}
+
+/// Read a login credential from `path`.
+fn login_file(path: &Path) -> Result<String> {
+    let value = fs::read_to_string(path).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotReadFile(path.to_owned()))
+    })?;
+    Ok(value.trim().to_owned())
+}
+
+#[test]
+fn login_credentials() {
+    let approle: Login = serde_yaml::from_str(
+        "method: approle\nrole_id: my-role\nsecret_id_env: CAGE_TEST_SECRET_ID\n",
+    )
+    .unwrap();
+    assert!(approle.credentials(|_| None).is_err());
+    let env_var: fn(&str) -> Option<String> =
+        |name| (name == "CAGE_TEST_SECRET_ID").then(|| "my-secret".to_owned());
+    let (mount, body) = approle.credentials(env_var).unwrap().unwrap();
+    assert_eq!(mount, "approle");
+    assert_eq!(
+        body,
+        json!({ "role_id": "my-role", "secret_id": "my-secret" })
+    );
+
+    let jwt: Login = serde_yaml::from_str(
+        "method: jwt\nmount: gitlab\nrole: ci\njwt_file: tests/fixtures/with_local_vault/ci.jwt\n",
+    )
+    .unwrap();
+    let (mount, body) = jwt.credentials(|_| None).unwrap().unwrap();
+    assert_eq!(mount, "gitlab");
+    assert_eq!(body, json!({ "role": "ci", "jwt": "not.a.jwt" }));
+
+    let kubernetes: Login =
+        serde_yaml::from_str("method: kubernetes\nrole: web\n").unwrap();
+    assert_eq!(
+        kubernetes,
+        Login::Kubernetes {
+            mount: None,
+            role: "web".to_owned(),
+            jwt_file: None,
+        }
+    );
+
+    let token: Login = serde_yaml::from_str("method: token\n").unwrap();
+    assert!(token.credentials(|_| None).unwrap().is_none());
+}
+
+/// Where do we get tokens and secrets?
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// A real Vault server, specified using `VAULT_ADDR`.
+    #[default]
+    Vault,
+    /// A local YAML file, for use in development and CI.  See `LocalVault`.
+    Local,
+}
+
+/// The default location of the YAML file used by `Backend::Local`.
+const DEFAULT_LOCAL_STORE: &str = "config/vault.local.yml";
+
+/// A secret to read from one of Vault's KV secrets engines and put in a
+/// service's environment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KvSecret {
+    /// Where the secrets engine is mounted.  Defaults to `secret`.
+    #[serde(default)]
+    mount: Option<String>,
+    /// The path of the secret within the engine.  This may contain
+    /// variables like `$TARGET`.
+    path: dc::RawOr<String>,
+    /// The key to read from the secret.
+    key: String,
+    /// The version of the KV engine, either 1 or 2.  Defaults to 2.
+    #[serde(default)]
+    kv_version: Option<u8>,
 }
 
 /// The policies associated with a specific pod.
@@ -47,6 +307,21 @@ struct ServiceConfig {
     /// Policies to apply to this service.
     #[serde(default)]
     policies: Vec<dc::RawOr<String>>,
+
+    /// How this service should authenticate, if it's different from our
+    /// top-level `auth_type`.
+    #[serde(default)]
+    auth_type: Option<AuthType>,
+
+    /// The AppRole to use with `auth_type: wrapped_secret_id`.  Defaults to
+    /// `$PROJECT-$TARGET-$POD-$SERVICE`.
+    #[serde(default)]
+    approle: Option<dc::RawOr<String>>,
+
+    /// Environment variables to set from KV secrets, in addition to those
+    /// for the whole target.
+    #[serde(default)]
+    secrets: BTreeMap<String, KvSecret>,
 }
 
 /// Policies to apply to each service in a pod.
@@ -60,6 +335,10 @@ struct TargetConfig {
     #[serde(default)]
     extra_environment: BTreeMap<String, dc::RawOr<String>>,
 
+    /// Environment variables to set from KV secrets in each service.
+    #[serde(default)]
+    secrets: BTreeMap<String, KvSecret>,
+
     /// How long should tokens be valid for?
     ///
     /// We support `default_ttl` as an alias for backwards compatibility with
@@ -70,6 +349,27 @@ struct TargetConfig {
     /// Default policies to apply to every service.
     #[serde(default)]
     default_policies: Option<Vec<dc::RawOr<String>>>,
+
+    /// Where should we get tokens and secrets?
+    #[serde(default)]
+    backend: Option<Backend>,
+
+    /// The YAML file to use for `backend: local`, relative to the project.
+    #[serde(default)]
+    local_store: Option<PathBuf>,
+
+    /// How should `cage` log into Vault?
+    #[serde(default)]
+    login: Option<Login>,
+
+    /// How many seconds should wrapped secret IDs be valid for?
+    #[serde(default)]
+    wrap_ttl: Option<u64>,
+
+    /// Where is the AppRole auth method we use for wrapped secret IDs
+    /// mounted?
+    #[serde(default)]
+    approle_mount: Option<String>,
 }
 
 impl TargetConfig {
@@ -78,15 +378,38 @@ impl TargetConfig {
     fn extended_with(&self, other: &TargetConfig) -> TargetConfig {
         let mut extra_environment = self.extra_environment.clone();
         extra_environment.extend(other.extra_environment.clone());
+        let mut secrets = self.secrets.clone();
+        secrets.extend(other.secrets.clone());
         TargetConfig {
             extra_environment,
+            secrets,
             default_ttl: other.default_ttl.or(self.default_ttl),
             default_policies: other
                 .default_policies
                 .clone()
                 .or_else(|| self.default_policies.clone()),
+            backend: other.backend.or(self.backend),
+            local_store: other
+                .local_store
+                .clone()
+                .or_else(|| self.local_store.clone()),
+            login: other.login.clone().or_else(|| self.login.clone()),
+            wrap_ttl: other.wrap_ttl.or(self.wrap_ttl),
+            approle_mount: other
+                .approle_mount
+                .clone()
+                .or_else(|| self.approle_mount.clone()),
         }
     }
+
+    /// The path to the YAML file used by `backend: local`.
+    fn local_store_path(&self, project: &Project) -> PathBuf {
+        project.root_dir().join(
+            self.local_store
+                .as_deref()
+                .unwrap_or_else(|| Path::new(DEFAULT_LOCAL_STORE)),
+        )
+    }
 }
 
 #[test]
@@ -97,6 +420,7 @@ fn target_config_extension() {
         extra_environment: e1,
         default_ttl: Some(1),
         default_policies: Some(vec![dc::value("p1".to_owned())]),
+        ..TargetConfig::default()
     };
 
     let mut e2 = BTreeMap::new();
@@ -105,6 +429,10 @@ fn target_config_extension() {
         extra_environment: e2,
         default_ttl: Some(2),
         default_policies: Some(vec![dc::value("p2".to_owned())]),
+        backend: Some(Backend::Local),
+        wrap_ttl: Some(60),
+        approle_mount: Some("ci-approle".to_owned()),
+        ..TargetConfig::default()
     };
 
     let mut e_all = BTreeMap::new();
@@ -116,6 +444,10 @@ fn target_config_extension() {
             extra_environment: e_all.clone(),
             default_ttl: Some(2),
             default_policies: Some(vec![dc::value("p2".to_owned())]),
+            backend: Some(Backend::Local),
+            wrap_ttl: Some(60),
+            approle_mount: Some("ci-approle".to_owned()),
+            ..TargetConfig::default()
         }
     );
     assert_eq!(
@@ -124,6 +456,10 @@ fn target_config_extension() {
             extra_environment: e_all,
             default_ttl: Some(1),
             default_policies: Some(vec![dc::value("p1".to_owned())]),
+            backend: Some(Backend::Local),
+            wrap_ttl: Some(60),
+            approle_mount: Some("ci-approle".to_owned()),
+            ..TargetConfig::default()
         }
     );
 }
@@ -249,10 +585,17 @@ impl TokenInfo {
         &self.policies != desired_policies
             || SystemTime::now() + desired_ttl / 2 >= self.expires
     }
+
+    /// Can we renew this token instead of issuing a new one?  Renewing
+    /// can't change a token's policies, or bring it back from the dead.
+    fn can_renew(&self, desired_policies: &BTreeSet<String>) -> bool {
+        &self.policies == desired_policies && SystemTime::now() < self.expires
+    }
 }
 
 /// An abstract interface to Vault's token-generation capabilities.  We use
-/// this to mock vault during tests.
+/// this to mock vault during tests, and to replace it with `LocalVault`
+/// during development.
 trait GenerateToken: Debug + Sync {
     /// Get a `VAULT_ADDR` value to use along with this token.
     fn addr(&self) -> &str;
@@ -263,6 +606,36 @@ trait GenerateToken: Debug + Sync {
         policies: &BTreeSet<String>,
         ttl: Duration,
     ) -> Result<TokenInfo>;
+    /// Extend the life of `token` by `ttl`, returning when it will now
+    /// expire.
+    fn renew_token(&self, token: &str, ttl: Duration) -> Result<SystemTime>;
+    /// Revoke `token`, so that it can no longer be used.
+    fn revoke_token(&self, token: &str) -> Result<()>;
+    /// Generate a response-wrapped secret ID for the AppRole `role`, using
+    /// the AppRole auth method mounted at `mount`.
+    fn generate_wrapped_secret_id(
+        &self,
+        mount: &str,
+        role: &str,
+        wrap_ttl: Duration,
+    ) -> Result<WrappedSecretId>;
+    /// Read the key/value pairs stored at `path` in the KV secrets engine
+    /// mounted at `mount`.  `kv_version` must be 1 or 2.
+    fn read_secret(
+        &self,
+        mount: &str,
+        path: &str,
+        kv_version: u8,
+    ) -> Result<BTreeMap<String, String>>;
+}
+
+/// Credentials which allow a service to log in using an AppRole.
+#[derive(Clone, Debug)]
+struct WrappedSecretId {
+    /// The role ID of the AppRole, which isn't secret.
+    role_id: String,
+    /// A single-use token which can be unwrapped to get a secret ID.
+    wrapping_token: String,
 }
 
 /// An interface to an actual vault server.
@@ -275,8 +648,8 @@ struct Vault {
 }
 
 impl Vault {
-    /// Create a new vault client.
-    fn new() -> Result<Vault> {
+    /// Create a new vault client, logging in using `login`.
+    fn new(login: &Login) -> Result<Vault> {
         let mut addr = env::var("VAULT_ADDR").map_err(|_| {
             err(
                 "Please set the environment variable VAULT_ADDR to the URL of \
@@ -290,7 +663,7 @@ impl Vault {
             let new_len = addr.len() - 1;
             addr.truncate(new_len);
         }
-        let token = find_vault_token()?;
+        let token = login.token(&addr)?;
         Ok(Vault { addr, token })
     }
 }
@@ -334,83 +707,384 @@ impl GenerateToken for Vault {
             expires,
         })
     }
+
+    fn renew_token(&self, token: &str, ttl: Duration) -> Result<SystemTime> {
+        let client = vault::Client::new(&self.addr[..], &self.token).map_err(|e| {
+            anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+        })?;
+        let body =
+            json!({ "token": token, "increment": format!("{}s", ttl.as_secs()) });
+        let response = client
+            .call_endpoint::<serde_json::Value>(
+                HttpVerb::POST,
+                "auth/token/renew",
+                None,
+                Some(&body.to_string()),
+            )
+            .map_err(|e| {
+                anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+            })?;
+        // Vault may give us less time than we asked for, if the token is
+        // close to its maximum TTL.
+        let lease_duration = match response {
+            EndpointResponse::VaultResponse(response) => {
+                response.auth.and_then(|auth| auth.lease_duration)
+            }
+            EndpointResponse::Empty => None,
+        }
+        .ok_or_else(|| err!("could not renew token using {}", self.addr))?;
+        Ok(SystemTime::now() + lease_duration.0)
+    }
+
+    fn revoke_token(&self, token: &str) -> Result<()> {
+        let client = vault::Client::new(&self.addr[..], &self.token).map_err(|e| {
+            anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+        })?;
+        let body = json!({ "token": token });
+        client
+            .call_endpoint::<serde_json::Value>(
+                HttpVerb::POST,
+                "auth/token/revoke",
+                None,
+                Some(&body.to_string()),
+            )
+            .map_err(|e| {
+                anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+            })?;
+        Ok(())
+    }
+
+    fn generate_wrapped_secret_id(
+        &self,
+        mount: &str,
+        role: &str,
+        wrap_ttl: Duration,
+    ) -> Result<WrappedSecretId> {
+        let client = vault::Client::new(&self.addr[..], &self.token).map_err(|e| {
+            anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+        })?;
+        let role_path = format!("auth/{}/role/{}", mount, role);
+        let role_id = client
+            .call_endpoint::<BTreeMap<String, String>>(
+                HttpVerb::GET,
+                &format!("{}/role-id", role_path),
+                None,
+                None,
+            )
+            .map_err(|e| {
+                anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+            })?;
+        let role_id = match role_id {
+            EndpointResponse::VaultResponse(response) => {
+                response.data.and_then(|mut data| data.remove("role_id"))
+            }
+            EndpointResponse::Empty => None,
+        }
+        .ok_or_else(|| err!("could not get role ID for AppRole {}", role))?;
+        let wrapped = client
+            .call_endpoint::<serde_json::Value>(
+                HttpVerb::POST,
+                &format!("{}/secret-id", role_path),
+                Some(&format!("{}s", wrap_ttl.as_secs())),
+                None,
+            )
+            .map_err(|e| {
+                anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+            })?;
+        let wrapping_token = match wrapped {
+            EndpointResponse::VaultResponse(response) => {
+                response.wrap_info.map(|info| info.token)
+            }
+            EndpointResponse::Empty => None,
+        }
+        .ok_or_else(|| err!("could not get wrapped secret ID for AppRole {}", role))?;
+        Ok(WrappedSecretId {
+            role_id,
+            wrapping_token,
+        })
+    }
+
+    fn read_secret(
+        &self,
+        mount: &str,
+        path: &str,
+        kv_version: u8,
+    ) -> Result<BTreeMap<String, String>> {
+        let client = vault::Client::new(&self.addr[..], &self.token).map_err(|e| {
+            anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+        })?;
+        let endpoint = if kv_version == 1 {
+            format!("{}/{}", mount, path)
+        } else {
+            format!("{}/data/{}", mount, path)
+        };
+        let response = client
+            .call_endpoint::<serde_json::Value>(HttpVerb::GET, &endpoint, None, None)
+            .map_err(|e| {
+                anyhow::anyhow!("{}: {}", Error::VaultError(self.addr.clone()), e)
+            })?;
+        let mut data = match response {
+            EndpointResponse::VaultResponse(response) => response.data,
+            EndpointResponse::Empty => None,
+        }
+        .ok_or_else(|| err!("no secret at {} in {}", endpoint, self.addr))?;
+        // Version 2 of the KV engine wraps our data in another layer, next
+        // to its metadata.
+        if kv_version != 1 {
+            data = data["data"].take();
+        }
+        match data {
+            serde_json::Value::Object(map) => Ok(map
+                .into_iter()
+                .map(|(key, value)| match value {
+                    serde_json::Value::String(value) => (key, value),
+                    value => (key, value.to_string()),
+                })
+                .collect()),
+            _ => Err(err!("no secret at {} in {}", endpoint, self.addr)),
+        }
+    }
 }
 
-/// Map the services in a pod to their `TokenInfo`.
-type CachedPodTokens = BTreeMap<String, TokenInfo>;
+/// A stand-in for a Vault server, which we use for local development and
+/// CI.  It issues fake tokens with predictable values, and serves secrets
+/// from a YAML file, so that we can use the same `config/vault.yml` in
+/// every target without needing a Vault server.
+#[derive(Debug)]
+struct LocalVault {
+    /// The file we loaded our data from.
+    path: PathBuf,
+    /// The data from `path`.
+    store: LocalStore,
+}
 
-/// Map the pods in an environment to their `TokenCachePod`.
-type CachedTargetTokens = BTreeMap<String, CachedPodTokens>;
+/// The contents of a `LocalVault` YAML file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocalStore {
+    /// The `VAULT_ADDR` to give to services.
+    #[serde(default = "LocalStore::default_addr")]
+    addr: String,
+    /// Secrets, keyed by their Vault path.
+    #[serde(default)]
+    secrets: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl LocalStore {
+    /// The `VAULT_ADDR` we use if none is specified.
+    fn default_addr() -> String {
+        "http://127.0.0.1:8200".to_owned()
+    }
+}
+
+impl LocalVault {
+    /// Load a local vault from `path`.  A missing file is treated as an
+    /// empty store, so that tokens work with no extra setup.
+    fn load(path: &Path) -> Result<LocalVault> {
+        let store = if path.exists() {
+            load_yaml(path)?
+        } else {
+            LocalStore {
+                addr: LocalStore::default_addr(),
+                secrets: BTreeMap::new(),
+            }
+        };
+        Ok(LocalVault {
+            path: path.to_owned(),
+            store,
+        })
+    }
+}
+
+impl GenerateToken for LocalVault {
+    fn addr(&self) -> &str {
+        &self.store.addr
+    }
+
+    fn generate_token(
+        &self,
+        display_name: &str,
+        policies: &BTreeSet<String>,
+        ttl: Duration,
+    ) -> Result<TokenInfo> {
+        Ok(TokenInfo {
+            token: format!("local.{}", display_name),
+            policies: policies.to_owned(),
+            expires: SystemTime::now() + ttl,
+        })
+    }
+
+    fn renew_token(&self, _token: &str, ttl: Duration) -> Result<SystemTime> {
+        Ok(SystemTime::now() + ttl)
+    }
+
+    fn revoke_token(&self, _token: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn generate_wrapped_secret_id(
+        &self,
+        _mount: &str,
+        role: &str,
+        _wrap_ttl: Duration,
+    ) -> Result<WrappedSecretId> {
+        Ok(WrappedSecretId {
+            role_id: format!("local.role_id.{}", role),
+            wrapping_token: format!("local.wrapped_secret_id.{}", role),
+        })
+    }
+
+    fn read_secret(
+        &self,
+        mount: &str,
+        path: &str,
+        _kv_version: u8,
+    ) -> Result<BTreeMap<String, String>> {
+        let path = format!("{}/{}", mount, path);
+        self.store
+            .secrets
+            .get(&path)
+            .cloned()
+            .ok_or_else(|| err!("no secret at {} in {}", path, self.path.display()))
+    }
+}
+
+/// The directory in which we cache the tokens issued to services in
+/// `target`.  Each token is stored in `$POD/$SERVICE.yml`.
+fn token_cache_dir(project: &Project, target: &Target) -> PathBuf {
+    project.output_dir().join("vault-cache").join(target.name())
+}
 
-/// A cache of Vault tokens. This format is stored on disk between runs.
-#[derive(Default, Deserialize, Serialize)]
-struct CachedTokens {
+/// The format of `vault-cache/$POD.yml`, which older versions of `cage` used
+/// to cache the tokens issued to `$POD` in every target.
+#[derive(Deserialize)]
+struct LegacyCachedTokens {
     /// Cached token information, keyed by target, pod and service.
-    targets: BTreeMap<String, CachedTargetTokens>,
+    targets: BTreeMap<String, BTreeMap<String, BTreeMap<String, TokenInfo>>>,
 }
 
-impl CachedTokens {
-    /// Look up an entry in our cache. An `Entry` is a Rust API that allows to
-    /// check whether an item is present in a hash table, get it, and set it.
-    fn entry(
-        &mut self,
-        target: String,
-        pod: String,
-        service: String,
-    ) -> Entry<'_, String, TokenInfo> {
-        self.targets
-            .entry(target)
-            .or_default()
-            .entry(pod)
-            .or_default()
-            .entry(service)
+/// If we have tokens for `pod` in the cache format used by older versions
+/// of `cage`, move them to where we currently keep them, so that we renew
+/// them instead of issuing new ones, and so that `cage vault revoke` can
+/// find them.  We never overwrite tokens which are already in the new cache.
+fn migrate_legacy_token_cache(project: &Project, pod: &Pod) -> Result<()> {
+    let legacy_path = project
+        .output_dir()
+        .join("vault-cache")
+        .join(format!("{}.yml", pod.name()));
+    if !legacy_path.exists() {
+        return Ok(());
     }
+    if project.dry_run() {
+        println!("Not migrating {} during a dry run", legacy_path.display());
+        return Ok(());
+    }
+    debug!("migrating Vault tokens from {}", legacy_path.display());
+    let legacy: LegacyCachedTokens = load_yaml(&legacy_path)?;
+    for (target, pods) in legacy.targets {
+        let target_dir = project.output_dir().join("vault-cache").join(target);
+        for (pod_name, services) in pods {
+            for (service, info) in services {
+                let path = target_dir.join(&pod_name).join(format!("{}.yml", service));
+                if !path.exists() {
+                    dump_yaml(&path, &info)?;
+                }
+            }
+        }
+    }
+    fs::remove_file(&legacy_path).map_err(|e| {
+        anyhow::Error::new(e)
+            .context(format!("could not delete {}", legacy_path.display()))
+    })
+}
+
+/// Load the tokens cached for the services in `pod_dir`.
+fn load_cached_pod_tokens(pod_dir: &Path) -> Result<BTreeMap<String, TokenInfo>> {
+    let mut tokens = BTreeMap::new();
+    if !pod_dir.exists() {
+        return Ok(tokens);
+    }
+    let entries = fs::read_dir(pod_dir).map_err(|e| {
+        anyhow::Error::new(e).context(Error::CouldNotReadFile(pod_dir.to_owned()))
+    })?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "yml") {
+            if let Some(service) = path.file_stem().and_then(|s| s.to_str()) {
+                tokens.insert(service.to_owned(), load_yaml(&path)?);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A token which we've issued to a service and cached in our output
+/// directory.
+#[derive(Debug)]
+pub(crate) struct CachedToken {
+    /// The pod containing the service.
+    pub(crate) pod: String,
+    /// The service which uses this token.
+    pub(crate) service: String,
+    /// The policies assigned to this token.
+    pub(crate) policies: BTreeSet<String>,
+    /// When this token will expire, unless it's renewed first.
+    pub(crate) expires: SystemTime,
 }
 
 /// A token cache which wraps `GenerateToken`, and keeps a cache of generated
-/// tokens.
+/// tokens.  This is stored on disk between runs, so that we renew existing
+/// tokens instead of issuing new ones every time.
 struct TokenCache<'gen> {
     /// The generator we use to create new tokens when needed.
     generator: &'gen dyn GenerateToken,
 
-    /// The path to our cache.
-    cache_path: PathBuf,
+    /// The directory containing the tokens for our pod.  We have to cache
+    /// per-pod because our plugin is called in parallel.
+    pod_dir: PathBuf,
 
-    /// Our cache of known tokens, some of which may have expired.
-    cached: CachedTokens,
+    /// Our cache of known tokens, keyed by service, some of which may have
+    /// expired.
+    cached: BTreeMap<String, TokenInfo>,
+
+    /// Are we doing a dry run?  If so, we never touch Vault or our cache
+    /// directory, and we only say what we would have done.
+    dry_run: bool,
 }
 
 impl<'gen> TokenCache<'gen> {
-    /// Create a new `TokenCache` object for `project`, loading any existing
-    /// tokens from disk.
+    /// Create a new `TokenCache` object for `pod` in the current target,
+    /// loading any existing tokens from disk.
     fn load_or_create(
         project: &Project,
         pod: &Pod,
         generator: &'gen dyn GenerateToken,
     ) -> Result<TokenCache<'gen>> {
-        let cache_path = project
-            .output_dir()
-            .join("vault-cache")
-            // We have to cache per-pod because our plugin is called in parallel.
-            .join(format!("{}.yml", pod.name()));
-        let cached = if cache_path.exists() {
-            load_yaml(&cache_path)?
-        } else {
-            CachedTokens::default()
-        };
+        migrate_legacy_token_cache(project, pod)?;
+        let pod_dir =
+            token_cache_dir(project, project.current_target()).join(pod.name());
+        let cached = load_cached_pod_tokens(&pod_dir)?;
         Ok(TokenCache {
             generator,
-            cache_path,
+            pod_dir,
             cached,
+            dry_run: project.dry_run(),
         })
     }
 
     /// Write our token cache to disk.
     fn save(&self) -> Result<()> {
-        dump_yaml(&self.cache_path, &self.cached)
+        if self.dry_run {
+            return Ok(());
+        }
+        for (service, info) in &self.cached {
+            dump_yaml(&self.pod_dir.join(format!("{}.yml", service)), info)?;
+        }
+        Ok(())
     }
 
-    /// Fetch a token from our cache, or generate a new one.
+    /// Fetch a token from our cache, renewing it if necessary, or generate a
+    /// new one.
     fn get<'cache>(
         &'cache mut self,
         project: &str,
@@ -427,12 +1101,17 @@ impl<'gen> TokenCache<'gen> {
         };
 
         // Look up what we have in the cache and decide what to do.
-        let entry =
-            self.cached
-                .entry(target.to_owned(), pod.to_owned(), service.to_owned());
-        match entry {
+        match self.cached.entry(service.to_owned()) {
             Entry::Vacant(vacancy) => {
                 trace!("token cache does not contain {}", service);
+                if self.dry_run {
+                    println!("Would issue a Vault token for {}", service);
+                    return Ok(vacancy.insert(TokenInfo {
+                        token: String::new(),
+                        policies: policies.to_owned(),
+                        expires: SystemTime::now(),
+                    }));
+                }
                 // We can't easily factor out any code using `self.generator`
                 // while `entry` holds a mutable reference to `self.cached`,
                 // because those two parts of object are currently "owned" by
@@ -447,13 +1126,51 @@ impl<'gen> TokenCache<'gen> {
             Entry::Occupied(occupied) => {
                 trace!("token cache hit for {}", service);
                 let cached = occupied.into_mut();
-                if cached.should_renew(policies, ttl) {
-                    trace!("token cache needs new token for {}", service);
-                    // We'll need a new token.
-                    *cached = self
-                        .generator
-                        .generate_token(&display_name(), policies, ttl)
-                        .map_err(mkerr)?;
+                if cached.should_renew(policies, ttl) && self.dry_run {
+                    if cached.can_renew(policies) {
+                        println!("Would renew the Vault token for {}", service);
+                    } else {
+                        println!("Would replace the Vault token for {}", service);
+                    }
+                } else if cached.should_renew(policies, ttl) {
+                    let renewed = if cached.can_renew(policies) {
+                        trace!("renewing token for {}", service);
+                        self.generator
+                            .renew_token(&cached.token, ttl)
+                            .map_err(|e| {
+                                warn!(
+                                    "Could not renew token for {}, issuing a new \
+                                     one: {:#}",
+                                    service, e
+                                )
+                            })
+                            .ok()
+                    } else {
+                        None
+                    };
+                    match renewed {
+                        Some(expires) => cached.expires = expires,
+                        None => {
+                            trace!("token cache needs new token for {}", service);
+                            let new_token = self
+                                .generator
+                                .generate_token(&display_name(), policies, ttl)
+                                .map_err(mkerr)?;
+                            // Nobody will use the old token again, so don't
+                            // leave it lying around until it expires.
+                            let old = mem::replace(cached, new_token);
+                            if SystemTime::now() < old.expires {
+                                trace!("revoking old token for {}", service);
+                                if let Err(e) = self.generator.revoke_token(&old.token)
+                                {
+                                    warn!(
+                                        "Could not revoke old token for {}: {:#}",
+                                        service, e
+                                    );
+                                }
+                            }
+                        }
+                    }
                 }
                 Ok(cached)
             }
@@ -468,8 +1185,11 @@ pub struct Plugin {
     /// Optional because if we're being run as a `PluginGenerate`, we won't
     /// have it (but it's guaranteed otherwise).
     config: Option<Config>,
-    /// Our source of tokens.
+    /// A source of tokens to use instead of the backend configured for the
+    /// current target.
     generator: Option<Box<dyn GenerateToken>>,
+    /// Our Vault server, which we only connect to when a target needs it.
+    vault: OnceLock<Vault>,
 }
 
 impl Plugin {
@@ -493,8 +1213,126 @@ impl Plugin {
             config,
             generator: generator
                 .map(|gen: G| -> Box<dyn GenerateToken> { Box::new(gen) }),
+            vault: OnceLock::new(),
         })
     }
+
+    /// Get our Vault server, connecting to it if we haven't already.
+    fn vault(&self, target_config: &TargetConfig) -> Result<&Vault> {
+        if let Some(vault) = self.vault.get() {
+            return Ok(vault);
+        }
+        let vault = Vault::new(target_config.login.as_ref().unwrap_or(&Login::Token))?;
+        Ok(self.vault.get_or_init(|| vault))
+    }
+
+    /// Figure out where to get tokens for a target using `target_config`.
+    /// If we need a `LocalVault`, we store it in `local`.
+    fn generator<'a>(
+        &'a self,
+        project: &Project,
+        target_config: &TargetConfig,
+        local: &'a mut Option<LocalVault>,
+    ) -> Result<&'a dyn GenerateToken> {
+        if let Some(ref generator) = self.generator {
+            return Ok(generator.as_ref());
+        }
+        match target_config.backend.unwrap_or_default() {
+            Backend::Vault => Ok(self.vault(target_config)?),
+            Backend::Local => Ok(local
+                .insert(LocalVault::load(&target_config.local_store_path(project))?)),
+        }
+    }
+
+    /// List the tokens which we've issued to services in the current target,
+    /// including any which have expired.
+    pub(crate) fn cached_tokens(project: &Project) -> Result<Vec<CachedToken>> {
+        let target_dir = token_cache_dir(project, project.current_target());
+        let mut tokens = vec![];
+        for pod in project.pods() {
+            migrate_legacy_token_cache(project, pod)?;
+            let pod_tokens = load_cached_pod_tokens(&target_dir.join(pod.name()))?;
+            for (service, info) in pod_tokens {
+                tokens.push(CachedToken {
+                    pod: pod.name().to_owned(),
+                    service,
+                    policies: info.policies,
+                    expires: info.expires,
+                });
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Revoke the tokens which we've issued to services in the current
+    /// target, and remove them from our cache.  Returns the tokens which
+    /// were revoked.  Expired tokens are removed without contacting Vault.
+    /// During a dry run, we only return the tokens we would have revoked.
+    pub(crate) fn revoke_cached_tokens(
+        &self,
+        project: &Project,
+    ) -> Result<Vec<CachedToken>> {
+        let config = self.config.as_ref().ok_or_else(|| {
+            err!("{} does not exist", Self::config_path(project).display())
+        })?;
+        let target_config = config.target_config_for(project.current_target());
+
+        // Find all our cached tokens.
+        let target_dir = token_cache_dir(project, project.current_target());
+        let mut cached = vec![];
+        for pod in project.pods() {
+            migrate_legacy_token_cache(project, pod)?;
+            let pod_dir = target_dir.join(pod.name());
+            for (service, info) in load_cached_pod_tokens(&pod_dir)? {
+                let path = pod_dir.join(format!("{}.yml", service));
+                cached.push((pod.name().to_owned(), service, info, path));
+            }
+        }
+
+        // During a dry run, leave both Vault and our cache alone.
+        let now = SystemTime::now();
+        if project.dry_run() {
+            return Ok(cached
+                .into_iter()
+                .filter(|(_, _, info, _)| now < info.expires)
+                .map(|(pod, service, info, _)| CachedToken {
+                    pod,
+                    service,
+                    policies: info.policies,
+                    expires: info.expires,
+                })
+                .collect());
+        }
+
+        // Only connect to Vault if we actually have something to revoke.
+        let mut local = None;
+        let generator = if cached.iter().any(|(_, _, info, _)| now < info.expires) {
+            Some(self.generator(project, &target_config, &mut local)?)
+        } else {
+            None
+        };
+
+        let mut revoked = vec![];
+        for (pod, service, info, path) in cached {
+            if now < info.expires {
+                let generator = generator.expect("should have a token generator");
+                generator.revoke_token(&info.token).map_err(|e| {
+                    e.context(format!("could not revoke token for '{}'", service))
+                })?;
+                revoked.push(CachedToken {
+                    pod,
+                    service,
+                    policies: info.policies,
+                    expires: info.expires,
+                });
+            }
+            fs::remove_file(&path).map_err(|e| {
+                anyhow::Error::new(e)
+                    .context(format!("could not delete {}", path.display()))
+            })?;
+        }
+        Ok(revoked)
+    }
 }
 
 impl plugins::Plugin for Plugin {
@@ -514,15 +1352,9 @@ impl PluginNew for Plugin {
     }
 
     fn new(project: &Project) -> Result<Self> {
-        // An annoying special case.  We may be called as a code generator,
-        // in which case we don't want to try to create a `GenerateToken`
-        // instance.
-        let token_gen = if Self::is_configured_for(project)? {
-            Some(Vault::new()?)
-        } else {
-            None
-        };
-        Self::new_with_generator(project, token_gen)
+        // We don't know which backend we need until we know the current
+        // target, so we don't create a `GenerateToken` here.
+        Self::new_with_generator::<Vault>(project, None)
     }
 }
 
@@ -535,7 +1367,7 @@ impl PluginGenerate for Plugin {
 impl PluginTransform for Plugin {
     fn transform(
         &self,
-        _op: Operation,
+        op: Operation,
         ctx: &plugins::Context<'_>,
         file: &mut dc::File,
     ) -> Result<()> {
@@ -544,10 +1376,6 @@ impl PluginTransform for Plugin {
             .config
             .as_ref()
             .expect("config should always be present for transform");
-        let generator = self
-            .generator
-            .as_ref()
-            .expect("generator should always be present for transform");
 
         // Should this plugin be excluded in this target?
         let target = ctx.project.current_target();
@@ -555,9 +1383,16 @@ impl PluginTransform for Plugin {
             return Ok(());
         }
 
-        // Set up our token cache.
-        let mut cache =
-            TokenCache::load_or_create(ctx.project, ctx.pod, generator.as_ref())?;
+        // Figure out where to get our tokens.
+        let target_config = config.target_config_for(target);
+        let mut local = None;
+        let generator = self.generator(ctx.project, &target_config, &mut local)?;
+
+        // Set up our token cache, and a cache of the KV secrets we've read,
+        // because several services often need the same ones.
+        let mut cache = TokenCache::load_or_create(ctx.project, ctx.pod, generator)?;
+        let mut kv_cache: BTreeMap<(String, String, u8), BTreeMap<String, String>> =
+            BTreeMap::new();
 
         // Apply to each service.
         for (name, service) in &mut file.services {
@@ -578,62 +1413,191 @@ impl PluginTransform for Plugin {
                 .get(ctx.pod.name())
                 .and_then(|pod| pod.get(name));
 
-            // Get a list of policy "patterns" that apply to this service.
-            let target_config = config.target_config_for(target);
-            let mut raw_policies =
-                if service_config.map_or_else(|| false, |s| s.no_default_policies) {
-                    vec![]
-                } else {
-                    target_config.default_policies.clone().unwrap_or_default()
-                };
-            raw_policies
-                .extend(service_config.map_or_else(Vec::new, |s| s.policies.clone()));
-
-            // If we have no raw policies, do nothing for this service.
-            if raw_policies.is_empty() {
-                debug!(
-                    "Skipping token generation for {} because it has no policies",
-                    name
-                );
-                continue;
+            let auth_type = service_config
+                .and_then(|s| s.auth_type)
+                .unwrap_or(config.auth_type);
+            let issued_credentials = match auth_type {
+                AuthType::Token => {
+                    // Get a list of policy "patterns" that apply to this
+                    // service.
+                    let mut raw_policies = if service_config
+                        .map_or_else(|| false, |s| s.no_default_policies)
+                    {
+                        vec![]
+                    } else {
+                        target_config.default_policies.clone().unwrap_or_default()
+                    };
+                    raw_policies.extend(
+                        service_config.map_or_else(Vec::new, |s| s.policies.clone()),
+                    );
+
+                    // If we have no raw policies, don't issue a token.
+                    if raw_policies.is_empty() {
+                        debug!(
+                            "Skipping token generation for {} because it has no \
+                             policies",
+                            name
+                        );
+                        false
+                    } else {
+                        // Interpolate the variables found in our policy patterns.
+                        let mut policies = BTreeSet::new();
+                        for result in raw_policies.iter().map(&interpolated) {
+                            // We'd like to use std::result::fold here but it's
+                            // unstable.
+                            policies.insert(result?);
+                        }
+                        debug!(
+                            "Generating token for '{}' with policies {:?}",
+                            name, &policies
+                        );
+
+                        // Insert our VAULT_ADDR value into the generated files.
+                        service.environment.insert(
+                            "VAULT_ADDR".to_owned(),
+                            dc::escape(generator.addr())?,
+                        );
+
+                        // Generate a VAULT_TOKEN.
+                        let ttl = Duration::from_secs(
+                            target_config.default_ttl.unwrap_or(DEFAULT_TTL),
+                        );
+                        let token_info = cache.get(
+                            ctx.project.name(),
+                            ctx.project.current_target().name(),
+                            ctx.pod.name(),
+                            name,
+                            &policies,
+                            ttl,
+                        )?;
+                        service.environment.insert(
+                            "VAULT_TOKEN".to_owned(),
+                            dc::escape(&token_info.token)?,
+                        );
+                        true
+                    }
+                }
+                AuthType::WrappedSecretId => {
+                    // Placeholder services can opt out just like they do for
+                    // tokens.
+                    let approle = service_config.and_then(|s| s.approle.as_ref());
+                    let opted_out =
+                        service_config.is_some_and(|s| s.no_default_policies);
+                    if opted_out && approle.is_none() {
+                        debug!("Skipping secret ID generation for {}", name);
+                        false
+                    } else if op == Operation::Export
+                        || !CONTAINER_CREATING_SUBCOMMANDS
+                            .contains(&ctx.subcommand.as_str())
+                    {
+                        // Nobody will start a container using these files, so
+                        // a single-use secret ID would just be wasted.
+                        debug!(
+                            "Not generating secret ID for {} during {}",
+                            name, ctx.subcommand
+                        );
+                        true
+                    } else if ctx.project.dry_run() {
+                        println!("Would generate a Vault secret ID for {}", name);
+                        true
+                    } else {
+                        let role = match approle {
+                            Some(approle) => interpolated(approle)?,
+                            None => format!(
+                                "{}-{}-{}-{}",
+                                ctx.project.name(),
+                                target.name(),
+                                ctx.pod.name(),
+                                name
+                            ),
+                        };
+                        debug!(
+                            "Generating wrapped secret ID for '{}' using {}",
+                            name, role
+                        );
+
+                        // Wrapped secret IDs can only be used once, so we never
+                        // cache them.
+                        let wrap_ttl = Duration::from_secs(
+                            target_config.wrap_ttl.unwrap_or(DEFAULT_WRAP_TTL),
+                        );
+                        let mount = target_config
+                            .approle_mount
+                            .as_deref()
+                            .unwrap_or(DEFAULT_APPROLE_MOUNT);
+                        let secret_id = generator
+                            .generate_wrapped_secret_id(mount, &role, wrap_ttl)
+                            .map_err(|e| {
+                                e.context(format!(
+                                    "could not generate secret ID for '{}'",
+                                    name
+                                ))
+                            })?;
+                        service.environment.insert(
+                            "VAULT_ADDR".to_owned(),
+                            dc::escape(generator.addr())?,
+                        );
+                        service.environment.insert(
+                            "VAULT_ROLE_ID".to_owned(),
+                            dc::escape(&secret_id.role_id)?,
+                        );
+                        service.environment.insert(
+                            "VAULT_WRAPPED_SECRET_ID".to_owned(),
+                            dc::escape(&secret_id.wrapping_token)?,
+                        );
+                        true
+                    }
+                }
+            };
+
+            // Add in any extra environment variables, but only for services
+            // which talk to Vault.
+            if issued_credentials {
+                for (var, val) in &target_config.extra_environment {
+                    service
+                        .environment
+                        .insert(var.to_owned(), dc::escape(interpolated(val)?)?);
+                }
             }
 
-            // Interpolate the variables found in our policy patterns.
-            let mut policies = BTreeSet::new();
-            for result in raw_policies.iter().map(&interpolated) {
-                // We'd like to use std::result::fold here but it's unstable.
-                policies.insert(result?);
+            // Look up any KV secrets that belong in our environment.  These
+            // also work for services which never talk to Vault themselves, but
+            // we don't export them unless asked.
+            if op == Operation::Export && !ctx.project.export_secrets() {
+                continue;
             }
-            debug!(
-                "Generating token for '{}' with policies {:?}",
-                name, &policies
+            let mut secrets = target_config.secrets.clone();
+            secrets.extend(
+                service_config.map_or_else(BTreeMap::new, |s| s.secrets.clone()),
             );
-
-            // Insert our VAULT_ADDR value into the generated files.
-            service
-                .environment
-                .insert("VAULT_ADDR".to_owned(), dc::escape(generator.addr())?);
-
-            // Generate a VAULT_TOKEN.
-            let ttl =
-                Duration::from_secs(target_config.default_ttl.unwrap_or(DEFAULT_TTL));
-            let token_info = cache.get(
-                ctx.project.name(),
-                ctx.project.current_target().name(),
-                ctx.pod.name(),
-                name,
-                &policies,
-                ttl,
-            )?;
-            service
-                .environment
-                .insert("VAULT_TOKEN".to_owned(), dc::escape(&token_info.token)?);
-
-            // Add in any extra environment variables.
-            for (var, val) in &target_config.extra_environment {
+            for (var, secret) in &secrets {
+                let mount = secret.mount.as_deref().unwrap_or("secret").to_owned();
+                let path = interpolated(&secret.path)?;
+                let kv_version = secret.kv_version.unwrap_or(2);
+                let mkerr = |e: anyhow::Error| {
+                    e.context(format!(
+                        "could not read {} for '{}' from {}/{}",
+                        var, name, mount, path
+                    ))
+                };
+                if kv_version != 1 && kv_version != 2 {
+                    return Err(mkerr(err!("kv_version must be 1 or 2")));
+                }
+                let data =
+                    match kv_cache.entry((mount.clone(), path.clone(), kv_version)) {
+                        Entry::Occupied(occupied) => occupied.into_mut(),
+                        Entry::Vacant(vacancy) => vacancy.insert(
+                            generator
+                                .read_secret(&mount, &path, kv_version)
+                                .map_err(mkerr)?,
+                        ),
+                    };
+                let value = data
+                    .get(&secret.key)
+                    .ok_or_else(|| mkerr(err!("no key named {}", secret.key)))?;
                 service
                     .environment
-                    .insert(var.to_owned(), dc::escape(interpolated(val)?)?);
+                    .insert(var.to_owned(), dc::escape(value)?);
             }
         }
 
@@ -666,6 +1630,10 @@ mod test {
         /// so that we can have "interior" mutability, because we don't want
         /// `generate_token` to be `&mut self` in the general case.
         calls: MockVaultCalls,
+        /// The tokens we were asked to renew.
+        renewed: Arc<RwLock<Vec<String>>>,
+        /// The tokens we were asked to revoke.
+        revoked: Arc<RwLock<Vec<String>>>,
     }
 
     #[cfg(test)]
@@ -674,6 +1642,8 @@ mod test {
         fn new() -> MockVault {
             MockVault {
                 calls: Arc::new(RwLock::new(vec![])),
+                renewed: Arc::new(RwLock::new(vec![])),
+                revoked: Arc::new(RwLock::new(vec![])),
             }
         }
 
@@ -706,6 +1676,37 @@ mod test {
                 expires: SystemTime::now() + ttl,
             })
         }
+
+        fn renew_token(&self, token: &str, ttl: Duration) -> Result<SystemTime> {
+            self.renewed.write().unwrap().push(token.to_owned());
+            Ok(SystemTime::now() + ttl)
+        }
+
+        fn revoke_token(&self, token: &str) -> Result<()> {
+            self.revoked.write().unwrap().push(token.to_owned());
+            Ok(())
+        }
+
+        fn generate_wrapped_secret_id(
+            &self,
+            mount: &str,
+            role: &str,
+            _wrap_ttl: Duration,
+        ) -> Result<WrappedSecretId> {
+            Ok(WrappedSecretId {
+                role_id: format!("{}/{}_role_id", mount, role),
+                wrapping_token: "fake_wrapping_token".to_owned(),
+            })
+        }
+
+        fn read_secret(
+            &self,
+            mount: &str,
+            path: &str,
+            _kv_version: u8,
+        ) -> Result<BTreeMap<String, String>> {
+            Err(err!("no secret at {}/{}", mount, path))
+        }
     }
 
     #[test]
@@ -741,6 +1742,142 @@ mod test {
         assert!(token_info.should_renew(&desired_policies, Duration::from_secs(60)));
     }
 
+    #[test]
+    fn renews_cached_tokens_instead_of_reissuing_them() {
+        /// Get a token for `frontend/web`.
+        fn get(cache: &mut TokenCache<'_>, policies: &BTreeSet<String>) -> TokenInfo {
+            let ttl = Duration::from_secs(60);
+            cache
+                .get("proj", "development", "frontend", "web", policies, ttl)
+                .unwrap()
+                .to_owned()
+        }
+
+        let _ = env_logger::try_init();
+
+        let proj = Project::from_example("vault_integration").unwrap();
+        let frontend = proj.pod("frontend").unwrap();
+        let vault = MockVault::new();
+        let policies = BTreeSet::from_iter(vec!["a".to_owned()]);
+        let ttl = Duration::from_secs(60);
+        // Our first token is issued and saved.
+        let mut cache = TokenCache::load_or_create(&proj, frontend, &vault).unwrap();
+        get(&mut cache, &policies);
+        cache.save().unwrap();
+        assert_eq!(vault.calls.read().unwrap().len(), 1);
+
+        // Past its half-life, it's renewed.
+        let mut cache = TokenCache::load_or_create(&proj, frontend, &vault).unwrap();
+        cache.cached.get_mut("web").unwrap().expires =
+            SystemTime::now() + Duration::from_secs(10);
+        let info = get(&mut cache, &policies);
+        assert!(!info.should_renew(&policies, ttl));
+        assert_eq!(vault.calls.read().unwrap().len(), 1);
+        assert_eq!(
+            *vault.renewed.read().unwrap(),
+            vec!["fake_token".to_owned()]
+        );
+
+        // But if its policies change, we need a new one, and we revoke the
+        // old one.
+        let new_policies = BTreeSet::from_iter(vec!["b".to_owned()]);
+        get(&mut cache, &new_policies);
+        assert_eq!(vault.calls.read().unwrap().len(), 2);
+        assert_eq!(vault.renewed.read().unwrap().len(), 1);
+        assert_eq!(
+            *vault.revoked.read().unwrap(),
+            vec!["fake_token".to_owned()]
+        );
+
+        // During a dry run, we leave Vault alone.
+        cache.dry_run = true;
+        get(&mut cache, &policies);
+        assert_eq!(vault.calls.read().unwrap().len(), 2);
+        assert_eq!(vault.renewed.read().unwrap().len(), 1);
+        assert_eq!(vault.revoked.read().unwrap().len(), 1);
+
+        proj.remove_test_output().unwrap();
+    }
+
+    #[test]
+    fn lists_and_revokes_cached_tokens() {
+        let _ = env_logger::try_init();
+
+        let mut proj = Project::from_example("vault_integration").unwrap();
+        proj.set_current_target_name("development").unwrap();
+        let vault = MockVault::new();
+        let revoked = vault.revoked.clone();
+        let plugin = Plugin::new_with_generator(&proj, Some(vault)).unwrap();
+
+        let frontend = proj.pod("frontend").unwrap();
+        let ctx = plugins::Context::new(&proj, frontend, "up");
+        let mut file = frontend.merged_file(proj.current_target()).unwrap();
+        plugin
+            .transform(Operation::Output, &ctx, &mut file)
+            .unwrap();
+
+        let tokens = Plugin::cached_tokens(&proj).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].pod, "frontend");
+        assert_eq!(tokens[0].service, "web");
+        assert!(tokens[0].expires > SystemTime::now());
+
+        // A dry run doesn't revoke or forget anything.
+        proj.set_dry_run(true);
+        let revoked_tokens = plugin.revoke_cached_tokens(&proj).unwrap();
+        assert_eq!(revoked_tokens.len(), 1);
+        assert!(revoked.read().unwrap().is_empty());
+        assert_eq!(Plugin::cached_tokens(&proj).unwrap().len(), 1);
+        proj.set_dry_run(false);
+
+        let revoked_tokens = plugin.revoke_cached_tokens(&proj).unwrap();
+        assert_eq!(revoked_tokens.len(), 1);
+        assert_eq!(*revoked.read().unwrap(), vec!["fake_token".to_owned()]);
+        assert!(Plugin::cached_tokens(&proj).unwrap().is_empty());
+
+        proj.remove_test_output().unwrap();
+    }
+
+    #[test]
+    fn migrates_legacy_token_caches() {
+        let _ = env_logger::try_init();
+
+        let mut proj = Project::from_example("vault_integration").unwrap();
+        proj.set_current_target_name("development").unwrap();
+        let expires = SystemTime::now() + Duration::from_secs(3600);
+        let info = TokenInfo {
+            token: "legacy_token".to_owned(),
+            policies: BTreeSet::from_iter(vec!["a".to_owned()]),
+            expires,
+        };
+        let legacy_path = proj.output_dir().join("vault-cache").join("frontend.yml");
+        let mut services = BTreeMap::new();
+        services.insert("web".to_owned(), info);
+        let mut pods = BTreeMap::new();
+        pods.insert("frontend".to_owned(), services);
+        let mut targets = BTreeMap::new();
+        targets.insert("development".to_owned(), pods.clone());
+        targets.insert("production".to_owned(), pods);
+        dump_yaml(&legacy_path, &json!({ "targets": targets })).unwrap();
+
+        // Our legacy tokens are moved to the new cache for every target.
+        let tokens = Plugin::cached_tokens(&proj).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].service, "web");
+        assert!(!legacy_path.exists());
+        let production = token_cache_dir(&proj, proj.target("production").unwrap());
+        assert!(production.join("frontend").join("web.yml").exists());
+
+        // ...where `cage vault revoke` can find them.
+        let vault = MockVault::new();
+        let revoked = vault.revoked.clone();
+        let plugin = Plugin::new_with_generator(&proj, Some(vault)).unwrap();
+        plugin.revoke_cached_tokens(&proj).unwrap();
+        assert_eq!(*revoked.read().unwrap(), vec!["legacy_token".to_owned()]);
+
+        proj.remove_test_output().unwrap();
+    }
+
     #[test]
     fn interpolates_policies() {
         let _ = env_logger::try_init();
@@ -818,4 +1955,103 @@ mod test {
         let web = file.services.get("web").unwrap();
         assert_eq!(web.environment.get("VAULT_ADDR"), None);
     }
+
+    #[test]
+    fn local_backend_issues_fake_tokens_and_serves_secrets() {
+        let _ = env_logger::try_init();
+
+        let mut proj = Project::from_fixture("with_local_vault").unwrap();
+        proj.set_current_target_name("development").unwrap();
+        let plugin = Plugin::new(&proj).unwrap();
+
+        let frontend = proj.pod("frontend").unwrap();
+        let ctx = plugins::Context::new(&proj, frontend, "up");
+        let mut file = frontend.merged_file(proj.current_target()).unwrap();
+        plugin
+            .transform(Operation::Output, &ctx, &mut file)
+            .unwrap();
+        let web = file.services.get("web").unwrap();
+        let vault_addr = web.environment.get("VAULT_ADDR").expect("has VAULT_ADDR");
+        assert_eq!(vault_addr.value().unwrap(), "http://vault.local:8200");
+        let vault_token = web.environment.get("VAULT_TOKEN").expect("has VAULT_TOKEN");
+        assert_eq!(
+            vault_token.value().unwrap(),
+            "local.withlocalvault_development_frontend_web"
+        );
+        let password = web
+            .environment
+            .get("DATABASE_PASSWORD")
+            .expect("has DATABASE_PASSWORD");
+        assert_eq!(password.value().unwrap(), "not very secret");
+        let api_key = web.environment.get("API_KEY").expect("has API_KEY");
+        assert_eq!(api_key.value().unwrap(), "abc123");
+
+        let worker = file.services.get("worker").unwrap();
+        let role_id = worker
+            .environment
+            .get("VAULT_ROLE_ID")
+            .expect("has role ID");
+        assert_eq!(
+            role_id.value().unwrap(),
+            "local.role_id.withlocalvault-development-frontend-worker"
+        );
+        let secret_id = worker
+            .environment
+            .get("VAULT_WRAPPED_SECRET_ID")
+            .expect("has wrapped secret ID");
+        assert_eq!(
+            secret_id.value().unwrap(),
+            "local.wrapped_secret_id.withlocalvault-development-frontend-worker"
+        );
+        assert!(worker.environment.get("VAULT_TOKEN").is_none());
+        assert!(worker.environment.get("DATABASE_PASSWORD").is_some());
+        assert!(worker.environment.get("API_KEY").is_none());
+
+        // We only generate single-use secret IDs for commands which create
+        // containers.
+        let status_ctx = plugins::Context::new(&proj, frontend, "status");
+        let mut status_file = frontend.merged_file(proj.current_target()).unwrap();
+        plugin
+            .transform(Operation::Output, &status_ctx, &mut status_file)
+            .unwrap();
+        let worker = status_file.services.get("worker").unwrap();
+        assert!(worker.environment.get("VAULT_WRAPPED_SECRET_ID").is_none());
+
+        // Services without Vault credentials still get their KV secrets.
+        let proxy = file.services.get("proxy").unwrap();
+        assert!(proxy.environment.get("VAULT_ADDR").is_none());
+        assert!(proxy.environment.get("VAULT_TOKEN").is_none());
+        let api_key = proxy.environment.get("API_KEY").expect("has API_KEY");
+        assert_eq!(api_key.value().unwrap(), "abc123");
+
+        // But we don't export KV secrets unless we're asked to.
+        let mut file = frontend.merged_file(proj.current_target()).unwrap();
+        plugin
+            .transform(Operation::Export, &ctx, &mut file)
+            .unwrap();
+        for service in file.services.values() {
+            assert!(service.environment.get("DATABASE_PASSWORD").is_none());
+            assert!(service.environment.get("API_KEY").is_none());
+            assert!(service.environment.get("VAULT_WRAPPED_SECRET_ID").is_none());
+        }
+
+        let config = plugin.config.as_ref().unwrap();
+        let target_config = config.target_config_for(proj.current_target());
+        assert_eq!(target_config.backend, Some(Backend::Local));
+        assert_eq!(target_config.login, None);
+        let local = LocalVault::load(&target_config.local_store_path(&proj)).unwrap();
+        let secret = local
+            .read_secret("secret", "withlocalvault/database", 2)
+            .unwrap();
+        assert_eq!(secret["password"], "not very secret");
+        assert!(local.read_secret("secret", "missing", 2).is_err());
+
+        // Production uses a real Vault server, and logs in using AppRole.
+        proj.set_current_target_name("production").unwrap();
+        let target_config = config.target_config_for(proj.current_target());
+        assert_eq!(target_config.backend, Some(Backend::Vault));
+        assert!(matches!(target_config.login, Some(Login::AppRole { .. })));
+
+        proj.remove_test_output().unwrap();
+    }
 }
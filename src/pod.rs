@@ -7,6 +7,7 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsString;
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::args;
 use crate::cmd::CommandRun;
@@ -48,6 +49,41 @@ impl fmt::Display for PodType {
     }
 }
 
+/// What should we wait for before treating a pod as ready to be
+/// initialized?
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WaitFor {
+    /// Wait until all of the pod's containers are running.
+    #[default]
+    #[serde(rename = "running")]
+    Running,
+
+    /// Wait until all of the pod's containers pass their Docker
+    /// `HEALTHCHECK`.  Containers without a health check only need to be
+    /// running.
+    #[serde(rename = "healthy")]
+    Healthy,
+
+    /// Wait until all of the pod's published TCP ports accept connections.
+    #[serde(rename = "port")]
+    Port,
+}
+
+/// In addition to serde serialization, also provide basic formatting.
+impl fmt::Display for WaitFor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            WaitFor::Running => write!(f, "running"),
+            WaitFor::Healthy => write!(f, "healthy"),
+            WaitFor::Port => write!(f, "listening on its ports"),
+        }
+    }
+}
+
+/// How long we wait for a pod to become ready if `wait_timeout` isn't
+/// specified.
+const DEFAULT_WAIT_TIMEOUT_SECS: u64 = 300;
+
 /// Configuration information about a pod.
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -59,6 +95,24 @@ struct Config {
     /// What kind of pod is this?
     pod_type: Option<PodType>,
 
+    /// Other pods which must be started before this one, and stopped after
+    /// it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    depends_on_pods: Vec<String>,
+
+    /// What should we wait for before running `run_on_init`?
+    wait_for: Option<WaitFor>,
+
+    /// How many seconds should we wait for this pod to become ready before
+    /// giving up?
+    wait_timeout: Option<u64>,
+
+    /// Extra keys to merge into this pod's output when the project uses
+    /// `compose_format: "spec"`, for features like `healthcheck` and
+    /// `deploy` which aren't part of the version 2 format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compose_spec: Option<serde_yaml::Value>,
+
     /// A list of commands to invoke with `cage run` when this pod is
     /// initialized.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -325,6 +379,31 @@ impl Pod {
         self.config.pod_type.unwrap_or(PodType::Service)
     }
 
+    /// The names of the pods which must be started before this one.
+    pub fn depends_on_pods(&self) -> &[String] {
+        &self.config.depends_on_pods
+    }
+
+    /// Extra keys to merge into this pod's output in Compose Specification
+    /// format.
+    pub fn compose_spec(&self) -> Option<&serde_yaml::Value> {
+        self.config.compose_spec.as_ref()
+    }
+
+    /// What should we wait for before treating this pod as ready?
+    pub fn wait_for(&self) -> WaitFor {
+        self.config.wait_for.unwrap_or_default()
+    }
+
+    /// How long should we wait for this pod to become ready?
+    pub fn wait_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.config
+                .wait_timeout
+                .unwrap_or(DEFAULT_WAIT_TIMEOUT_SECS),
+        )
+    }
+
     /// Get the names of the services declared in this pod.
     pub fn service_names(&self) -> &BTreeSet<String> {
         &self.service_names
@@ -552,3 +631,21 @@ fn pod_type_returns_type_of_pod() {
     let rake = proj.pod("rake").unwrap();
     assert_eq!(rake.pod_type(), PodType::Task);
 }
+
+#[test]
+fn wait_for_and_wait_timeout_are_read_from_metadata() {
+    let _ = env_logger::try_init();
+    let proj: Project = Project::from_example("rails_hello").unwrap();
+    let db = proj.pod("db").unwrap();
+    assert_eq!(db.wait_for(), WaitFor::Running);
+    assert_eq!(db.wait_timeout(), Duration::from_secs(120));
+    let frontend = proj.pod("frontend").unwrap();
+    assert_eq!(
+        frontend.wait_timeout(),
+        Duration::from_secs(DEFAULT_WAIT_TIMEOUT_SECS)
+    );
+
+    let proj: Project = Project::from_fixture("with_pod_dependencies").unwrap();
+    let queue = proj.pod("queue").unwrap();
+    assert_eq!(queue.wait_for(), WaitFor::Healthy);
+}
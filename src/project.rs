@@ -4,6 +4,7 @@
 use faraday_compose_yml::v2 as dc;
 use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
+use std::collections::BTreeSet;
 use std::env;
 use std::fs;
 use std::io;
@@ -16,13 +17,20 @@ use std::result;
 use std::slice;
 use std::str;
 
+use crate::command_runner::{
+    CaptureOutput, Command, CommandRunner, DryRunCommandRunner, OsCommandRunner,
+};
+use crate::compose_backend::{self, ComposeBackend};
+use crate::compose_spec;
 use crate::dir;
 use crate::errors::*;
 use crate::hook::HookManager;
+use crate::k8s;
+use crate::plugins::transform::secrets;
 use crate::plugins::{self, Operation};
 use crate::pod::{Pod, PodType};
 use crate::runtime_state::RuntimeState;
-use crate::serde_helpers::deserialize_parsable_opt;
+use crate::serde_helpers::{deserialize_parsable_opt, dump_yaml};
 use crate::service_locations::ServiceLocations;
 use crate::sources::Sources;
 use crate::target::Target;
@@ -48,12 +56,40 @@ pub struct ProjectConfig {
     #[serde(default, deserialize_with = "deserialize_parsable_opt")]
     pub cage_version: Option<semver::VersionReq>,
 
+    /// Should we run `"docker-compose"` or `"docker compose"`, or talk to
+    /// Docker directly using `"engine-api"`?  Defaults to `"auto"`, which
+    /// prefers `docker-compose` if it's installed.  May be overridden using
+    /// `CAGE_COMPOSE_BACKEND`.
+    #[serde(default, deserialize_with = "compose_backend::deserialize_choice")]
+    pub compose_backend: Option<ComposeBackend>,
+
+    /// Which `docker-compose.yml` format should we output?
+    #[serde(default)]
+    pub compose_format: ComposeFormat,
+
     /// Ensure that this struct has at least one private field so we
     /// can extend it in the future.
     #[serde(default, skip_deserializing)]
     _phantom: PhantomData<()>,
 }
 
+/// The file formats which we can use when outputting pods for
+/// `docker-compose`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ComposeFormat {
+    /// The legacy `version: "2"` format.
+    #[default]
+    #[serde(rename = "v2")]
+    V2,
+
+    /// The [Compose Specification][spec], as used by modern versions of
+    /// `docker compose`.
+    ///
+    /// [spec]: https://compose-spec.io/
+    #[serde(rename = "spec")]
+    Spec,
+}
+
 impl ProjectConfig {
     /// Load a config file from the specified path.
     pub fn new(path: &Path) -> Result<Self> {
@@ -159,6 +195,13 @@ pub enum PodOrService<'a> {
 }
 
 impl<'a> PodOrService<'a> {
+    /// Get either our `Pod` or the pod containing our service.
+    pub fn pod(&self) -> &'a Pod {
+        match *self {
+            PodOrService::Pod(pod) | PodOrService::Service(pod, _) => pod,
+        }
+    }
+
     /// Get the `pod_type` for either our `Pod` or the pod containing our
     /// service.
     pub fn pod_type(&self) -> PodType {
@@ -208,7 +251,19 @@ pub struct Project {
     hooks: HookManager,
 
     /// The main configuration for this project.
-    _config: ProjectConfig,
+    config: ProjectConfig,
+
+    /// The tool we use to run `docker-compose` commands.
+    compose_backend: ComposeBackend,
+
+    /// How many pods may we start or stop at the same time?
+    jobs: usize,
+
+    /// Are we only printing commands instead of running them?
+    dry_run: bool,
+
+    /// Should `export` include secrets from `config/secrets.yml.age`?
+    export_secrets: bool,
 
     /// Docker image tags to use for images that don't have them.
     /// Typically used to lock down versions supplied by a CI system.
@@ -237,6 +292,7 @@ impl Project {
         let sources = Sources::new(root_dir, output_dir, &pods)?;
         let config_path = root_dir.join(PROJECT_CONFIG_PATH.deref());
         let config = ProjectConfig::new(&config_path)?;
+        let compose_backend = ComposeBackend::detect(config.compose_backend)?;
         let absolute_root = root_dir.to_absolute()?;
         let name = absolute_root
             .file_name()
@@ -255,7 +311,11 @@ impl Project {
             current_target,
             sources,
             hooks: HookManager::new(root_dir)?,
-            _config: config,
+            config,
+            compose_backend,
+            jobs: 1,
+            dry_run: false,
+            export_secrets: false,
             default_tags: None,
             plugins: None,
         };
@@ -292,7 +352,10 @@ impl Project {
         let root_dir = Path::new("examples").join(name);
         let rand_name = format!("{}-{}", name, id);
         let test_output = Path::new("target/test_output").join(&rand_name);
-        Project::from_dirs(&root_dir, &test_output.join("src"), &test_output)
+        let mut proj =
+            Project::from_dirs(&root_dir, &test_output.join("src"), &test_output)?;
+        proj.set_compose_backend(ComposeBackend::DockerCompose);
+        Ok(proj)
     }
 
     /// (Tests only.) Create a `Project` from a subdirectory of `tests/fixtures`,
@@ -303,7 +366,10 @@ impl Project {
         let root_dir = Path::new("tests/fixtures").join(name);
         let rand_name = format!("{}-{}", name, random::<u16>());
         let test_output = Path::new("target/test_output").join(&rand_name);
-        Project::from_dirs(&root_dir, &test_output.join("src"), &test_output)
+        let mut proj =
+            Project::from_dirs(&root_dir, &test_output.join("src"), &test_output)?;
+        proj.set_compose_backend(ComposeBackend::DockerCompose);
+        Ok(proj)
     }
 
     /// (Tests only.) Remove our output directory after a test.
@@ -347,7 +413,52 @@ impl Project {
         // Make sure placeholders are before other pods.  This is necessary
         // for `up` to run things in the right order.
         pods.sort_by_key(|p| (p.pod_type(), p.name().to_owned()));
-        Ok(pods)
+        Project::sort_pods_by_dependencies(pods)
+    }
+
+    /// Reorder `pods` so that each pod appears after all the pods listed in
+    /// its `depends_on_pods`.  Pods without any ordering constraints between
+    /// them keep their existing relative order, so placeholders will still
+    /// come first unless a dependency says otherwise.
+    fn sort_pods_by_dependencies(pods: Vec<Pod>) -> Result<Vec<Pod>> {
+        // Make sure every dependency actually exists.
+        let names: BTreeSet<&str> = pods.iter().map(|p| p.name()).collect();
+        for pod in &pods {
+            for dep in pod.depends_on_pods() {
+                if !names.contains(dep.as_str()) {
+                    return Err(Error::UnknownPodDependency {
+                        pod: pod.name().to_owned(),
+                        dependency: dep.to_owned(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        // Repeatedly move the first pod whose dependencies have all been
+        // placed.  This is quadratic, but we only have a handful of pods.
+        let mut remaining = pods;
+        let mut sorted: Vec<Pod> = Vec::with_capacity(remaining.len());
+        let mut placed: BTreeSet<String> = BTreeSet::new();
+        while !remaining.is_empty() {
+            let ready = remaining.iter().position(|pod| {
+                pod.depends_on_pods().iter().all(|dep| placed.contains(dep))
+            });
+            match ready {
+                Some(idx) => {
+                    let pod = remaining.remove(idx);
+                    placed.insert(pod.name().to_owned());
+                    sorted.push(pod);
+                }
+                None => {
+                    return Err(Error::PodDependencyCycle(find_dependency_cycle(
+                        &remaining, &placed,
+                    ))
+                    .into())
+                }
+            }
+        }
+        Ok(sorted)
     }
 
     /// The name of this project.  This defaults to the name of the current
@@ -363,6 +474,77 @@ impl Project {
         self
     }
 
+    /// The main configuration for this project, from `config/project.yml`.
+    pub fn config(&self) -> &ProjectConfig {
+        &self.config
+    }
+
+    /// The tool we use to run `docker-compose` commands.
+    pub fn compose_backend(&self) -> ComposeBackend {
+        self.compose_backend
+    }
+
+    /// Override the tool we use to run `docker-compose` commands.
+    pub fn set_compose_backend(&mut self, backend: ComposeBackend) {
+        self.compose_backend = backend;
+    }
+
+    /// How many pods may we start or stop at the same time?  Defaults to
+    /// 1, which runs everything in order with unbuffered output.
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    /// Set how many pods we may start or stop at the same time.
+    pub fn set_jobs(&mut self, jobs: usize) -> &mut Project {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Are we only printing commands instead of running them?  Defaults to
+    /// `false`.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Only print commands instead of running them.  We can't print Docker
+    /// Engine API calls, so this also switches the Engine API backend to
+    /// the equivalent `docker compose` commands.
+    pub fn set_dry_run(&mut self, dry_run: bool) -> &mut Project {
+        self.dry_run = dry_run;
+        if dry_run && self.compose_backend.is_engine_api() {
+            warn!(
+                "Showing `docker compose` commands instead of Docker Engine API calls"
+            );
+            self.compose_backend = ComposeBackend::DockerComposePlugin;
+        }
+        self
+    }
+
+    /// Should `export` write out the decrypted contents of
+    /// `config/secrets.yml.age`?  Defaults to `false`.
+    pub fn export_secrets(&self) -> bool {
+        self.export_secrets
+    }
+
+    /// Allow `export` to write out decrypted secrets.
+    pub fn set_export_secrets(&mut self, export_secrets: bool) -> &mut Project {
+        self.export_secrets = export_secrets;
+        self
+    }
+
+    /// Build a `docker-compose` command for the specified pod, using our
+    /// current backend.  Callers should add a subcommand and its
+    /// arguments.
+    pub fn compose_command<CR>(&self, runner: &CR, pod: &Pod) -> Result<CR::Command>
+    where
+        CR: CommandRunner,
+    {
+        let mut cmd = self.compose_backend.build(runner);
+        cmd.args(&pod.compose_args(self)?);
+        Ok(cmd)
+    }
+
     /// Get that name that `docker_compose` would use for this project.
     pub fn compose_name(&self) -> String {
         self.current_target.compose_project_name(self)
@@ -420,6 +602,43 @@ impl Project {
         self.pods().find(|pod| pod.name() == name)
     }
 
+    /// The position of `pod` in our dependency-sorted list of pods.  Pods
+    /// with lower positions should be started first.
+    pub fn pod_position(&self, pod: &Pod) -> usize {
+        self.pods
+            .iter()
+            .position(|p| p.name() == pod.name())
+            .expect("pod should belong to this project")
+    }
+
+    /// Which stage of a parallel operation does `pod` belong to?  Each pod
+    /// must wait for the pods it `depends_on_pods`, and for any earlier pod
+    /// of an earlier `PodType`, so that placeholders still start before
+    /// services.  Pods in the same stage may be processed at the same time.
+    pub fn pod_stage(&self, pod: &Pod) -> usize {
+        let mut stages: Vec<usize> = Vec::with_capacity(self.pods.len());
+        for (i, current) in self.pods.iter().enumerate() {
+            let stage = self.pods[..i]
+                .iter()
+                .zip(&stages)
+                .filter(|&(earlier, _)| {
+                    earlier.pod_type() < current.pod_type()
+                        || current
+                            .depends_on_pods()
+                            .iter()
+                            .any(|d| d == earlier.name())
+                })
+                .map(|(_, &stage)| stage + 1)
+                .max()
+                .unwrap_or(0);
+            if current.name() == pod.name() {
+                return stage;
+            }
+            stages.push(stage);
+        }
+        0
+    }
+
     /// Look up the named service.  Returns the pod containing the service
     /// and the name of the service within that pod.
     pub fn service(&self, name: &str) -> Option<(&Pod, &str)> {
@@ -545,14 +764,42 @@ impl Project {
         Ok(result)
     }
 
+    /// Combine a pod's targets, make it standalone, and tweak it as needed
+    /// using our plugins.  Plugins use `runner` to run any commands they
+    /// need.
+    pub(crate) fn processed_file(
+        &self,
+        runner: &dyn CaptureOutput,
+        op: Operation,
+        subcommand: &str,
+        pod: &Pod,
+    ) -> Result<dc::File> {
+        let mut file = pod.merged_file(&self.current_target)?;
+        file.make_standalone(&self.pods_dir())?;
+        let ctx = plugins::Context::with_runner(self, pod, subcommand, runner);
+        self.plugins().transform(op, &ctx, &mut file)?;
+        Ok(file)
+    }
+
     /// Process our pods, flattening and transforming them using our
     /// plugins, and output them to the specified directory.
     fn output_helper(
         &self,
+        runner: &(dyn CaptureOutput + Sync),
         op: Operation,
         subcommand: &str,
         export_dir: &Path,
     ) -> Result<()> {
+        // Version 2 files can't contain the keys in `compose_spec`.
+        if self.config.compose_format == ComposeFormat::V2 {
+            for pod in self.pods.iter().filter(|p| p.compose_spec().is_some()) {
+                warn!(
+                    "Ignoring compose_spec for pod {} because compose_format is not \"spec\"",
+                    pod.name()
+                );
+            }
+        }
+
         // Output each pod.  This isn't especially slow (except maybe the
         // Vault plugin), but parallelizing things is easy.
         self.pods
@@ -576,13 +823,24 @@ impl Project {
                 let out_path = export_dir.join(&rel_path).with_guaranteed_parent()?;
                 debug!("Outputting {}", out_path.display());
 
-                // Combine targets, make it standalone, tweak as needed, and
-                // output.
-                let mut file = pod.merged_file(&self.current_target)?;
-                file.make_standalone(&self.pods_dir())?;
-                let ctx = plugins::Context::new(self, pod, subcommand);
-                self.plugins().transform(op, &ctx, &mut file)?;
-                file.write_to_path(out_path)?;
+                let file = self.processed_file(runner, op, subcommand, pod)?;
+                match self.config.compose_format {
+                    ComposeFormat::V2 => file.write_to_path(out_path)?,
+                    ComposeFormat::Spec => {
+                        // Like `abs_path`, we leave paths alone when we export.
+                        let pods_dir = self.pods_dir();
+                        let base_dir = match op {
+                            Operation::Output => Some(pods_dir.as_path()),
+                            Operation::Export => None,
+                        };
+                        let spec = compose_spec::from_v2(
+                            &file,
+                            pod.compose_spec(),
+                            base_dir,
+                        )?;
+                        dump_yaml(&out_path, &spec)?;
+                    }
+                }
                 Ok(())
             })
             // If more than one parallel branch fails, just return one error.
@@ -601,13 +859,34 @@ impl Project {
                 .map_err(|e| err!("Cannot delete {}: {}", out_pods.display(), e))?;
         }
 
-        self.output_helper(Operation::Output, subcommand, &out_pods)
+        self.with_plugin_runner(|runner| {
+            self.output_helper(runner, Operation::Output, subcommand, &out_pods)
+        })
+    }
+
+    /// Call `f` with the runner our plugins should use to run commands,
+    /// which depends on whether we're doing a dry run.
+    fn with_plugin_runner<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&(dyn CaptureOutput + Sync)) -> Result<T>,
+    {
+        if self.dry_run {
+            f(&DryRunCommandRunner::new())
+        } else {
+            f(&OsCommandRunner::new())
+        }
     }
 
     /// Export this project (with the specified target applied) as a set
-    /// of standalone `*.yml` files with no environment variable
+    /// of standalone `docker-compose.yml` files with no environment variable
     /// interpolations and no external dependencies.
     pub fn export(&self, export_dir: &Path) -> Result<()> {
+        self.export_as(export_dir, ExportFormat::Compose)
+    }
+
+    /// Like `export`, but depending on `format`, we'll write either
+    /// `docker-compose.yml` files or Kubernetes manifests.
+    pub fn export_as(&self, export_dir: &Path, format: ExportFormat) -> Result<()> {
         // Don't clobber an existing directory.
         if export_dir.exists() {
             return Err(err!(
@@ -621,7 +900,59 @@ impl Project {
             warn!("Exporting project without --default-tags");
         }
 
-        self.output_helper(Operation::Export, "export", export_dir)
+        // Our encrypted secrets stay encrypted unless we're asked otherwise.
+        let encrypted_secrets = secrets::Plugin::encrypted_config_path(self);
+        if encrypted_secrets.exists() && !self.export_secrets {
+            warn!(
+                "Not exporting secrets from {} (use --include-secrets)",
+                encrypted_secrets.display()
+            );
+        }
+
+        self.with_plugin_runner(|runner| match format {
+            ExportFormat::Compose => {
+                self.output_helper(runner, Operation::Export, "export", export_dir)
+            }
+            ExportFormat::Kubernetes => k8s::export(self, runner, export_dir),
+        })
+    }
+}
+
+/// The formats which `Project::export_as` can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Flattened `docker-compose.yml` files, one per pod.
+    Compose,
+    /// Kubernetes manifests, with a `Deployment` and `Service` for each
+    /// service and a `Job` for each task pod.
+    Kubernetes,
+}
+
+/// Given a list of pods which can't be placed because each of them has at
+/// least one dependency which hasn't been `placed` yet, follow dependencies
+/// until we come back to a pod we've already seen, and return the names of
+/// the pods in that cycle (with the first pod repeated at the end).
+fn find_dependency_cycle(stuck: &[Pod], placed: &BTreeSet<String>) -> Vec<String> {
+    let mut path: Vec<String> = vec![];
+    let mut current = &stuck[0];
+    loop {
+        if let Some(start) = path.iter().position(|name| name == current.name()) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(current.name().to_owned());
+            return cycle;
+        }
+        path.push(current.name().to_owned());
+        // Every stuck pod has at least one unplaced dependency, and every
+        // unplaced pod is stuck, so these lookups should always succeed.
+        let next = current
+            .depends_on_pods()
+            .iter()
+            .find(|dep| !placed.contains(dep.as_str()))
+            .expect("stuck pod should have an unplaced dependency");
+        current = stuck
+            .iter()
+            .find(|pod| pod.name() == next)
+            .expect("unplaced dependency should be stuck");
     }
 }
 
@@ -722,6 +1053,87 @@ fn pods_are_loaded() {
     assert_eq!(names, ["db", "frontend", "rake"]);
 }
 
+#[test]
+fn pods_are_sorted_by_dependencies() {
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_pod_dependencies").unwrap();
+    let names: Vec<_> = proj.pods.iter().map(|pod| pod.name()).collect();
+    // `queue` depends on `cache`, and `frontend` depends on `queue`.
+    assert_eq!(names, ["db", "cache", "queue", "frontend"]);
+}
+
+#[test]
+fn reverse_order_keeps_services_in_order_within_pods() {
+    use crate::args::{ActOn, DependencyOrder};
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_pod_dependencies").unwrap();
+    let act_on = ActOn::Named(vec![
+        "web".to_owned(),
+        "proxy".to_owned(),
+        "queue".to_owned(),
+    ]);
+    let names = |order: DependencyOrder| -> Vec<String> {
+        act_on
+            .pods_or_services_in_order(&proj, order)
+            .unwrap()
+            .iter()
+            .map(|pod_or_service| match pod_or_service {
+                PodOrService::Pod(pod) => pod.name().to_owned(),
+                PodOrService::Service(pod, service) => {
+                    format!("{}/{}", pod.name(), service)
+                }
+            })
+            .collect()
+    };
+    assert_eq!(
+        names(DependencyOrder::Forward),
+        ["queue", "frontend/web", "frontend/proxy"]
+    );
+    assert_eq!(
+        names(DependencyOrder::Reverse),
+        ["frontend/web", "frontend/proxy", "queue"]
+    );
+}
+
+#[test]
+fn pods_are_grouped_into_stages() {
+    use crate::args::{ActOn, DependencyOrder};
+
+    let _ = env_logger::try_init();
+    let proj = Project::from_fixture("with_parallel_pods").unwrap();
+    let act_on = ActOn::All;
+    let stage_names = |order: DependencyOrder| -> Vec<Vec<&str>> {
+        act_on
+            .pods_or_services_in_stages(&proj, order)
+            .unwrap()
+            .iter()
+            .map(|stage| stage.iter().map(|p| p.pod().name()).collect())
+            .collect()
+    };
+    // Placeholders come first, and `worker` waits for `api`.
+    assert_eq!(
+        stage_names(DependencyOrder::Forward),
+        [vec!["cache", "db"], vec!["api", "web"], vec!["worker"]]
+    );
+    assert_eq!(
+        stage_names(DependencyOrder::Reverse),
+        [vec!["worker"], vec!["web", "api"], vec!["db", "cache"]]
+    );
+}
+
+#[test]
+fn pod_dependency_cycles_are_reported() {
+    let _ = env_logger::try_init();
+    let err = Project::from_fixture("with_pod_dependency_cycle").unwrap_err();
+    match err.downcast_ref::<Error>() {
+        Some(Error::PodDependencyCycle(cycle)) => {
+            assert_eq!(cycle, &["a", "b", "a"]);
+        }
+        _ => panic!("expected a dependency cycle, got {:?}", err),
+    }
+}
+
 #[test]
 fn targets_are_loaded() {
     let _ = env_logger::try_init();
@@ -858,6 +1270,17 @@ fn output_supports_in_tree_source_code() {
     );
 }
 
+#[test]
+fn dry_runs_show_compose_commands_instead_of_engine_api_calls() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("hello").unwrap();
+    proj.set_compose_backend(ComposeBackend::EngineApi);
+    proj.set_dry_run(true);
+    assert!(proj.dry_run());
+    assert_eq!(proj.compose_backend(), ComposeBackend::DockerComposePlugin);
+    proj.remove_test_output().unwrap();
+}
+
 #[test]
 fn export_creates_a_directory_of_flat_yml_files() {
     let _ = env_logger::try_init();
@@ -1,12 +1,18 @@
 //! Support for fetching runtime state directly from the Docker daemon.
 
 use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
 use tokio::runtime;
 
 use crate::errors::*;
-use crate::pod::Pod;
+use crate::pod::{Pod, WaitFor};
 use crate::project::Project;
 
+/// How long should we wait for a TCP connection when checking whether a
+/// published port is accepting connections?
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
 /// Everything we know about the running application, based on querying Docker.
 #[derive(Debug)]
 pub struct RuntimeState {
@@ -25,6 +31,43 @@ impl RuntimeState {
             .map_err(|e| e.context(Error::CouldNotGetRuntimeState))
     }
 
+    /// Create a fake `RuntimeState` containing one container with the
+    /// specified status for each listed service.
+    #[cfg(test)]
+    pub(crate) fn fake(services: &[(&str, ContainerStatus)]) -> RuntimeState {
+        let services = services
+            .iter()
+            .map(|&(name, state)| {
+                let info = ContainerInfo {
+                    id: format!("{}_1", name),
+                    is_one_off: false,
+                    state,
+                    health: HealthStatus::NoHealthCheck,
+                    published_tcp_ports: BTreeMap::new(),
+                };
+                (name.to_owned(), vec![info])
+            })
+            .collect();
+        RuntimeState { services }
+    }
+
+    /// Add a fake one-off container to this `RuntimeState`, as though it
+    /// had been created by `docker-compose run`.
+    #[cfg(test)]
+    pub(crate) fn add_fake_one_off(&mut self, service_name: &str, id: &str) {
+        let info = ContainerInfo {
+            id: id.to_owned(),
+            is_one_off: true,
+            state: ContainerStatus::Done,
+            health: HealthStatus::NoHealthCheck,
+            published_tcp_ports: BTreeMap::new(),
+        };
+        self.services
+            .entry(service_name.to_owned())
+            .or_insert_with(Vec::new)
+            .push(info);
+    }
+
     /// The actual implementation of `for_project`.
     fn for_project_inner(project: &Project) -> Result<RuntimeState> {
         debug!("Querying Docker for running containers");
@@ -88,27 +131,58 @@ impl RuntimeState {
 
     /// Is the specified pod running?
     pub fn all_services_in_pod_are_running(&self, pod: &Pod) -> bool {
+        self.all_services_in_pod_are_ready(pod, WaitFor::Running)
+    }
+
+    /// Is every service in the specified pod ready, according to the
+    /// standard specified by `wait_for`?
+    pub fn all_services_in_pod_are_ready(&self, pod: &Pod, wait_for: WaitFor) -> bool {
         for service_name in pod.service_names() {
-            let containers = self
-                .service_containers(service_name)
-                .iter()
-                .filter(|c| !c.is_one_off())
-                .collect::<Vec<_>>();
+            let containers = self.long_running_containers(service_name);
             if containers.is_empty() {
                 // No containers are associated with this service.
                 return false;
             }
-            if containers
-                .iter()
-                .any(|c| c.state() != ContainerStatus::Running)
-            {
-                // We have at least one container which isn't running.
+            if containers.iter().any(|c| !c.is_ready(wait_for)) {
+                // We have at least one container which isn't ready.
                 return false;
             }
         }
         true
     }
 
+    /// Return the name of the first service in `pod` which has a container
+    /// that Docker considers unhealthy, if any.
+    pub fn unhealthy_service_in_pod<'a>(&self, pod: &'a Pod) -> Option<&'a str> {
+        pod.service_names()
+            .iter()
+            .find(|service_name| {
+                self.long_running_containers(service_name)
+                    .iter()
+                    .any(|c| c.health() == HealthStatus::Unhealthy)
+            })
+            .map(|service_name| service_name.as_str())
+    }
+
+    /// Get the one-off containers created by `docker-compose run` for the
+    /// services in `pod`.
+    pub fn one_off_containers_in_pod(&self, pod: &Pod) -> Vec<&ContainerInfo> {
+        pod.service_names()
+            .iter()
+            .flat_map(|service_name| self.service_containers(service_name))
+            .filter(|c| c.is_one_off())
+            .collect()
+    }
+
+    /// Get the containers associated with a service, excluding one-off
+    /// containers created by `docker-compose run`.
+    fn long_running_containers(&self, service_name: &str) -> Vec<&ContainerInfo> {
+        self.service_containers(service_name)
+            .iter()
+            .filter(|c| !c.is_one_off())
+            .collect()
+    }
+
     /// Get the containers associated with a service.  This will return the
     /// empty list if it can't find any containers related to the specified
     /// `service_name`.
@@ -120,13 +194,23 @@ impl RuntimeState {
 }
 
 /// Information about a specific container associated with a service.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct ContainerInfo {
+    /// Docker's ID for this container.
+    id: String,
+
     /// Was this a one-off container?
     is_one_off: bool,
 
     /// The current state of this container.
     state: ContainerStatus,
+
+    /// The result of this container's health check.
+    health: HealthStatus,
+
+    /// Host addresses where Docker has published this container's TCP
+    /// ports, keyed by container port.
+    published_tcp_ports: BTreeMap<String, Vec<SocketAddr>>,
 }
 
 impl ContainerInfo {
@@ -144,9 +228,24 @@ impl ContainerInfo {
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("container missing state"))?;
 
+        let published_tcp_ports = info
+            .network_settings
+            .as_ref()
+            .and_then(|settings| settings.ports.as_ref())
+            .map(published_tcp_ports)
+            .unwrap_or_default();
+
+        let id = info
+            .id
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("container missing id"))?;
+
         Ok(ContainerInfo {
+            id,
             is_one_off,
             state: ContainerStatus::new(state),
+            health: HealthStatus::new(state),
+            published_tcp_ports,
         })
     }
 
@@ -155,14 +254,125 @@ impl ContainerInfo {
         self.is_one_off
     }
 
+    /// Docker's ID for this container.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     /// The current state of this container.
     pub fn state(&self) -> ContainerStatus {
         self.state
     }
+
+    /// The result of this container's health check.
+    pub fn health(&self) -> HealthStatus {
+        self.health
+    }
+
+    /// Host addresses where Docker has published this container's TCP
+    /// ports, keyed by container port (like `"5432/tcp"`).  Docker often
+    /// publishes a port on both IPv4 and IPv6.
+    pub fn published_tcp_ports(&self) -> &BTreeMap<String, Vec<SocketAddr>> {
+        &self.published_tcp_ports
+    }
+
+    /// Is this container ready, according to the standard specified by
+    /// `wait_for`?  Containers which have no health check are considered
+    /// healthy once they're running, and containers which publish no ports
+    /// are considered to be listening once they're running.  A published
+    /// port is listening if we can connect to any of its host addresses.
+    pub fn is_ready(&self, wait_for: WaitFor) -> bool {
+        if self.state != ContainerStatus::Running {
+            return false;
+        }
+        match wait_for {
+            WaitFor::Running => true,
+            WaitFor::Healthy => matches!(
+                self.health,
+                HealthStatus::Healthy | HealthStatus::NoHealthCheck
+            ),
+            WaitFor::Port => self.published_tcp_ports.values().all(|addrs| {
+                addrs.iter().any(|addr| {
+                    TcpStream::connect_timeout(addr, PORT_PROBE_TIMEOUT).is_ok()
+                })
+            }),
+        }
+    }
+}
+
+/// Convert Docker's published port information into the local addresses
+/// that we can connect to for each container port.  Ports published on all
+/// interfaces are mapped to the corresponding loopback address.
+fn published_tcp_ports(
+    ports: &bollard::models::PortMap,
+) -> BTreeMap<String, Vec<SocketAddr>> {
+    let mut published = BTreeMap::new();
+    for (container_port, bindings) in ports {
+        if !container_port.ends_with("/tcp") {
+            continue;
+        }
+        let mut addrs = vec![];
+        for binding in bindings.iter().flatten() {
+            let port = binding
+                .host_port
+                .as_ref()
+                .and_then(|p| p.parse::<u16>().ok());
+            let ip = match binding.host_ip.as_deref() {
+                None | Some("") | Some("0.0.0.0") => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                Some("::") => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                Some(other) => match other.parse() {
+                    Ok(ip) => ip,
+                    Err(_) => continue,
+                },
+            };
+            if let Some(port) = port {
+                addrs.push(SocketAddr::new(ip, port));
+            }
+        }
+        if !addrs.is_empty() {
+            addrs.sort();
+            addrs.dedup();
+            published.insert(container_port.to_owned(), addrs);
+        }
+    }
+    published
+}
+
+/// The state of a container's Docker `HEALTHCHECK`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HealthStatus {
+    /// The container has no health check.
+    #[serde(rename = "none")]
+    NoHealthCheck,
+    /// The health check has not passed yet.
+    #[serde(rename = "starting")]
+    Starting,
+    /// The health check is passing.
+    #[serde(rename = "healthy")]
+    Healthy,
+    /// The health check has failed too many times in a row.
+    #[serde(rename = "unhealthy")]
+    Unhealthy,
+}
+
+impl HealthStatus {
+    /// Create a new `HealthStatus` from Docker data.
+    fn new(state: &bollard::models::ContainerState) -> HealthStatus {
+        use bollard::models::HealthStatusEnum;
+
+        let status = state.health.as_ref().and_then(|h| h.status.as_ref());
+        match status {
+            Some(HealthStatusEnum::STARTING) => HealthStatus::Starting,
+            Some(HealthStatusEnum::HEALTHY) => HealthStatus::Healthy,
+            Some(HealthStatusEnum::UNHEALTHY) => HealthStatus::Unhealthy,
+            _ => HealthStatus::NoHealthCheck,
+        }
+    }
 }
 
 /// Is a Docker container running? Stopped?
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ContainerStatus {
     /// The container has been created.
     Created,
@@ -202,4 +412,122 @@ impl ContainerStatus {
             _ => ContainerStatus::Other,
         }
     }
+
+    /// The exit code of this container, if it has stopped.
+    pub fn exit_code(&self) -> Option<i64> {
+        match *self {
+            ContainerStatus::Done => Some(0),
+            ContainerStatus::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+fn running_container(health: HealthStatus) -> ContainerInfo {
+    ContainerInfo {
+        id: "0123456789ab".to_owned(),
+        is_one_off: false,
+        state: ContainerStatus::Running,
+        health,
+        published_tcp_ports: BTreeMap::new(),
+    }
+}
+
+#[test]
+fn health_status_is_read_from_docker_state() {
+    use bollard::models::{ContainerState, Health, HealthStatusEnum};
+
+    let mut state = ContainerState::default();
+    assert_eq!(HealthStatus::new(&state), HealthStatus::NoHealthCheck);
+
+    state.health = Some(Health {
+        status: Some(HealthStatusEnum::STARTING),
+        ..Default::default()
+    });
+    assert_eq!(HealthStatus::new(&state), HealthStatus::Starting);
+
+    state.health = Some(Health {
+        status: Some(HealthStatusEnum::UNHEALTHY),
+        ..Default::default()
+    });
+    assert_eq!(HealthStatus::new(&state), HealthStatus::Unhealthy);
+}
+
+#[test]
+fn readiness_depends_on_wait_for() {
+    let starting = running_container(HealthStatus::Starting);
+    assert!(starting.is_ready(WaitFor::Running));
+    assert!(!starting.is_ready(WaitFor::Healthy));
+
+    let healthy = running_container(HealthStatus::Healthy);
+    assert!(healthy.is_ready(WaitFor::Healthy));
+
+    // Containers without health checks are healthy once they're running.
+    let unchecked = running_container(HealthStatus::NoHealthCheck);
+    assert!(unchecked.is_ready(WaitFor::Healthy));
+    assert!(unchecked.is_ready(WaitFor::Port));
+
+    let mut exited = running_container(HealthStatus::Healthy);
+    exited.state = ContainerStatus::Exited(1);
+    assert!(!exited.is_ready(WaitFor::Running));
+    assert!(!exited.is_ready(WaitFor::Healthy));
+}
+
+#[test]
+fn published_tcp_ports_are_mapped_to_loopback() {
+    use bollard::models::PortBinding;
+    use std::collections::HashMap;
+
+    let mut ports = HashMap::new();
+    ports.insert(
+        "5432/tcp".to_owned(),
+        Some(vec![
+            PortBinding {
+                host_ip: Some("0.0.0.0".to_owned()),
+                host_port: Some("15432".to_owned()),
+            },
+            PortBinding {
+                host_ip: Some("::".to_owned()),
+                host_port: Some("15432".to_owned()),
+            },
+        ]),
+    );
+    ports.insert("53/udp".to_owned(), None);
+    ports.insert("6379/tcp".to_owned(), None);
+
+    let published = published_tcp_ports(&ports);
+    assert_eq!(published.len(), 1);
+    assert_eq!(
+        published["5432/tcp"],
+        vec![
+            "127.0.0.1:15432".parse::<SocketAddr>().unwrap(),
+            "[::1]:15432".parse::<SocketAddr>().unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn ports_are_ready_when_any_of_their_addresses_accept_connections() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    // Docker publishes on both IPv4 and IPv6, but we only need one.
+    let mut container = running_container(HealthStatus::NoHealthCheck);
+    container
+        .published_tcp_ports
+        .insert("80/tcp".to_owned(), vec![open, closed]);
+    assert!(container.is_ready(WaitFor::Port));
+
+    // Every port needs to be listening, though.
+    container
+        .published_tcp_ports
+        .insert("443/tcp".to_owned(), vec![closed]);
+    assert!(!container.is_ready(WaitFor::Port));
 }
@@ -3,7 +3,8 @@
 
 use faraday_compose_yml::v2 as dc;
 use std::collections::btree_map;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
 #[cfg(test)]
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -25,9 +26,55 @@ use crate::util::ConductorPathExt;
 /// services.
 const SOURCES_YML: &str = "config/sources.yml";
 
-/// The file where we store our `mounted` state.
+/// The file where we lock source trees to specific commits.  Unlike our
+/// `mounted` state, this is meant to be checked into the project's repo.
+pub const SOURCES_LOCK_YML: &str = "config/sources.lock.yml";
+
+/// The file where we store our `mounted` state and our worktrees.
 const MOUNTED_YML: &str = "mounted.yml";
 
+/// The state of a source tree which we save in `MOUNTED_YML`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum SavedSource {
+    /// Just the `mounted` flag.  Older versions of `cage` only saved this,
+    /// and we still save it this way when there are no worktrees.
+    Mounted(bool),
+    /// All of our settings.
+    Settings(SourceSettings),
+}
+
+impl SavedSource {
+    /// Convert to `SourceSettings`.
+    fn into_settings(self) -> SourceSettings {
+        match self {
+            SavedSource::Mounted(mounted) => SourceSettings {
+                mounted,
+                ..SourceSettings::default()
+            },
+            SavedSource::Settings(settings) => settings,
+        }
+    }
+}
+
+/// Persistent settings for a source tree.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SourceSettings {
+    /// Should this source tree be mounted?
+    #[serde(default)]
+    mounted: bool,
+    /// The names of the git worktrees we've created for this source tree.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    worktrees: BTreeSet<String>,
+    /// The worktree to mount instead of our main checkout, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    worktree: Option<String>,
+    /// An existing checkout outside of `src/` to use as our main checkout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<PathBuf>,
+}
+
 /// Configuration for an individual source tree.
 #[derive(Debug, Clone, Deserialize)]
 struct SourceConfig {
@@ -37,6 +84,49 @@ struct SourceConfig {
     /// `docker-compose.yml` files, and that's what our `faraday_compose_yml`
     /// library supports.
     context: dc::RawOr<dc::Context>,
+    /// How to clone this source tree.
+    #[serde(flatten)]
+    clone_options: CloneOptions,
+}
+
+/// Options for cloning a source tree, which may be specified for any
+/// source tree in `config/sources.yml`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CloneOptions {
+    /// Only fetch this many commits of history.
+    #[serde(default)]
+    pub depth: Option<u32>,
+    /// Only check out the subdirectories used by our services' git URLs.
+    #[serde(default)]
+    pub sparse: bool,
+    /// Recursively clone git submodules.
+    #[serde(default)]
+    pub submodules: bool,
+}
+
+impl CloneOptions {
+    /// Extra arguments to pass to `git clone`.
+    fn clone_args(&self, sparse: bool) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![];
+        if let Some(depth) = self.depth {
+            args.push("--depth".into());
+            args.push(depth.to_string().into());
+        }
+        if self.submodules {
+            args.push("--recurse-submodules".into());
+            if self.depth.is_some() {
+                args.push("--shallow-submodules".into());
+            }
+        }
+        if sparse {
+            // Don't download files outside of our sparse checkout until we
+            // need them.
+            args.push("--filter=blob:none".into());
+            args.push("--sparse".into());
+        }
+        args
+    }
 }
 
 /// Project-related directories needed by `sources`.
@@ -67,16 +157,21 @@ pub struct Sources {
 
 impl Sources {
     /// Add a source tree to a map, keyed by its alias.  Returns the alias.
+    /// `used_by_service` should be true if `context` is a service's build
+    /// context, in which case we track which subdirectories it uses.
     fn add_source(
         sources: &mut BTreeMap<String, Source>,
-        mounted_sources: &BTreeMap<String, bool>,
+        saved_sources: &BTreeMap<String, SourceSettings>,
         context: &dc::Context,
+        used_by_service: bool,
     ) -> Result<String> {
         // Figure out what alias we want to use.
         let alias = context.human_alias()?;
 
-        // Look up whether we've mounted this container or not.
-        let mounted = mounted_sources.get(&alias).cloned().unwrap_or(false);
+        // Look up whether we've mounted this container or not, and any
+        // worktrees we've created.
+        let default_settings = SourceSettings::default();
+        let settings = saved_sources.get(&alias).unwrap_or(&default_settings);
 
         // Build our Source object. If two services share a git repo but
         // use different subdirectories, we only create a single Source
@@ -86,15 +181,20 @@ impl Sources {
         let source = Source {
             alias: alias.clone(),
             context: context.without_repository_subdirectory(),
-            mounted,
+            mounted: settings.mounted,
+            worktrees: settings.worktrees.clone(),
+            worktree: settings.worktree.clone(),
+            link: settings.link.clone(),
+            clone_options: CloneOptions::default(),
+            subdirectories: BTreeSet::new(),
+            uses_whole_repository: false,
+            locked_commit: None,
         };
 
         // Insert our Source object into our map, checking for alias
         // clashes.
-        match sources.entry(source.alias.clone()) {
-            btree_map::Entry::Vacant(vacant) => {
-                vacant.insert(source);
-            }
+        let source = match sources.entry(source.alias.clone()) {
+            btree_map::Entry::Vacant(vacant) => vacant.insert(source),
             btree_map::Entry::Occupied(occupied) => {
                 if source.context != occupied.get().context {
                     return Err(err!(
@@ -105,7 +205,22 @@ impl Sources {
                         &source.alias
                     ));
                 }
+                occupied.into_mut()
+            }
+        };
+
+        // Keep track of which subdirectories our services need, in case we
+        // want a sparse checkout.
+        match *context {
+            dc::Context::GitUrl(ref git_url) if used_by_service => {
+                match git_url.subdirectory() {
+                    Some(subdir) => {
+                        source.subdirectories.insert(subdir.to_string());
+                    }
+                    None => source.uses_whole_repository = true,
+                }
             }
+            _ => {}
         }
         Ok(alias)
     }
@@ -119,8 +234,12 @@ impl Sources {
 
         // Load our `mounted` state, if we've saved it previously.
         let mounted_path = output_dir.join(MOUNTED_YML);
-        let mounted: BTreeMap<String, bool> = if mounted_path.exists() {
-            load_yaml(&mounted_path)?
+        let mounted: BTreeMap<String, SourceSettings> = if mounted_path.exists() {
+            let saved: BTreeMap<String, SavedSource> = load_yaml(&mounted_path)?;
+            saved
+                .into_iter()
+                .map(|(alias, saved)| (alias, saved.into_settings()))
+                .collect()
         } else {
             Default::default()
         };
@@ -130,13 +249,16 @@ impl Sources {
             for file in pod.all_files() {
                 for service in file.services.values() {
                     if let Some(context) = service.context()? {
-                        Self::add_source(&mut sources, &mounted, context)?;
+                        Self::add_source(&mut sources, &mounted, context, true)?;
                     }
                 }
             }
         }
 
-        // Scan our config files for more source trees.
+        // Scan our config files for more source trees.  Entries whose key is
+        // the alias of a service's source tree only set clone options for
+        // it, and aren't libs.
+        let service_aliases: BTreeSet<String> = sources.keys().cloned().collect();
         let path = root_dir.join(SOURCES_YML);
         if path.exists() {
             let libs: BTreeMap<String, SourceConfig> = load_yaml(&path)?;
@@ -147,8 +269,30 @@ impl Sources {
                     // are already awkward enough without adding more features.
                     return Err(Error::LibHasRepoSubdirectory(lib_key.clone()).into());
                 }
-                let alias = Self::add_source(&mut sources, &mounted, context)?;
-                lib_keys.insert(lib_key.clone(), alias);
+                let alias = Self::add_source(&mut sources, &mounted, context, false)?;
+                if lib_info.clone_options != CloneOptions::default() {
+                    let source =
+                        sources.get_mut(&alias).expect("source was just added");
+                    source.clone_options = lib_info.clone_options.clone();
+                }
+                if *lib_key != alias || !service_aliases.contains(&alias) {
+                    lib_keys.insert(lib_key.clone(), alias);
+                }
+            }
+        }
+
+        // Load the commits our source trees are locked to, if any.
+        let lock_path = root_dir.join(SOURCES_LOCK_YML);
+        if lock_path.exists() {
+            let locks: BTreeMap<String, String> = load_yaml(&lock_path)?;
+            for (alias, commit) in locks {
+                match sources.get_mut(&alias) {
+                    Some(source) => source.locked_commit = Some(commit),
+                    None => warn!(
+                        "{} locks unknown source tree '{}'",
+                        SOURCES_LOCK_YML, alias
+                    ),
+                }
             }
         }
 
@@ -209,13 +353,32 @@ impl Sources {
         }
     }
 
+    /// Write the commits that our source trees are locked to into the
+    /// lockfile under `root_dir`.
+    pub fn save_lockfile(&self, root_dir: &Path) -> Result<()> {
+        let locks: BTreeMap<&str, &str> = self
+            .iter()
+            .filter_map(|source| Some((source.alias(), source.locked_commit()?)))
+            .collect();
+        dump_yaml(&root_dir.join(SOURCES_LOCK_YML), &locks)
+    }
+
     /// Save any state that we want to persist until the next run.
     pub fn save_settings(&self, out_dir: &Path) -> Result<()> {
         let mut mounted = BTreeMap::new();
         for source in self.iter() {
-            // Only record non-default mount values.
-            if source.mounted() {
-                mounted.insert(source.alias(), source.mounted());
+            // Only record non-default values, and use the old format when
+            // we can.
+            if !source.worktrees.is_empty() || source.link.is_some() {
+                let settings = SourceSettings {
+                    mounted: source.mounted(),
+                    worktrees: source.worktrees.clone(),
+                    worktree: source.worktree.clone(),
+                    link: source.link.clone(),
+                };
+                mounted.insert(source.alias(), SavedSource::Settings(settings));
+            } else if source.mounted() {
+                mounted.insert(source.alias(), SavedSource::Mounted(true));
             }
         }
         dump_yaml(&out_dir.join(MOUNTED_YML), &mounted)?;
@@ -268,6 +431,22 @@ pub struct Source {
     /// Should this source tree be mounted into all of the containers that
     /// use it?
     mounted: bool,
+    /// The names of the git worktrees we've created for this source tree.
+    worktrees: BTreeSet<String>,
+    /// The worktree to mount instead of our main checkout, if any.
+    worktree: Option<String>,
+    /// An existing checkout outside of `src/` to use as our main checkout,
+    /// as set by `cage source link`.
+    link: Option<PathBuf>,
+    /// How to clone this source tree.
+    clone_options: CloneOptions,
+    /// The subdirectories of this source tree used by our services.
+    subdirectories: BTreeSet<String>,
+    /// Does anything use this entire source tree, and not just a
+    /// subdirectory?
+    uses_whole_repository: bool,
+    /// The commit we should check out, as specified in `SOURCES_LOCK_YML`.
+    locked_commit: Option<String>,
 }
 
 impl Source {
@@ -294,37 +473,263 @@ impl Source {
     }
 
     /// The full path to where we expect any local copies of this code to
-    /// live.  This will either be the location where we will check out a
-    /// git repository, or the path to the actual source tree, depending on
-    /// what type of `Context` object we're dealing with.
+    /// live.  This will either be the location of the worktree we want to
+    /// mount, the location where we will check out a git repository, or
+    /// the path to the actual source tree, depending on what type of
+    /// `Context` object we're dealing with.
     ///
-    /// The `project` argument is mandatory because we can't store a pointer
-    /// to it without creating a circular reference loop.
+    /// The `dirs` argument is mandatory because we can't store a pointer
+    /// to our project without creating a circular reference loop.
     pub fn path(&self, dirs: &SourcesDirs) -> PathBuf {
+        match self.worktree {
+            Some(ref name) => self.worktree_path(dirs, name),
+            None => self.checkout_path(dirs),
+        }
+    }
+
+    /// The path to our main checkout, ignoring any worktrees.  This is
+    /// normally in `src/`, unless we've been linked to another directory.
+    pub fn checkout_path(&self, dirs: &SourcesDirs) -> PathBuf {
+        if let Some(ref link) = self.link {
+            return link.to_owned();
+        }
         match self.context {
             dc::Context::GitUrl(_) => dirs.src_dir.join(Path::new(self.alias())),
             dc::Context::Dir(ref path) => dirs.pods_dir.join(path),
         }
     }
 
+    /// The path to the worktree `name`, which lives next to our main
+    /// checkout.  Branch names may contain `/`, so we replace it.
+    pub fn worktree_path(&self, dirs: &SourcesDirs, name: &str) -> PathBuf {
+        let dir_name = format!("{}@{}", self.alias(), name.replace('/', "-"));
+        dirs.src_dir.join(dir_name)
+    }
+
+    /// The names of the git worktrees we've created for this source tree.
+    pub fn worktrees(&self) -> impl Iterator<Item = &str> {
+        self.worktrees.iter().map(|name| name.as_str())
+    }
+
+    /// The worktree we mount instead of our main checkout, if any.
+    pub fn worktree(&self) -> Option<&str> {
+        self.worktree.as_deref()
+    }
+
+    /// Choose a worktree to mount instead of our main checkout, or pass
+    /// `None` to mount our main checkout.
+    pub fn set_worktree(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            if !self.worktrees.contains(name) {
+                return Err(Error::UnknownWorktree {
+                    alias: self.alias.clone(),
+                    worktree: name.to_owned(),
+                }
+                .into());
+            }
+        }
+        self.worktree = name.map(|name| name.to_owned());
+        Ok(())
+    }
+
+    /// Use `git worktree` to check out `branch` next to our main checkout,
+    /// using the branch name as the worktree name.  This doesn't change
+    /// which worktree is mounted.
+    pub fn add_worktree<CR>(
+        &mut self,
+        runner: &CR,
+        dirs: &SourcesDirs,
+        branch: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        if !matches!(self.context, dc::Context::GitUrl(_)) {
+            return Err(err!("'{}' is not a git repository", &self.context));
+        }
+        let checkout = self.checkout_path(dirs);
+        if !checkout.exists() {
+            return Err(err!(
+                "'{}' must be cloned before adding worktrees",
+                self.alias()
+            ));
+        }
+        if self.worktrees.contains(branch) {
+            return Err(err!(
+                "'{}' already has a worktree named '{}'",
+                self.alias(),
+                branch
+            ));
+        }
+        // Different branch names, like `feature/x` and `feature-x`, may map
+        // to the same directory.
+        let path = self.worktree_path(dirs, branch);
+        let clash = self
+            .worktrees
+            .iter()
+            .find(|name| self.worktree_path(dirs, name) == path);
+        if let Some(clash) = clash {
+            return Err(err!(
+                "worktree '{}' of '{}' would use the same directory as '{}'",
+                branch,
+                self.alias(),
+                clash
+            ));
+        }
+        runner
+            .build("git")
+            .arg("-C")
+            .arg(&checkout)
+            .args(&["worktree", "add"])
+            .arg(&path)
+            .arg(branch)
+            .exec()?;
+        self.worktrees.insert(branch.to_owned());
+        Ok(())
+    }
+
+    /// The existing checkout we've been linked to, if any.
+    pub fn link(&self) -> Option<&Path> {
+        self.link.as_deref()
+    }
+
+    /// Use an existing checkout in `dir` as our main checkout, and mount
+    /// it.  We check that `dir` has a git remote pointing at our git URL,
+    /// so that we don't mount the wrong code by accident.
+    pub fn link_to<CR>(&mut self, runner: &CR, dir: &Path) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        let expected = match self.context {
+            dc::Context::GitUrl(ref git_url) => normalize_git_remote(git_url.as_ref()),
+            dc::Context::Dir(_) => {
+                return Err(err!("'{}' is already a local directory", &self.context));
+            }
+        };
+        if !dir.is_dir() {
+            return Err(err!("{} is not a directory", dir.display()));
+        }
+        let dir = dir.canonicalize()?;
+        // We can't check the remotes of `dir` without running `git`.
+        if !runner.is_dry_run() {
+            let remotes = runner
+                .build("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(&["remote", "-v"])
+                .output()?;
+            let matches = remotes
+                .lines()
+                .filter_map(|line| line.split_whitespace().nth(1))
+                .any(|remote| normalize_git_remote(remote) == expected);
+            if !matches {
+                return Err(err!(
+                    "{} has no git remote pointing at {}",
+                    dir.display(),
+                    &self.context
+                ));
+            }
+        }
+        self.link = Some(dir);
+        self.set_mounted(true);
+        Ok(())
+    }
+
+    /// Go back to using our main checkout in `src/`.
+    pub fn unlink(&mut self) {
+        self.link = None;
+    }
+
+    /// Remove the worktree `name` using `git worktree remove`.  If it was
+    /// mounted, we go back to mounting our main checkout.
+    pub fn remove_worktree<CR>(
+        &mut self,
+        runner: &CR,
+        dirs: &SourcesDirs,
+        name: &str,
+    ) -> Result<()>
+    where
+        CR: CommandRunner,
+    {
+        if !self.worktrees.contains(name) {
+            return Err(Error::UnknownWorktree {
+                alias: self.alias.clone(),
+                worktree: name.to_owned(),
+            }
+            .into());
+        }
+        runner
+            .build("git")
+            .arg("-C")
+            .arg(self.checkout_path(dirs))
+            .args(&["worktree", "remove"])
+            .arg(self.worktree_path(dirs, name))
+            .exec()?;
+        self.worktrees.remove(name);
+        if self.worktree.as_deref() == Some(name) {
+            self.worktree = None;
+        }
+        Ok(())
+    }
+
     /// Has this project been cloned locally?
     pub fn is_available_locally(&self, dirs: &SourcesDirs) -> bool {
         self.path(dirs).exists()
     }
 
+    /// Is this source tree a git repository which has been cloned locally?
+    pub fn is_cloned_git_repository(&self, dirs: &SourcesDirs) -> bool {
+        matches!(self.context, dc::Context::GitUrl(_))
+            && self.is_available_locally(dirs)
+    }
+
     /// Clone the source code of this repository using git.
     pub fn clone_source<CR>(&mut self, runner: &CR, dirs: &SourcesDirs) -> Result<()>
     where
         CR: CommandRunner,
     {
         if let dc::Context::GitUrl(ref git_url) = self.context {
-            let dest = self.path(dirs).with_guaranteed_parent()?;
+            let sparse_paths = self.sparse_paths();
+            let dest = self.checkout_path(dirs).with_guaranteed_parent()?;
             runner
                 .build("git")
                 .arg("clone")
+                .args(&self.clone_options.clone_args(sparse_paths.is_some()))
                 .args(&git_url.clone_args()?)
                 .arg(&dest)
                 .exec()?;
+            if let Some(paths) = sparse_paths {
+                runner
+                    .build("git")
+                    .arg("-C")
+                    .arg(&dest)
+                    .args(&["sparse-checkout", "set"])
+                    .args(&paths)
+                    .exec()?;
+            }
+            if let Some(ref commit) = self.locked_commit {
+                // A shallow clone may not include our commit.
+                if let Some(depth) = self.clone_options.depth {
+                    runner
+                        .build("git")
+                        .arg("-C")
+                        .arg(&dest)
+                        .arg("fetch")
+                        .arg(format!("--depth={}", depth))
+                        .arg("origin")
+                        .arg(commit)
+                        .exec()?;
+                }
+                // Move our branch to the locked commit, so that `cage source
+                // pull` can still fast-forward it later.
+                runner
+                    .build("git")
+                    .arg("-C")
+                    .arg(&dest)
+                    .args(&["reset", "--hard", "--quiet"])
+                    .arg(commit)
+                    .exec()?;
+            }
             self.set_mounted(true);
             Ok(())
         } else {
@@ -335,16 +740,353 @@ impl Source {
         }
     }
 
+    /// How we clone this source tree, as specified in `config/sources.yml`.
+    pub fn clone_options(&self) -> &CloneOptions {
+        &self.clone_options
+    }
+
+    /// The commit this source tree is locked to, if any.
+    pub fn locked_commit(&self) -> Option<&str> {
+        self.locked_commit.as_deref()
+    }
+
+    /// Lock this source tree to `commit`, or pass `None` to unlock it.
+    /// Call `Sources::save_lockfile` to save the change.
+    pub fn set_locked_commit(&mut self, commit: Option<String>) {
+        self.locked_commit = commit;
+    }
+
+    /// Find the commit we'd want to lock this source tree to.  If we have a
+    /// local checkout, we use its `HEAD`.  Otherwise, we ask the remote
+    /// repository about our branch.
+    pub fn resolve_commit<CR>(&self, runner: &CR, dirs: &SourcesDirs) -> Result<String>
+    where
+        CR: CommandRunner,
+    {
+        let git_url = match self.context {
+            dc::Context::GitUrl(ref git_url) => git_url,
+            dc::Context::Dir(_) => {
+                return Err(err!("'{}' is not a git repository", &self.context));
+            }
+        };
+        if self.is_available_locally(dirs) {
+            return self.head_commit(runner, &self.path(dirs));
+        }
+        let url_str: &str = git_url.as_ref();
+        let base_url = url_str.split('#').next().unwrap_or(url_str);
+        let output = runner
+            .build("git")
+            .args(&[
+                "ls-remote",
+                base_url,
+                self.pinned_branch().unwrap_or("HEAD"),
+            ])
+            .output()?;
+        output
+            .split_whitespace()
+            .next()
+            .map(|commit| commit.to_owned())
+            .ok_or_else(|| err!("could not find a commit to lock '{}' to", self.alias))
+    }
+
+    /// If our local checkout isn't at our locked commit, return the commit
+    /// it's at instead.  During a dry run, we can't tell, so we assume it
+    /// hasn't drifted.
+    pub fn lock_drift<CR>(
+        &self,
+        runner: &CR,
+        dirs: &SourcesDirs,
+    ) -> Result<Option<String>>
+    where
+        CR: CommandRunner,
+    {
+        let locked = match self.locked_commit {
+            Some(ref locked) => locked,
+            None => return Ok(None),
+        };
+        if !self.is_cloned_git_repository(dirs) || runner.is_dry_run() {
+            return Ok(None);
+        }
+        let head = self.head_commit(runner, &self.path(dirs))?;
+        if head == *locked {
+            Ok(None)
+        } else {
+            Ok(Some(head))
+        }
+    }
+
+    /// The paths to include in a sparse checkout, or `None` if we want to
+    /// check out everything.
+    fn sparse_paths(&self) -> Option<Vec<&str>> {
+        if !self.clone_options.sparse {
+            None
+        } else if self.uses_whole_repository || self.subdirectories.is_empty() {
+            warn!(
+                "'{}' is used without a subdirectory, so checking out all of it",
+                self.alias
+            );
+            None
+        } else {
+            Some(self.subdirectories.iter().map(|s| s.as_str()).collect())
+        }
+    }
+
+    /// The branch which this source tree should have checked out, as
+    /// specified after the `#` in its git URL.  If this is `None`, we use
+    /// the remote repository's default branch.
+    pub fn pinned_branch(&self) -> Option<&str> {
+        match self.context {
+            dc::Context::GitUrl(ref git_url) => git_url.branch(),
+            dc::Context::Dir(_) => None,
+        }
+    }
+
+    /// Ask git about the state of our local checkout.  Returns `None` if
+    /// this source tree isn't a git repository, or if it hasn't been cloned
+    /// yet.  During a dry run, we can't capture git's output, so we print
+    /// the command we'd run and return `None`.
+    pub fn git_status<CR>(
+        &self,
+        runner: &CR,
+        dirs: &SourcesDirs,
+    ) -> Result<Option<GitStatus>>
+    where
+        CR: CommandRunner,
+    {
+        if !self.is_cloned_git_repository(dirs) {
+            return Ok(None);
+        }
+        let mut cmd = runner.build("git");
+        cmd.arg("-C").arg(self.path(dirs)).args(&[
+            "status",
+            "--porcelain=v2",
+            "--branch",
+        ]);
+        if runner.is_dry_run() {
+            cmd.exec()?;
+            return Ok(None);
+        }
+        Ok(Some(GitStatus::parse(&cmd.output()?)?))
+    }
+
+    /// Fetch and fast-forward our local checkout.  If our git URL specifies
+    /// a branch, we pull that branch, and we refuse to touch checkouts which
+    /// have a different branch checked out.  We also refuse to touch
+    /// checkouts with uncommitted changes.
+    ///
+    /// `git` output is captured and only printed if `git` fails, so this may
+    /// be called for several source trees in parallel.
+    pub fn pull_source<CR>(
+        &self,
+        runner: &CR,
+        dirs: &SourcesDirs,
+    ) -> Result<PullOutcome>
+    where
+        CR: CommandRunner,
+    {
+        let path = self.path(dirs);
+        let status = match self.git_status(runner, dirs)? {
+            Some(status) => status,
+            // During a dry run, we can't see the state of our checkout, so
+            // just show how we'd pull it.
+            None if runner.is_dry_run() && self.is_cloned_git_repository(dirs) => {
+                self.pull_command(runner, &path).exec()?;
+                return Ok(PullOutcome::Skipped("dry run".to_owned()));
+            }
+            None if self.is_available_locally(dirs) => {
+                return Ok(PullOutcome::Skipped("not a git repository".to_owned()));
+            }
+            None => return Ok(PullOutcome::Skipped("not cloned".to_owned())),
+        };
+        if !status.dirty_files.is_empty() {
+            return Ok(PullOutcome::Skipped(format!(
+                "{} uncommitted change(s)",
+                status.dirty_files.len()
+            )));
+        }
+        if let Some(pinned) = self.pinned_branch() {
+            if status.branch.as_deref() != Some(pinned) {
+                let current = status.branch.as_deref().unwrap_or("(detached HEAD)");
+                return Ok(PullOutcome::Skipped(format!(
+                    "on branch {}, not {}",
+                    current, pinned
+                )));
+            }
+        }
+
+        let before = self.head_commit(runner, &path)?;
+        self.pull_command(runner, &path)
+            .exec_capturing(self.alias())?;
+        let after = self.head_commit(runner, &path)?;
+
+        if before == after {
+            Ok(PullOutcome::UpToDate)
+        } else {
+            Ok(PullOutcome::Updated {
+                from: before,
+                to: after,
+            })
+        }
+    }
+
+    /// Build the `git pull` command for our checkout in `path`.
+    fn pull_command<CR>(&self, runner: &CR, path: &Path) -> CR::Command
+    where
+        CR: CommandRunner,
+    {
+        let mut cmd = runner.build("git");
+        cmd.arg("-C").arg(path).args(&["pull", "--ff-only"]);
+        if let Some(pinned) = self.pinned_branch() {
+            cmd.arg("origin").arg(pinned);
+        }
+        cmd
+    }
+
+    /// Get the commit which is checked out in `path`.
+    fn head_commit<CR>(&self, runner: &CR, path: &Path) -> Result<String>
+    where
+        CR: CommandRunner,
+    {
+        let output = runner
+            .build("git")
+            .arg("-C")
+            .arg(path)
+            .args(&["rev-parse", "HEAD"])
+            .output()?;
+        Ok(output.trim().to_owned())
+    }
+
     /// (Test mode only.) Pretend to clone the source code for this
     /// repository by creating an empty directory in the right place.
     #[cfg(test)]
     pub fn fake_clone_source(&mut self, dirs: &SourcesDirs) -> Result<()> {
-        fs::create_dir_all(self.path(dirs))?;
+        fs::create_dir_all(self.checkout_path(dirs))?;
         self.set_mounted(true);
         Ok(())
     }
 }
 
+/// The state of a local git checkout, as reported by `git status`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct GitStatus {
+    /// The branch we have checked out, or `None` if `HEAD` is detached.
+    pub branch: Option<String>,
+    /// The upstream branch that `branch` tracks, if any.
+    pub upstream: Option<String>,
+    /// How many local commits haven't been pushed to `upstream`.
+    pub ahead: usize,
+    /// How many commits on `upstream` haven't been merged locally.
+    pub behind: usize,
+    /// Files with uncommitted changes, including untracked files, relative
+    /// to the root of the checkout.
+    pub dirty_files: Vec<String>,
+}
+
+impl GitStatus {
+    /// Parse the output of `git status --porcelain=v2 --branch`.
+    fn parse(porcelain: &str) -> Result<GitStatus> {
+        let mut status = GitStatus::default();
+        for line in porcelain.lines() {
+            // See `git help status` for the format of each type of line.
+            let mut words = line.split(' ');
+            match words.next() {
+                Some("#") => match (words.next(), words.next()) {
+                    (Some("branch.head"), Some(head)) if head != "(detached)" => {
+                        status.branch = Some(head.to_owned());
+                    }
+                    (Some("branch.upstream"), Some(upstream)) => {
+                        status.upstream = Some(upstream.to_owned());
+                    }
+                    (Some("branch.ab"), Some(ahead)) => {
+                        let behind = words.next().unwrap_or_default();
+                        status.ahead = parse_count(ahead, '+', line)?;
+                        status.behind = parse_count(behind, '-', line)?;
+                    }
+                    _ => {}
+                },
+                Some("1") => status.push_dirty_file(line, 9)?,
+                Some("2") => status.push_dirty_file(line, 10)?,
+                Some("u") => status.push_dirty_file(line, 11)?,
+                Some("?") => status.push_dirty_file(line, 2)?,
+                _ => {}
+            }
+        }
+        Ok(status)
+    }
+
+    /// Record the path in field number `field` of `line` as a dirty file.
+    /// Renamed files are followed by a tab and their original path, which
+    /// we ignore.
+    fn push_dirty_file(&mut self, line: &str, field: usize) -> Result<()> {
+        let path = line
+            .splitn(field, ' ')
+            .nth(field - 1)
+            .and_then(|path| path.split('\t').next())
+            .ok_or_else(|| err!("could not parse git status line {:?}", line))?;
+        self.dirty_files.push(path.to_owned());
+        Ok(())
+    }
+}
+
+/// What happened when we tried to update a source tree using
+/// `Source::pull_source`?
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PullOutcome {
+    /// We fast-forwarded from one commit to another.
+    Updated {
+        /// The commit we had checked out before.
+        from: String,
+        /// The commit we have checked out now.
+        to: String,
+    },
+    /// There were no new commits.
+    UpToDate,
+    /// We didn't try to update this source tree, for the specified reason.
+    Skipped(String),
+}
+
+/// Normalize a git remote URL so that we can compare the different ways of
+/// writing it, such as `git@github.com:org/repo.git` and
+/// `https://github.com/org/repo`.  We ignore any branch after a `#`.
+fn normalize_git_remote(url: &str) -> String {
+    let url = url.split('#').next().unwrap_or(url);
+    let url = match url.find("://") {
+        Some(pos) => &url[pos + 3..],
+        None => url,
+    };
+    let host_end = url.find(['/', ':']).unwrap_or(url.len());
+    let url = match url[..host_end].rfind('@') {
+        Some(pos) => &url[pos + 1..],
+        None => url,
+    };
+    let (host, path) = url.split_at(url.find(['/', ':']).unwrap_or(url.len()));
+    // Skip past the `:` in `host:path`, or a `:port`.
+    let path = match path.strip_prefix(':') {
+        Some(rest) => match rest.find('/') {
+            Some(pos)
+                if pos > 0 && rest[..pos].bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                &rest[pos..]
+            }
+            _ => rest,
+        },
+        None => path,
+    };
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    format!("{}/{}", host.to_lowercase(), path)
+}
+
+/// Parse an ahead or behind count from `git status`, which has the form
+/// `+3` or `-2`.
+fn parse_count(count: &str, prefix: char, line: &str) -> Result<usize> {
+    count
+        .strip_prefix(prefix)
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| err!("could not parse git status line {:?}", line))
+}
+
 #[test]
 fn are_loaded_with_projects() {
     let _ = env_logger::try_init();
@@ -406,6 +1148,117 @@ fn can_be_cloned() {
     proj.remove_test_output().unwrap();
 }
 
+#[test]
+fn can_be_cloned_with_clone_options() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_fixture("with_clone_options").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    // This entry only sets clone options, so it doesn't define a lib.
+    assert!(proj.sources().find_by_lib_key("rails_hello").is_none());
+    // But libs may set clone options, too.
+    let lib = proj.sources().find_by_lib_key("coffee_rails").unwrap();
+    assert_eq!(lib.clone_options().depth, Some(1));
+    let source = proj.sources_mut().find_by_alias_mut("rails_hello").unwrap();
+    assert_eq!(source.clone_options().depth, Some(1));
+    let runner = TestCommandRunner::new();
+    source.clone_source(&runner, &sources_dirs).unwrap();
+    let url = "https://github.com/faradayio/rails_hello.git";
+    let dest = source.path(&sources_dirs);
+    assert_ran!(runner, {
+        [
+            "git",
+            "clone",
+            "--depth",
+            "1",
+            "--recurse-submodules",
+            "--shallow-submodules",
+            "--filter=blob:none",
+            "--sparse",
+            url,
+            &dest,
+        ],
+        ["git", "-C", &dest, "sparse-checkout", "set", "myfolder", "otherfolder"]
+    });
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn can_be_cloned_at_a_locked_commit() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_fixture("with_lockfile").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    let source = proj.sources_mut().find_by_alias_mut("rails_hello").unwrap();
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    assert_eq!(source.locked_commit(), Some(commit));
+    let runner = TestCommandRunner::new();
+    source.clone_source(&runner, &sources_dirs).unwrap();
+    let url = "https://github.com/faradayio/rails_hello.git";
+    let dest = source.path(&sources_dirs);
+    assert_ran!(runner, {
+        ["git", "clone", "--depth", "1", url, &dest],
+        ["git", "-C", &dest, "fetch", "--depth=1", "origin", commit],
+        ["git", "-C", &dest, "reset", "--hard", "--quiet", commit]
+    });
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn lock_drift_is_detected() {
+    use crate::command_runner::{DryRunCommandRunner, OsCommandRunner};
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("hello").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    let output_dir = proj.output_dir().to_owned();
+    let runner = OsCommandRunner::new();
+    let source = proj
+        .sources_mut()
+        .find_by_alias_mut("dockercloud-hello-world")
+        .unwrap();
+    source.fake_clone_source(&sources_dirs).unwrap();
+    let checkout = source.path(&sources_dirs);
+    for args in &[
+        &["init", "-q"][..],
+        &[
+            "-c",
+            "user.name=Cage",
+            "-c",
+            "user.email=cage@example.com",
+            "commit",
+            "-q",
+            "--allow-empty",
+            "-m",
+            "Initial commit",
+        ][..],
+    ] {
+        runner
+            .build("git")
+            .arg("-C")
+            .arg(&checkout)
+            .args(args)
+            .output()
+            .unwrap();
+    }
+
+    let head = source.resolve_commit(&runner, &sources_dirs).unwrap();
+    assert_eq!(head.len(), 40);
+    source.set_locked_commit(Some(head.clone()));
+    assert_eq!(source.lock_drift(&runner, &sources_dirs).unwrap(), None);
+    source.set_locked_commit(Some("0".repeat(40)));
+    assert_eq!(
+        source.lock_drift(&runner, &sources_dirs).unwrap(),
+        Some(head)
+    );
+    let dry_run = DryRunCommandRunner::new();
+    assert_eq!(source.lock_drift(&dry_run, &sources_dirs).unwrap(), None);
+
+    proj.sources().save_lockfile(&output_dir).unwrap();
+    let locks: BTreeMap<String, String> =
+        load_yaml(&output_dir.join(SOURCES_LOCK_YML)).unwrap();
+    assert_eq!(locks["dockercloud-hello-world"], "0".repeat(40));
+    proj.remove_test_output().unwrap();
+}
+
 #[test]
 fn can_be_checked_to_see_if_cloned() {
     let _ = env_logger::try_init();
@@ -421,6 +1274,225 @@ fn can_be_checked_to_see_if_cloned() {
     proj.remove_test_output().unwrap();
 }
 
+#[test]
+fn git_status_is_parsed_from_porcelain_output() {
+    let porcelain = "\
+# branch.oid 0123456789abcdef0123456789abcdef01234567
+# branch.head feature-x
+# branch.upstream origin/feature-x
+# branch.ab +2 -1
+1 .M N... 100644 100644 100644 0123456 0123456 Gemfile
+2 R. N... 100644 100644 100644 0123456 0123456 R100 app/new name.rb\tapp/old.rb
+? tmp/notes.txt
+";
+    let status = GitStatus::parse(porcelain).unwrap();
+    assert_eq!(status.branch.as_deref(), Some("feature-x"));
+    assert_eq!(status.upstream.as_deref(), Some("origin/feature-x"));
+    assert_eq!(status.ahead, 2);
+    assert_eq!(status.behind, 1);
+    assert_eq!(
+        status.dirty_files,
+        vec!["Gemfile", "app/new name.rb", "tmp/notes.txt"]
+    );
+
+    let detached = GitStatus::parse("# branch.head (detached)\n").unwrap();
+    assert_eq!(detached, GitStatus::default());
+}
+
+#[test]
+fn git_status_is_only_checked_for_cloned_repositories() {
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("hello").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    let runner = TestCommandRunner::new();
+    let source = proj
+        .sources_mut()
+        .find_by_alias_mut("dockercloud-hello-world")
+        .unwrap();
+    assert_eq!(source.git_status(&runner, &sources_dirs).unwrap(), None);
+    source.fake_clone_source(&sources_dirs).unwrap();
+    assert!(source.git_status(&runner, &sources_dirs).unwrap().is_some());
+    assert_ran!(runner, {
+        [
+            "git",
+            "-C",
+            source.path(&sources_dirs),
+            "status",
+            "--porcelain=v2",
+            "--branch",
+        ]
+    });
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn can_be_pulled() {
+    use crate::command_runner::DryRunCommandRunner;
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("hello").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    let runner = TestCommandRunner::new();
+    let source = proj
+        .sources_mut()
+        .find_by_alias_mut("dockercloud-hello-world")
+        .unwrap();
+    assert_eq!(
+        source.pull_source(&runner, &sources_dirs).unwrap(),
+        PullOutcome::Skipped("not cloned".to_owned())
+    );
+    source.fake_clone_source(&sources_dirs).unwrap();
+    assert_eq!(
+        source.pull_source(&runner, &sources_dirs).unwrap(),
+        PullOutcome::UpToDate
+    );
+    let path = source.path(&sources_dirs);
+    assert_ran!(runner, {
+        ["git", "-C", &path, "status", "--porcelain=v2", "--branch"],
+        ["git", "-C", &path, "rev-parse", "HEAD"],
+        ["git", "-C", &path, "pull", "--ff-only"],
+        ["git", "-C", &path, "rev-parse", "HEAD"]
+    });
+
+    // During a dry run, we only show the commands we'd run.
+    let dry_run = DryRunCommandRunner::new();
+    assert_eq!(source.git_status(&dry_run, &sources_dirs).unwrap(), None);
+    assert_eq!(
+        source.pull_source(&dry_run, &sources_dirs).unwrap(),
+        PullOutcome::Skipped("dry run".to_owned())
+    );
+    let cmds = dry_run.cmds();
+    assert_eq!(cmds.len(), 3);
+    assert!(cmds[0].ends_with("status --porcelain=v2 --branch"));
+    assert!(cmds[2].ends_with("pull --ff-only"));
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn worktrees_can_be_added_mounted_and_saved() {
+    let _ = env_logger::try_init();
+    use rand::random;
+    let id: u16 = random();
+
+    // Add a worktree and mount it.
+    {
+        let mut proj = Project::from_example_and_random_id("hello", id).unwrap();
+        let sources_dirs = proj.sources_dirs();
+        let runner = TestCommandRunner::new();
+        {
+            let source = proj
+                .sources_mut()
+                .find_by_alias_mut("dockercloud-hello-world")
+                .unwrap();
+            assert!(source.add_worktree(&runner, &sources_dirs, "dev").is_err());
+            source.fake_clone_source(&sources_dirs).unwrap();
+            source
+                .add_worktree(&runner, &sources_dirs, "feature/x")
+                .unwrap();
+            assert!(source
+                .add_worktree(&runner, &sources_dirs, "feature-x")
+                .is_err());
+            assert!(source.set_worktree(Some("nope")).is_err());
+            source.set_worktree(Some("feature/x")).unwrap();
+
+            let worktree_path = sources_dirs
+                .src_dir
+                .join("dockercloud-hello-world@feature-x");
+            assert_eq!(source.path(&sources_dirs), worktree_path);
+            assert_ran!(runner, {
+                [
+                    "git",
+                    "-C",
+                    source.checkout_path(&sources_dirs),
+                    "worktree",
+                    "add",
+                    &worktree_path,
+                    "feature/x",
+                ]
+            });
+        }
+        proj.save_settings().unwrap();
+    }
+
+    // Reload the project and make sure our worktree was saved.
+    let proj = Project::from_example_and_random_id("hello", id).unwrap();
+    let source = proj
+        .sources()
+        .find_by_alias("dockercloud-hello-world")
+        .unwrap();
+    assert_eq!(source.worktrees().collect::<Vec<_>>(), vec!["feature/x"]);
+    assert_eq!(source.worktree(), Some("feature/x"));
+    assert!(source.mounted());
+    proj.remove_test_output().unwrap();
+}
+
+#[test]
+fn git_remotes_are_normalized_for_comparison() {
+    let expected = "github.com/faradayio/rails_hello";
+    for url in &[
+        "https://github.com/faradayio/rails_hello.git",
+        "https://github.com/faradayio/rails_hello/",
+        "https://user@GitHub.com/faradayio/rails_hello.git#dev",
+        "git@github.com:faradayio/rails_hello.git",
+        "ssh://git@github.com:22/faradayio/rails_hello.git",
+    ] {
+        assert_eq!(normalize_git_remote(url), expected, "{}", url);
+    }
+    assert_ne!(
+        normalize_git_remote("git@github.com:someone/rails_hello.git"),
+        expected
+    );
+}
+
+#[test]
+fn can_be_linked_to_a_checkout_with_a_matching_remote() {
+    use crate::command_runner::OsCommandRunner;
+
+    let _ = env_logger::try_init();
+    let mut proj = Project::from_example("hello").unwrap();
+    let sources_dirs = proj.sources_dirs();
+    let src_path = proj.src_dir().join("dockercloud-hello-world");
+    let checkout = proj.output_dir().join("elsewhere/hello");
+    fs::create_dir_all(&checkout).unwrap();
+
+    let runner = OsCommandRunner::new();
+    let git = |args: &[&str]| {
+        runner
+            .build("git")
+            .arg("-C")
+            .arg(&checkout)
+            .args(args)
+            .output()
+            .unwrap();
+    };
+    git(&["init", "-q"]);
+    git(&["remote", "add", "origin", "git@github.com:docker/wrong.git"]);
+
+    let source = proj
+        .sources_mut()
+        .find_by_alias_mut("dockercloud-hello-world")
+        .unwrap();
+    assert!(source.link_to(&runner, &checkout).is_err());
+    assert_eq!(source.link(), None);
+
+    git(&[
+        "remote",
+        "set-url",
+        "origin",
+        "git@github.com:docker/dockercloud-hello-world.git",
+    ]);
+    source.link_to(&runner, &checkout).unwrap();
+    let canonical = checkout.canonicalize().unwrap();
+    assert_eq!(source.link(), Some(canonical.as_path()));
+    assert_eq!(source.path(&sources_dirs), canonical);
+    assert!(source.is_available_locally(&sources_dirs));
+    assert!(source.mounted());
+
+    source.unlink();
+    assert_eq!(source.path(&sources_dirs), src_path);
+    proj.remove_test_output().unwrap();
+}
+
 #[test]
 fn dir_context_is_always_available_locally() {
     let _ = env_logger::try_init();
@@ -22,3 +22,49 @@ fn project_from_current_dir() {
 
     testdir.expect_path("hello/pods/exported/frontend.yml");
 }
+
+#[test]
+fn source_link_and_unlink() {
+    use std::process::Command;
+
+    let testdir = TestDir::new("cage", "source_link_and_unlink");
+    copy_dir(
+        testdir.src_path("examples/rails_hello"),
+        testdir.path("rails_hello"),
+    )
+    .expect("could not copy rails_hello example");
+
+    // Make a checkout outside of `src/` whose remote matches our source.
+    let checkout = testdir.path("checkout");
+    std::fs::create_dir_all(&checkout).expect("could not create checkout");
+    for args in [
+        &["init", "-q"][..],
+        &[
+            "remote",
+            "add",
+            "origin",
+            "https://github.com/faradayio/rails_hello.git",
+        ][..],
+    ] {
+        let status = Command::new("git")
+            .current_dir(&checkout)
+            .args(args)
+            .status()
+            .expect("could not run git");
+        assert!(status.success());
+    }
+
+    testdir
+        .cmd()
+        .current_dir(testdir.path("rails_hello"))
+        .args(&["source", "link", "rails_hello", "../checkout"])
+        .expect_success();
+    testdir.expect_contains("rails_hello/.cage/mounted.yml", "checkout");
+
+    testdir
+        .cmd()
+        .current_dir(testdir.path("rails_hello"))
+        .args(&["source", "unlink", "rails_hello"])
+        .expect_success();
+    testdir.expect_does_not_contain("rails_hello/.cage/mounted.yml", "checkout");
+}